@@ -0,0 +1,255 @@
+// bptree 查看和修改树文件的命令行工具, key 为 u64, 数据类型由 --value 指定, 默认字符串
+use std::env;
+use std::fmt::Debug;
+use std::io::{self, BufRead, Write};
+use std::ops::Bound;
+use std::process;
+use anyhow::{anyhow, Result};
+use BPlusTree::tree::{StructureFormat, Tree};
+use BPlusTree::{DecodableU8, EncodableU8};
+
+const USAGE: &str = "usage: bptree [--value <string|bytes|u64>] <file> <command> [args]
+
+options:
+  --value <type>                         数据类型, bytes 按十六进制读写, 默认 string
+
+commands:
+  info                                   文件和配置信息
+  dump-page <offset>                     解析一个页
+  get <key>
+  put <key> <value>
+  del <key>
+  scan [--from <key>] [--to <key>] [--limit <n>]   --to 包含在内
+  stats                                  页数 填充率统计
+  check                                  检查树结构
+  compact                                重写文件 回收空闲页
+  export <dot|json>                      导出树结构
+  backup <path>                          在线备份到 path
+  shell                                  交互模式";
+
+//Value 命令行中可以读写的数据类型
+trait Value: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync {
+    fn parse(s: &str) -> Result<Self>;
+    fn show(&self) -> String;
+}
+
+impl Value for String {
+    fn parse(s: &str) -> Result<Self> {
+        Ok(s.to_string())
+    }
+
+    fn show(&self) -> String {
+        self.clone()
+    }
+}
+
+impl Value for Vec<u8> {
+    fn parse(s: &str) -> Result<Self> {
+        let s = s.strip_prefix("0x").unwrap_or(s);
+        if !s.is_ascii() || !s.len().is_multiple_of(2) {
+            return Err(anyhow!("invalid hex {}", s));
+        }
+        (0..s.len()).step_by(2).map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?)).collect()
+    }
+
+    fn show(&self) -> String {
+        self.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl Value for u64 {
+    fn parse(s: &str) -> Result<Self> {
+        parse_u64(s)
+    }
+
+    fn show(&self) -> String {
+        self.to_string()
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut value_type = "string".to_string();
+    if args.first().map(|s| s.as_str()) == Some("--value") && args.len() > 1 {
+        value_type = args.drain(..2).nth(1).unwrap_or_default();
+    }
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let result = match value_type.as_str() {
+        "string" => open::<String>(&args),
+        "bytes" => open::<Vec<u8>>(&args),
+        "u64" => open::<u64>(&args),
+        other => Err(anyhow!("unknown value type {}\n{}", other, USAGE)),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn open<V: Value>(args: &[String]) -> Result<()> {
+    //不修改数据的命令只读打开, 可以和其他只读打开同时进行
+    let read_only = matches!(args[1].as_str(), "info" | "dump-page" | "get" | "scan" | "stats" | "check" | "export" | "backup");
+    let tree = if read_only { Tree::<u64, V>::open_read_only(&args[0])? } else { Tree::<u64, V>::open(&args[0])? };
+    if args[1] == "shell" {
+        shell(&tree)
+    } else {
+        run(&tree, &args[1..])
+    }
+}
+
+//shell 交互模式 每行一个命令
+fn shell<V: Value>(tree: &Tree<u64, V>) -> Result<()> {
+    let stdin = io::stdin();
+    loop {
+        print!("bptree> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let args: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect();
+        match args.first().map(|s| s.as_str()) {
+            None => continue,
+            Some("quit") | Some("exit") => return Ok(()),
+            Some("help") => println!("{}", USAGE),
+            Some(_) => {
+                if let Err(e) = run(tree, &args) {
+                    println!("error: {}", e);
+                }
+            }
+        }
+    }
+}
+
+fn run<V: Value>(tree: &Tree<u64, V>, args: &[String]) -> Result<()> {
+    let arg = |i: usize| args.get(i).ok_or_else(|| anyhow!("missing argument for {}\n{}", args[0], USAGE));
+    match args[0].as_str() {
+        "info" => {
+            let page = Tree::<u64, V>::page_size() as u64;
+            println!("path: {}", tree.path());
+            println!("file size: {}", tree.file_size());
+            println!("page size: {}", page);
+            println!("pages: {}", tree.file_size() / page);
+            print_page(tree, 0)
+        }
+        "dump-page" => print_page(tree, parse_u64(arg(1)?)?),
+        "get" => {
+            match tree.get(&parse_u64(arg(1)?)?)? {
+                Some(value) => println!("{}", value.show()),
+                None => println!("(not found)"),
+            }
+            Ok(())
+        }
+        "put" => {
+            //shell 中数据可以包含空格
+            let value = args.get(2..).filter(|v| !v.is_empty()).ok_or_else(|| anyhow!("missing value\n{}", USAGE))?.join(" ");
+            if let Some(old) = tree.insert(parse_u64(arg(1)?)?, V::parse(&value)?)? {
+                println!("replaced: {}", old.show());
+            }
+            Ok(())
+        }
+        "del" => {
+            match tree.remove(&parse_u64(arg(1)?)?)? {
+                Some(old) => println!("deleted: {}", old.show()),
+                None => println!("(not found)"),
+            }
+            Ok(())
+        }
+        "scan" => {
+            let mut from = Bound::Unbounded;
+            let mut to = Bound::Unbounded;
+            let mut limit = usize::MAX;
+            let mut i = 1;
+            while i < args.len() {
+                let value = arg(i + 1)?;
+                match args[i].as_str() {
+                    "--from" => from = Bound::Included(parse_u64(value)?),
+                    "--to" => to = Bound::Included(parse_u64(value)?),
+                    "--limit" => limit = value.parse()?,
+                    other => return Err(anyhow!("unknown option {}", other)),
+                }
+                i += 2;
+            }
+            for entry in tree.range((from, to))?.take(limit) {
+                let (key, value) = entry?;
+                println!("{}\t{}", key, value.show());
+            }
+            Ok(())
+        }
+        "stats" => {
+            let stats = tree.stats()?;
+            println!("height: {}", stats.height);
+            println!("pages: root {} middle {} leaf {} extra {} free {}", stats.root_pages, stats.middle_pages, stats.leaf_pages, stats.extra_pages, stats.free_pages);
+            println!("entries: {}", stats.entries);
+            println!("avg leaf fill: {:.1}%", stats.avg_leaf_fill * 100.0);
+            for (i, count) in stats.leaf_fill_histogram.iter().enumerate() {
+                println!("  {:>3}%-{:>3}%: {}", i * 10, (i + 1) * 10, count);
+            }
+            println!("overflow bytes: {}", stats.overflow_bytes);
+            println!("file size: {}", stats.file_size);
+            Ok(())
+        }
+        "check" => {
+            let report = tree.verify()?;
+            println!("pages: {} leaves: {} depth: {}", report.pages, report.leaves, report.depth);
+            for violation in report.violations.iter() {
+                println!("{}", violation);
+            }
+            if !report.is_ok() {
+                return Err(anyhow!("{} violations", report.violations.len()));
+            }
+            println!("ok");
+            Ok(())
+        }
+        "compact" => {
+            println!("reclaimed: {} bytes", tree.compact()?);
+            Ok(())
+        }
+        "export" => {
+            let format = match arg(1)?.as_str() {
+                "dot" => StructureFormat::Dot,
+                "json" => StructureFormat::Json,
+                other => return Err(anyhow!("unknown format {}", other)),
+            };
+            print!("{}", tree.export_structure(format)?);
+            Ok(())
+        }
+        "backup" => {
+            println!("backup lsn: {}", tree.backup_to(arg(1)?)?);
+            Ok(())
+        }
+        other => Err(anyhow!("unknown command {}\n{}", other, USAGE)),
+    }
+}
+
+fn print_page<V: Value>(tree: &Tree<u64, V>, seek: u64) -> Result<()> {
+    let page = tree.inspect_page(seek)?;
+    println!("page {}: flag {:#010b} {}", page.seek, page.flag, page.flag_names().join("|"));
+    println!("  key_count: {} data_count: {} residual_storage_size: {}", page.key_count, page.data_count, page.residual_storage_size);
+    println!("  prev: {} next: {}", page.prev, page.next);
+    if let Some((origin_length, len)) = page.extra_length {
+        println!("  owner key: {:?} origin length: {} length: {}", page.keys.first(), origin_length, len);
+        return Ok(());
+    }
+    println!("  keys: {:?}", page.keys);
+    if !page.key_seek.is_empty() {
+        println!("  key_seek: {:?}", page.key_seek);
+    }
+    for (key, extra) in page.keys.iter().zip(page.extra.iter()) {
+        if let Some(extra) = extra {
+            println!("  extra data: key {} at {}", key, extra);
+        }
+    }
+    Ok(())
+}
+
+//parse_u64 支持十进制和 0x 开头的十六进制
+fn parse_u64(s: &str) -> Result<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => Ok(u64::from_str_radix(hex, 16)?),
+        None => Ok(s.parse()?),
+    }
+}
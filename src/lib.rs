@@ -1,8 +1,12 @@
+#![allow(non_snake_case)]
+
 use std::io::{Cursor, Error, Write};
 use byteorder::{ReadBytesExt, WriteBytesExt};
 
 pub mod tree;
 pub mod node;
+pub mod store;
+pub mod versioned;
 
 pub trait Size {
     fn size() -> u64;
@@ -20,7 +24,8 @@ pub trait DecodableU8 where Self: Sized {
 
 use byteorder::{BigEndian};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ValueTest {
     pub id: u32,
     pub data: String,
@@ -46,15 +51,36 @@ impl DecodableU8 for u64 {
     }
 }
 
-impl EncodableU8 for ValueTest {
+impl EncodableU8 for String {
     fn encode(&self, buf: &mut Vec<u8>) -> Result<u64, Error> {
-        if let Err(e) = buf.write_u32::<BigEndian>(self.id) {
-            return Err(e);
-        }
+        buf.write_all(self.as_bytes())?;
+        Ok(buf.len() as u64)
+    }
+}
 
-        if let Err(e) = buf.write(self.data.as_bytes()) {
-            return Err(e);
-        }
+impl DecodableU8 for String {
+    fn decode(buf: &[u8]) -> Result<(Self, u64), Error> {
+        Ok((String::from_utf8_lossy(buf).to_string(), buf.len() as u64))
+    }
+}
+
+impl EncodableU8 for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<u64, Error> {
+        buf.write_all(self)?;
+        Ok(buf.len() as u64)
+    }
+}
+
+impl DecodableU8 for Vec<u8> {
+    fn decode(buf: &[u8]) -> Result<(Self, u64), Error> {
+        Ok((buf.to_vec(), buf.len() as u64))
+    }
+}
+
+impl EncodableU8 for ValueTest {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<u64, Error> {
+        buf.write_u32::<BigEndian>(self.id)?;
+        buf.write_all(self.data.as_bytes())?;
         Ok(buf.len() as u64)
     }
 }
@@ -75,27 +101,70 @@ impl DecodableU8 for ValueTest {
 }
 
 
+/// serde 适配: 已经实现 Serialize/Deserialize 的类型包一层即可存入树中
+/// 使用 bincode 紧凑编码(变长整数)
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Serde<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl<T> Serde<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> EncodableU8 for Serde<T> {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<u64, Error> {
+        use bincode::Options;
+        bincode::options()
+            .serialize_into(&mut *buf, &self.0)
+            .map_err(|e| Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(buf.len() as u64)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: serde::de::DeserializeOwned> DecodableU8 for Serde<T> {
+    fn decode(buf: &[u8]) -> Result<(Self, u64), Error> {
+        use bincode::Options;
+        let mut rdr = Cursor::new(buf);
+        let data = bincode::options()
+            .deserialize_from(&mut rdr)
+            .map_err(|e| Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok((Serde(data), rdr.position()))
+    }
+}
+
+//temp_path 测试文件放在各自的临时目录中, 测试可以并行运行
+#[cfg(test)]
+pub(crate) fn temp_path(dir: &tempfile::TempDir, name: &str) -> String {
+    dir.path().join(name).to_string_lossy().into_owned()
+}
 
 // tree(pub 接口，缓存lru，并发安全,可变静态变量配置，写入存储)====》》》node(底层驱动decode encode)
 #[cfg(test)]
 mod tests {
     use std::io::{Cursor, Read, Write};
     use byteorder::ReadBytesExt;
-    use crate::{DecodableU8, EncodableU8, ValueTest};
+    use crate::{temp_path, DecodableU8, EncodableU8, ValueTest};
     use std::fs::OpenOptions;
     use std::io::{Seek, SeekFrom};
     use byteorder::{BigEndian, WriteBytesExt};
 
     #[test]
     fn experiment() {
+        let dir = tempfile::tempdir().unwrap();
         {
 
             let mut fd = OpenOptions::new()
                 .create(true)
                 .write(true)
                 .read(true)
-                .open("./experiment.db").expect("文件打开 or 创建  失败");
-            let  data: [u8; 16384] = [0; 16384];
+                .truncate(false)
+                .open(temp_path(&dir, "experiment.db")).expect("文件打开 or 创建  失败");
             let value = ValueTest {
                 id: 16,
                 data: String::from("asadfoqnljasdfjoij"),
@@ -107,12 +176,13 @@ mod tests {
             wtr.write_u64::<BigEndian>(value_len).unwrap();
             //写入数据
             println!("{:?}", fd.seek(SeekFrom::Start(0)));
-            fd.write(&wtr).unwrap();
-            fd.write(&encode_value_u8).unwrap();
+            fd.write_all(&wtr).unwrap();
+            fd.write_all(&encode_value_u8).unwrap();
             //读取数据
             let mut data: [u8; 16384] = [0; 16384];
             println!("{:?}", fd.seek(SeekFrom::Start(0)));
-            fd.read(&mut data).unwrap();
+            //文件只有写入的长度, 读取超出时报错
+            fd.read_exact(&mut data[..wtr.len() + encode_value_u8.len()]).unwrap();
             let data_len = Cursor::new(&data[0..8]).read_u64::<BigEndian>().unwrap();
             println!("read data {:?}", data_len);
             println!("{:?}", ValueTest::decode(&data[8..=data_len as usize]).unwrap());
@@ -143,5 +213,38 @@ mod tests {
             println!("COUNTER: {}", COUNTER.load(Ordering::Relaxed));
         }
     }
+
+    #[test]
+    fn bytes_codec() {
+        for value in ["", "bytes", "字节"] {
+            let mut buf = vec![];
+            assert_eq!(value.to_string().encode(&mut buf).unwrap(), value.len() as u64);
+            assert_eq!(String::decode(&buf).unwrap(), (value.to_string(), value.len() as u64));
+            let bytes = value.as_bytes().to_vec();
+            let mut buf = vec![];
+            bytes.encode(&mut buf).unwrap();
+            assert_eq!(Vec::<u8>::decode(&buf).unwrap(), (bytes, value.len() as u64));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_value() {
+        use crate::Serde;
+        let value = Serde(ValueTest {
+            id: 16,
+            data: String::from("asadfoqnljasdfjoij"),
+        });
+        let mut buf: Vec<u8> = vec![];
+        let len = value.encode(&mut buf).unwrap();
+        assert_eq!(len, buf.len() as u64);
+        // 尾部多余数据不影响解析
+        buf.extend_from_slice(&[0xff; 4]);
+        let (decode, size) = Serde::<ValueTest>::decode(&buf).unwrap();
+        assert_eq!(size, len);
+        assert_eq!(decode.0.id, 16);
+        assert_eq!(decode.into_inner().data, "asadfoqnljasdfjoij");
+        assert!(Serde::<ValueTest>::decode(&buf[0..3]).is_err());
+    }
 }
 
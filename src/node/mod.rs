@@ -1 +1,4 @@
-mod node;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub(crate) mod node;
+
+pub use node::BPlusError;
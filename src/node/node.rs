@@ -12,22 +12,50 @@ use std::io::Cursor;
 // From<std::io::Error>
 /// 是 root 节点也有可能是叶子节点(初始状态)
 //无效空闲列表
-const INVALID: u8 = 0b00000000;
+pub(crate) const INVALID: u8 = 0b00000000;
 //有效位
-const VALID: u8 = 0b00000001;
-const ROOT: u8 = 0b00000010;
+pub(crate) const VALID: u8 = 0b00000001;
+pub(crate) const ROOT: u8 = 0b00000010;
 //中间节点
-const MIDDLE_NODE: u8 = 0b00000100;
+pub(crate) const MIDDLE_NODE: u8 = 0b00000100;
 //叶子
-const LEAF: u8 = 0b00001000;
+pub(crate) const LEAF: u8 = 0b00001000;
 //额外数据页
-const EXTRA_DATA: u8 = 0b00010000;
-const NODE_FIXED_SIZE: usize = 41;
+pub(crate) const EXTRA_DATA: u8 = 0b00010000;
+pub(crate) const NODE_FIXED_SIZE: usize = 41;
+//页头中 prev next 的位置
+pub(crate) const PREV_OFFSET: u64 = 25;
+pub(crate) const NEXT_OFFSET: u64 = 33;
 
 static PAGE_SIZE: AtomicUsize = AtomicUsize::new(16 * 1024);
+#[allow(dead_code)]
 static MAX_KEY: AtomicUsize = AtomicUsize::new(3);
 static DATA_LENGTH: AtomicUsize = AtomicUsize::new(256);
 
+pub(crate) fn page_size() -> usize {
+    PAGE_SIZE.load(Ordering::Relaxed)
+}
+
+//叶子一条数据在页内最多可保存的数据长度, 超出部分写入额外数据页
+pub(crate) fn data_max_len<K: Size>() -> u64 {
+    DATA_LENGTH.load(Ordering::Relaxed) as u64 - K::size() - 16
+}
+
+//叶子一条数据在页内占用的大小
+pub(crate) fn leaf_entry_size<K: Size>(encode_len: u64) -> usize {
+    (16 + K::size() + encode_len.min(data_max_len::<K>())) as usize
+}
+
+//中间节点最多可保存的key个数
+pub(crate) fn middle_max_key<K: Size>() -> usize {
+    (page_size() - NODE_FIXED_SIZE - 8) / (K::size() as usize + 8)
+}
+
+//额外数据页可保存的数据长度
+pub(crate) fn extra_capacity<K: Size>() -> usize {
+    page_size() - NODE_FIXED_SIZE - 24 - K::size() as usize
+}
+
 #[derive(Debug)]
 pub struct ExtraData {
     pub seek: u64,
@@ -35,42 +63,95 @@ pub struct ExtraData {
     pub next: Option<Vec<ExtraData>>,
 }
 
+// 额外数据页: 页头 | 本页及之后数据长度 8 | 本页数据长度 8 | 所属数据的 key | 数据 | 下一页位置 8
 impl ExtraData {
-    pub(crate) fn data_extra_decode(b: &Vec<u8>, seek_index: u64) -> Result<(ExtraData, u64)> {
+    pub(crate) fn data_extra_decode<K: Size>(b: &[u8], seek_index: u64) -> Result<(ExtraData, u64)> {
         let mut seek = NODE_FIXED_SIZE;
         let mut result = ExtraData {
             seek: seek_index,
             data: None,
             next: None,
         };
-        let extra_origin_length = Cursor::new(&b[seek..seek + 8]).read_u64::<BigEndian>()?;
-        let extra_len = Cursor::new(&b[seek + 8..seek + 16]).read_u64::<BigEndian>()?;
-        seek += 16;
+        let extra_origin_length = Cursor::new(&b[seek..seek + 8]).read_u64::<BigEndian>()? as usize;
+        let extra_len = Cursor::new(&b[seek + 8..seek + 16]).read_u64::<BigEndian>()? as usize;
+        seek += 16 + K::size() as usize;
+        //损坏的长度越界时返回错误
+        let end = seek.saturating_add(extra_len);
+        let out_of_page = || BPlusError::NodeError(format!("extra page {}: length {} out of page", seek_index, extra_len));
+        result.data = Some(b.get(seek..end).ok_or_else(out_of_page)?.to_vec());
         if extra_origin_length > extra_len {
-            result.data = Some(b[seek..seek + extra_origin_length].to_vec());
-            return Ok((result, Cursor::new(&b[seek + extra_origin_length..seek + extra_origin_length + 8]).read_u64::<BigEndian>()?));
+            let next = b.get(end..end.saturating_add(8)).ok_or_else(out_of_page)?;
+            return Ok((result, Cursor::new(next).read_u64::<BigEndian>()?));
         }
-        result.data = Some(b[seek..seek + extra_origin_length].to_vec());
         Ok((result, 0))
     }
+
+    //data_extra_encode 额外数据页 origin_length 为本页及之后所有页的数据长度
+    pub(crate) fn data_extra_encode<K: Size + EncodableU8>(origin_length: u64, key: &K, data: &[u8], prev: u64, next: u64) -> Result<Vec<u8>, BPlusError> {
+        let max_page_size = PAGE_SIZE.load(Ordering::Relaxed);
+        if data.len() > extra_capacity::<K>() {
+            return Err(BPlusError::PageMax());
+        }
+        let mut b: Vec<u8> = Vec::with_capacity(max_page_size);
+        b.push(EXTRA_DATA | VALID);
+        b.write_u64::<BigEndian>(0)?;
+        b.write_u64::<BigEndian>(0)?;
+        b.write_u64::<BigEndian>((extra_capacity::<K>() - data.len()) as u64)?;
+        b.write_u64::<BigEndian>(prev)?;
+        b.write_u64::<BigEndian>(next)?;
+        b.write_u64::<BigEndian>(origin_length)?;
+        b.write_u64::<BigEndian>(data.len() as u64)?;
+        key.encode(&mut b)?;
+        b.extend_from_slice(data);
+        if origin_length > data.len() as u64 {
+            b.write_u64::<BigEndian>(next)?;
+        }
+        b.resize(max_page_size, 0);
+        Ok(b)
+    }
+
+    //data_extra_length 额外数据页 (本页及之后数据长度, 本页数据长度)
+    pub(crate) fn data_extra_length(b: &[u8]) -> Result<(u64, u64)> {
+        let seek = NODE_FIXED_SIZE;
+        Ok((Cursor::new(&b[seek..seek + 8]).read_u64::<BigEndian>()?, Cursor::new(&b[seek + 8..seek + 16]).read_u64::<BigEndian>()?))
+    }
+
+    //data_extra_key 额外数据页所属数据的 key
+    pub(crate) fn data_extra_key<K: Size + DecodableU8>(b: &[u8]) -> Result<K> {
+        let seek = NODE_FIXED_SIZE + 16;
+        Ok(K::decode(&b[seek..seek + K::size() as usize])?.0)
+    }
+
+    //data_extra_set_next 修改额外数据页的下一页位置
+    pub(crate) fn data_extra_set_next<K: Size>(b: &mut [u8], next: u64) -> Result<()> {
+        let seek = NODE_FIXED_SIZE;
+        let extra_origin_length = Cursor::new(&b[seek..seek + 8]).read_u64::<BigEndian>()? as usize;
+        let extra_len = Cursor::new(&b[seek + 8..seek + 16]).read_u64::<BigEndian>()? as usize;
+        b[NEXT_OFFSET as usize..NEXT_OFFSET as usize + 8].copy_from_slice(&next.to_be_bytes());
+        if extra_origin_length > extra_len {
+            let seek = seek + 16 + K::size() as usize + extra_len;
+            b[seek..seek + 8].copy_from_slice(&next.to_be_bytes());
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
-struct Node<K, V> {
-    flag: u8,
-    is_change: bool,
+pub(crate) struct Node<K, V> {
+    pub(crate) flag: u8,
+    pub(crate) is_change: bool,
     // 空间换时间
-    key: Option<Vec<Box<K>>>,
-    key_seek: Option<Vec<u64>>,
+    pub(crate) key: Option<Vec<Box<K>>>,
+    pub(crate) key_seek: Option<Vec<u64>>,
     // 空间换时间
-    value: Option<Vec<Box<V>>>,
+    pub(crate) value: Option<Vec<Box<V>>>,
     pub extra_data: Option<Vec<Option<ExtraData>>>,
-    seek_start: u64,
-    key_count: u64,
-    data_count: u64,
-    residual_storage_size: u64,
-    next: u64,
-    prev: u64,
+    pub(crate) seek_start: u64,
+    pub(crate) key_count: u64,
+    pub(crate) data_count: u64,
+    pub(crate) residual_storage_size: u64,
+    pub(crate) next: u64,
+    pub(crate) prev: u64,
     _k: PhantomData<K>,
     // key value 需要固定泛型
     _v: PhantomData<V>,
@@ -81,9 +162,21 @@ impl<K, V> Node<K, V> where
     V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync
 {
     //new_node_from_byte u8转换成node
-    pub(crate) fn new_node_from_byte(seek: u64, data: Vec<u8>) -> Result<Self> {
-        let mut node_data = Node::<K, V>::default();
-        node_data.flag = data[0];
+    pub(crate) fn new_node_from_byte(seek: u64, data: &[u8]) -> Result<Self> {
+        let mut node_data = Self::new_node_header(seek, data)?;
+        //有额外数据页时由 tree 读取额外数据页后再解析
+        if node_data.is_leaf() && !node_data.need_extra() {
+            node_data.data_decode(data)?
+        }
+        Ok(node_data)
+    }
+
+    //new_node_header 只解析页头和 key, 叶子的数据不解析
+    pub(crate) fn new_node_header(seek: u64, data: &[u8]) -> Result<Self> {
+        let mut node_data = Node::<K, V> {
+            flag: data[0],
+            ..Default::default()
+        };
 
         if (node_data.flag & VALID) != VALID {
             return Ok(node_data);
@@ -101,16 +194,210 @@ impl<K, V> Node<K, V> where
             return Ok(node_data);
         }
         if (node_data.flag & MIDDLE_NODE) == MIDDLE_NODE {
-            node_data.key_decode(&data)?;
+            node_data.key_decode(data)?;
             return Ok(node_data);
         }
         if (node_data.flag & LEAF) == LEAF {
-            node_data.data_decode(&data)?
+            node_data.data_decode_init(data)?;
         }
 
         Ok(node_data)
     }
 
+    //check_page 检查页内长度不越界, 损坏的页直接解析会越界
+    pub(crate) fn check_page(data: &[u8]) -> Result<(), String> {
+        let read = |seek: usize| -> Result<u64, String> {
+            data.get(seek..seek + 8)
+                .map(|b| u64::from_be_bytes(b.try_into().unwrap_or([0; 8])))
+                .ok_or_else(|| format!("read out of page at {}", seek))
+        };
+        let page = data.len();
+        if page != page_size() {
+            return Err(format!("page length {}", page));
+        }
+        let flag = data[0];
+        if (flag & VALID) != VALID {
+            return Ok(());
+        }
+        let key_size = K::size() as usize;
+        let key_count = read(1)?;
+        let data_count = read(9)?;
+        let residual = read(17)?;
+        if (flag & EXTRA_DATA) == EXTRA_DATA {
+            let origin_length = read(NODE_FIXED_SIZE)?;
+            let len = read(NODE_FIXED_SIZE + 8)?;
+            if len as usize > extra_capacity::<K>() || origin_length < len {
+                return Err(format!("extra length {} origin length {}", len, origin_length));
+            }
+            return Ok(());
+        }
+        if (flag & MIDDLE_NODE) == MIDDLE_NODE {
+            if key_count as usize > middle_max_key::<K>() {
+                return Err(format!("key count {} out of page", key_count));
+            }
+            return Ok(());
+        }
+        if (flag & LEAF) == LEAF {
+            let max_len = data_max_len::<K>();
+            let mut seek = NODE_FIXED_SIZE;
+            for i in 0..data_count.min(page as u64) {
+                let origin_length = read(seek)?;
+                let len = read(seek + 8)?;
+                if len > max_len || origin_length < len || (origin_length > len && len != max_len) {
+                    return Err(format!("data {} length {} origin length {}", i, len, origin_length));
+                }
+                seek += 16 + key_size + len as usize;
+                if seek > page {
+                    return Err(format!("data {} out of page", i));
+                }
+            }
+            if data_count > page as u64 {
+                return Err(format!("data count {} out of page", data_count));
+            }
+            if residual != (page - seek) as u64 {
+                return Err(format!("residual storage size {} used {}", residual, seek));
+            }
+            return Ok(());
+        }
+        Err(format!("unknown flag {:#010b}", flag))
+    }
+
+    //need_extra 是否还有未读取的额外数据页
+    pub(crate) fn need_extra(&self) -> bool {
+        if let Some(extra) = &self.extra_data {
+            return extra.iter().any(|v| matches!(v, Some(e) if e.data.is_none()));
+        }
+        false
+    }
+
+    //leaf 新建叶子节点
+    pub(crate) fn new_leaf(seek: u64, key: Vec<Box<K>>, value: Vec<Box<V>>) -> Self {
+        Node::<K, V> {
+            flag: LEAF | VALID,
+            is_change: true,
+            key_count: key.len() as u64,
+            data_count: value.len() as u64,
+            extra_data: Some(value.iter().map(|_| None).collect()),
+            key: Some(key),
+            value: Some(value),
+            seek_start: seek,
+            ..Default::default()
+        }
+    }
+
+    //new_middle 新建中间节点 key_seek 比 key 多一个
+    pub(crate) fn new_middle(seek: u64, key: Vec<Box<K>>, key_seek: Vec<u64>) -> Self {
+        Node::<K, V> {
+            flag: MIDDLE_NODE | VALID,
+            is_change: true,
+            key_count: key.len() as u64,
+            key: Some(key),
+            key_seek: Some(key_seek),
+            seek_start: seek,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn is_leaf(&self) -> bool {
+        (self.flag & LEAF) == LEAF
+    }
+
+    //leaf_insert 叶子 index 位置插入数据
+    pub(crate) fn leaf_insert(&mut self, index: usize, key: K, value: V, extra: Option<ExtraData>) {
+        self.key.get_or_insert_with(Vec::new).insert(index, Box::new(key));
+        self.value.get_or_insert_with(Vec::new).insert(index, Box::new(value));
+        self.extra_data.get_or_insert_with(Vec::new).insert(index, extra);
+        self.key_count += 1;
+        self.data_count += 1;
+        self.is_change = true;
+    }
+
+    //leaf_replace 替换 index 位置数据 返回旧数据和旧的额外数据页
+    pub(crate) fn leaf_replace(&mut self, index: usize, value: V, extra: Option<ExtraData>) -> (V, Option<ExtraData>) {
+        let old = std::mem::replace(&mut self.value.get_or_insert_with(Vec::new)[index], Box::new(value));
+        let old_extra = std::mem::replace(&mut self.extra_data.get_or_insert_with(Vec::new)[index], extra);
+        self.is_change = true;
+        (*old, old_extra)
+    }
+
+    //leaf_remove 删除 index 位置数据 返回数据和额外数据页
+    pub(crate) fn leaf_remove(&mut self, index: usize) -> (V, Option<ExtraData>) {
+        self.key.get_or_insert_with(Vec::new).remove(index);
+        let value = self.value.get_or_insert_with(Vec::new).remove(index);
+        let extra = self.extra_data.get_or_insert_with(Vec::new).remove(index);
+        self.key_count -= 1;
+        self.data_count -= 1;
+        self.is_change = true;
+        (*value, extra)
+    }
+
+    //leaf_append 右边叶子的数据全部追加到当前叶子
+    pub(crate) fn leaf_append(&mut self, right: Self) {
+        self.key.get_or_insert_with(Vec::new).extend(right.key.unwrap_or_default());
+        self.value.get_or_insert_with(Vec::new).extend(right.value.unwrap_or_default());
+        self.extra_data.get_or_insert_with(Vec::new).extend(right.extra_data.unwrap_or_default());
+        self.key_count += right.key_count;
+        self.data_count += right.data_count;
+        self.next = right.next;
+        self.is_change = true;
+    }
+
+    //data_size 叶子每条数据在页内占用大小
+    pub(crate) fn data_size(&self) -> Result<Vec<usize>, BPlusError> {
+        let mut size = vec![];
+        let mut data_encode: Vec<u8> = vec![];
+        if let Some(value) = &self.value {
+            for v in value.iter() {
+                data_encode.clear();
+                size.push(leaf_entry_size::<K>(v.encode(&mut data_encode)?));
+            }
+        }
+        Ok(size)
+    }
+
+    //leaf_split_off 叶子从 at 位置拆分 返回右半部分
+    pub(crate) fn leaf_split_off(&mut self, at: usize, seek: u64) -> Self {
+        let key = self.key.get_or_insert_with(Vec::new).split_off(at);
+        let value = self.value.get_or_insert_with(Vec::new).split_off(at);
+        let extra = self.extra_data.get_or_insert_with(Vec::new).split_off(at);
+        self.key_count = at as u64;
+        self.data_count = at as u64;
+        self.is_change = true;
+        let mut right = Node::new_leaf(seek, key, value);
+        right.extra_data = Some(extra);
+        right
+    }
+
+    //middle_insert 中间节点插入 key, 右边子节点位置是 seek
+    pub(crate) fn middle_insert(&mut self, index: usize, key: K, seek: u64) {
+        self.key.get_or_insert_with(Vec::new).insert(index, Box::new(key));
+        self.key_seek.get_or_insert_with(Vec::new).insert(index + 1, seek);
+        self.key_count += 1;
+        self.is_change = true;
+    }
+
+    //middle_remove 中间节点删除第 index 个子节点和它左边的 key, 第一个子节点删除右边的 key
+    pub(crate) fn middle_remove(&mut self, index: usize) {
+        let keys = self.key.get_or_insert_with(Vec::new);
+        if !keys.is_empty() {
+            keys.remove(index.saturating_sub(1));
+            self.key_count -= 1;
+        }
+        self.key_seek.get_or_insert_with(Vec::new).remove(index);
+        self.is_change = true;
+    }
+
+    //middle_split_off 中间节点从 at 位置拆分, at 位置的 key 上移到父节点
+    pub(crate) fn middle_split_off(&mut self, at: usize, seek: u64) -> (K, Self) {
+        let keys = self.key.get_or_insert_with(Vec::new);
+        let mut right_key = keys.split_off(at);
+        let up = right_key.remove(0);
+        let right_seek = self.key_seek.get_or_insert_with(Vec::new).split_off(at + 1);
+        self.key_count = at as u64;
+        self.is_change = true;
+        (*up, Node::new_middle(seek, right_key, right_seek))
+    }
+
     pub(crate) fn stop(&self) -> Result<Vec<u8>, BPlusError> {
         let max_page_size = PAGE_SIZE.load(Ordering::Relaxed);
         let mut data: Vec<u8> = Vec::with_capacity(max_page_size);
@@ -143,17 +430,17 @@ impl<K, V> Node<K, V> where
         if (self.flag & MIDDLE_NODE) == MIDDLE_NODE {
             let mut data_u8 = self.key_encode()?;
             data.append(&mut data_u8);
-            if data.len() < max_page_size {
-                unsafe {
-                    data.set_len(max_page_size);
-                }
+            if data.len() > max_page_size {
+                return Err(BPlusError::PageMax());
             }
+            data.resize(max_page_size, 0);
         } else if (self.flag & LEAF) == LEAF {
             let mut data_u8 = self.data_encode()?;
             data.append(&mut data_u8);
             //剩余数据容量
             wtr.write_u64::<BigEndian>((max_page_size - data.len()) as u64)?;
-            data[residual_storage_size_index - 1..residual_storage_size_index + 7] = wtr[0..8]
+            data[residual_storage_size_index..residual_storage_size_index + 8].copy_from_slice(&wtr[0..8]);
+            data.resize(max_page_size, 0);
         }
 
         Ok(data)
@@ -178,22 +465,20 @@ impl<K, V> Node<K, V> where
 
                 //可变长度显示
                 if encode_len > data_max_len {
-                    if let Some(extra_vec) = &self.extra_data {
-                        if let Some(extra) = &extra_vec[i] {
-                            //写入保存长度
-                            temp.write_u64::<BigEndian>(data_max_len)?;
-                            data_u8.append(&mut temp);
-                            //写入key
-                            key[i].encode(&mut key_encode)?;
-                            data_u8.append(&mut key_encode);
-
-                            // data
-                            v.encode(&mut data_encode)?;
-                            data_u8.append(&mut data_encode[0..data_max_len - 8]);
-
-                            temp.write_u64::<BigEndian>(extra.seek)?;
-                            data_u8.append(&mut temp);
-                        }
+                    if let Some(extra) = self.extra_data.as_ref().and_then(|e| e.get(i)).and_then(|e| e.as_ref()) {
+                        //写入保存长度
+                        temp.write_u64::<BigEndian>(data_max_len)?;
+                        data_u8.append(&mut temp);
+                        //写入key
+                        key[i].encode(&mut key_encode)?;
+                        data_u8.append(&mut key_encode);
+
+                        // data
+                        data_u8.extend_from_slice(&data_encode[0..(data_max_len - 8) as usize]);
+                        data_encode.clear();
+
+                        temp.write_u64::<BigEndian>(extra.seek)?;
+                        data_u8.append(&mut temp);
                     } else {
                         //没有额外数据页
                         return Err(BPlusError::MissingExtraData());
@@ -218,7 +503,7 @@ impl<K, V> Node<K, V> where
     }
 
     //data_decode
-    pub(crate) fn data_decode(&mut self, b: &Vec<u8>) -> Result<()> {
+    pub(crate) fn data_decode(&mut self, b: &[u8]) -> Result<()> {
         if self.data_count > 0 {
             let mut i: usize = 0;
             let data_count: usize = self.data_count as usize;
@@ -245,16 +530,17 @@ impl<K, V> Node<K, V> where
                         value.push(Box::new(V::decode(&data_decode_vec)?.0));
                         seek += node_data_not_extra_length;
                     } else {
-                        data_decode_vec.extend_from_slice(&b[seek..seek + data_length]);
+                        data_decode_vec.extend_from_slice(&b[seek..seek + data_length as usize]);
                         value.push(Box::new(V::decode(&data_decode_vec)?.0));
-                        seek += data_length;
-                    }
-                    unsafe {
-                        data_decode_vec.set_len(0)
+                        seek += data_length as usize;
                     }
+                    data_decode_vec.clear();
                     i += 1;
                 }
             }
+            self.value = Some(value);
+        } else {
+            self.value = Some(vec![]);
         }
 
         Ok(())
@@ -262,7 +548,7 @@ impl<K, V> Node<K, V> where
 
 
     //data_decode 因为有可变长数据的存在，只是解析出是否有可变长的额外数据
-    pub(crate) fn data_decode_init(&mut self, b: &Vec<u8>) -> Result<()> {
+    pub(crate) fn data_decode_init(&mut self, b: &[u8]) -> Result<()> {
         if self.data_count > 0 {
             let mut i: u64 = 0;
             let mut seek = NODE_FIXED_SIZE;
@@ -290,21 +576,37 @@ impl<K, V> Node<K, V> where
                     }));
                     seek += 8;
                 } else {
-                    seek += data_length;
+                    seek += data_length as usize;
                     extra_data.push(None)
                 }
                 i += 1;
             }
             self.key = Some(key);
-            self.key_seek = Some(key_seek);
             self.extra_data = Some(extra_data);
+        } else {
+            self.key = Some(vec![]);
+            self.extra_data = Some(vec![]);
         }
         Ok(())
     }
 
 
+    //data_origin_lengths 叶子每条数据的原始长度, 超出页内保存长度的部分在额外数据页中
+    pub(crate) fn data_origin_lengths(&self, b: &[u8]) -> Result<Vec<u64>> {
+        let mut seek = NODE_FIXED_SIZE;
+        let key_size = K::size() as usize;
+        let mut origin_lengths = Vec::with_capacity(self.data_count as usize);
+        for _ in 0..self.data_count {
+            let data_origin_length = Cursor::new(&b[seek..seek + 8]).read_u64::<BigEndian>()?;
+            let data_length = Cursor::new(&b[seek + 8..seek + 16]).read_u64::<BigEndian>()?;
+            seek += 16 + key_size + data_length as usize;
+            origin_lengths.push(data_origin_length);
+        }
+        Ok(origin_lengths)
+    }
+
     //key_decode key 编码处理
-    pub(crate) fn key_decode(&mut self, b: &Vec<u8>) -> Result<()> {
+    pub(crate) fn key_decode(&mut self, b: &[u8]) -> Result<()> {
         if self.key_count > 0 {
             let mut i: u64 = 0;
             let mut seek = NODE_FIXED_SIZE;
@@ -321,6 +623,11 @@ impl<K, V> Node<K, V> where
             key_seek.push(Cursor::new(&b[seek..seek + 8]).read_u64::<BigEndian>()?);
             self.key = Some(key);
             self.key_seek = Some(key_seek);
+        } else {
+            //删除后只剩一个子节点
+            let seek = NODE_FIXED_SIZE;
+            self.key = Some(vec![]);
+            self.key_seek = Some(vec![Cursor::new(&b[seek..seek + 8]).read_u64::<BigEndian>()?]);
         }
         Ok(())
     }
@@ -328,9 +635,9 @@ impl<K, V> Node<K, V> where
     //key_encode key 转换u8
     pub(crate) fn key_encode(&self) -> Result<Vec<u8>, BPlusError> {
         if let (Some(key), Some(index)) = (&self.key, &self.key_seek) {
-            if key.len() > 0 {
+            if !key.is_empty() {
                 //偏移固定 u64 大小
-                let mut key_u8: Vec<u8> = Vec::with_capacity(((K::size() + 8) * &self.key_count + 8) as usize);
+                let mut key_u8: Vec<u8> = Vec::with_capacity(((K::size() + 8) * self.key_count + 8) as usize);
                 let mut key_encode: Vec<u8> = Vec::with_capacity(K::size() as usize);
                 let mut index_seek = vec![];
                 for (k, v) in key.iter().enumerate() {
@@ -346,6 +653,9 @@ impl<K, V> Node<K, V> where
                 key_u8.append(&mut index_seek);
                 return Ok(key_u8);
             }
+            if index.len() == 1 {
+                return Ok(index[0].to_be_bytes().to_vec());
+            }
         }
         Err(BPlusError::NodeError("not key".to_string()))
     }
@@ -358,7 +668,7 @@ impl<K, V> Node<K, V> where
 fn join_extra(extra: &ExtraData) -> Vec<u8> {
     let mut extra_data: Vec<u8> = Vec::new();
     if let Some(data) = &extra.data {
-        extra_data.extend_from_slice(&data);
+        extra_data.extend_from_slice(data);
     }
     if let Some(data) = &extra.next {
         for v in data.iter() {
@@ -399,6 +709,16 @@ pub enum BPlusError {
     MissingExtraData(),
     #[error("page max error")]
     PageMax(),
+    #[error("bulk load input not sorted error")]
+    Unsorted(),
+    #[error("tree not empty error")]
+    NotEmpty(),
+    #[error("direct io alignment error: {0}")]
+    Unaligned(String),
+    #[error("file locked by another opener: {0}")]
+    Locked(String),
+    #[error("tree opened read only")]
+    ReadOnly(),
 }
 
 
@@ -410,37 +730,13 @@ impl From<std::io::Error> for BPlusError {
 
 #[cfg(test)]
 mod tests {
-    use std::fs::OpenOptions;
-    use std::io::{Read, Seek, SeekFrom, Write};
     use std::marker::PhantomData;
     use crate::node::node::{LEAF, MIDDLE_NODE, Node, VALID};
+    use crate::store::{MemoryStore, PageStore};
 
-    #[test]
-    fn data_encode() {
-        let mut fd = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open("./data.db").expect("文件打开 or 创建  失败");
-        let mut data = Vec::with_capacity(16384);
-        unsafe {
-            data.set_len(16384);
-        }
-        fd.seek(SeekFrom::Start(0)).unwrap();
-        fd.read(&mut data).unwrap();
-        let node = Node::<u64, u64>::new_node_from_byte(0, data).unwrap();
-        println!("{:?}", node)
-    }
-
-    #[test]
-    fn data_decode() {
-        let mut fd = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open("./data.db").expect("文件打开 or 创建  失败");
+    fn leaf() -> Node<u64, u64> {
         let key: Vec<Box<u64>> = vec![Box::new(1), Box::new(2), Box::new(3), Box::new(4)];
-        let node = Node::<u64, u64> {
+        Node::<u64, u64> {
             flag: LEAF | VALID,
             is_change: true,
             key: Some(key),
@@ -455,21 +751,12 @@ mod tests {
             prev: 0,
             _k: PhantomData,
             _v: PhantomData,
-        };
-        let u8data = node.stop().unwrap();
-        fd.seek(SeekFrom::Start(node.seek_start)).unwrap();
-        fd.write(&u8data).unwrap();
+        }
     }
 
-    #[test]
-    fn node_key() {
-        let mut fd = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open("./key.db").expect("文件打开 or 创建  失败");
+    fn middle() -> Node<u64, u64> {
         let key: Vec<Box<u64>> = vec![Box::new(3), Box::new(4)];
-        let node = Node::<u64, u64> {
+        Node::<u64, u64> {
             flag: MIDDLE_NODE | VALID,
             is_change: true,
             key: Some(key),
@@ -484,26 +771,67 @@ mod tests {
             prev: 0,
             _k: PhantomData,
             _v: PhantomData,
-        };
-        let u8data = node.stop().unwrap();
-        fd.seek(SeekFrom::Start(node.seek_start)).unwrap();
-        fd.write(&u8data).unwrap();
+        }
+    }
+
+    //read 从内存存储读取一页并解析
+    fn read(store: &MemoryStore, seek: u64) -> Node<u64, u64> {
+        let mut data = vec![0u8; 16384];
+        store.read_page(seek, &mut data).unwrap();
+        Node::<u64, u64>::new_node_from_byte(seek, &data).unwrap()
+    }
+
+    #[test]
+    fn data_encode() {
+        let store = MemoryStore::new();
+        let node = leaf();
+        store.write_page(node.seek_start, &node.stop().unwrap()).unwrap();
+        let node = read(&store, 0);
+        assert_eq!(node.key.unwrap().iter().map(|k| **k).collect::<Vec<u64>>(), vec![1, 2, 3, 4]);
+        assert_eq!(node.value.unwrap().iter().map(|v| **v).collect::<Vec<u64>>(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn leaf_encode() {
+        let u8data = leaf().stop().unwrap();
+        assert_eq!(u8data.len(), 16384);
+        assert_eq!(u8data[0], LEAF | VALID);
+        //每条数据 16 + key 8 + 数据 8
+        assert_eq!(u64::from_be_bytes(u8data[17..25].try_into().unwrap()), 16384 - 41 - 4 * 32);
+    }
+
+    #[test]
+    fn node_key() {
+        let u8data = middle().stop().unwrap();
+        assert_eq!(u8data.len(), 16384);
+        assert_eq!(u8data[0], MIDDLE_NODE | VALID);
+        assert_eq!(u64::from_be_bytes(u8data[1..9].try_into().unwrap()), 2);
     }
 
     #[test]
     fn key_encode() {
-        let mut fd = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open("./key.db").expect("文件打开 or 创建  失败");
-        let mut data = Vec::with_capacity(16384);
-        unsafe {
-            data.set_len(16384);
-        }
-        fd.seek(SeekFrom::Start(0)).unwrap();
-        fd.read(&mut data).unwrap();
-        let node = Node::<u64, u64>::new_node_from_byte(0, data).unwrap();
-        println!("{:?}", node)
+        let store = MemoryStore::new();
+        let node = middle();
+        store.write_page(node.seek_start, &node.stop().unwrap()).unwrap();
+        let node = read(&store, 0);
+        assert_eq!(node.key.unwrap().iter().map(|k| **k).collect::<Vec<u64>>(), vec![3, 4]);
+        assert_eq!(node.key_seek.unwrap(), vec![16384, 32768, 49152]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn middle_remove() {
+        let store = MemoryStore::new();
+        let mut node = middle();
+        //删除第一个子节点时删除右边的 key
+        node.middle_remove(0);
+        assert_eq!(node.key.as_ref().unwrap().iter().map(|k| **k).collect::<Vec<u64>>(), vec![4]);
+        assert_eq!(node.key_seek.as_ref().unwrap(), &vec![32768, 49152]);
+        //只剩一个没有 key 的子节点
+        node.middle_remove(1);
+        assert_eq!(node.key_count, 0);
+        store.write_page(node.seek_start, &node.stop().unwrap()).unwrap();
+        let node = read(&store, 0);
+        assert!(node.key.unwrap().is_empty());
+        assert_eq!(node.key_seek.unwrap(), vec![32768]);
+    }
+}
@@ -0,0 +1,570 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use anyhow::Result;
+use byteorder::{BigEndian, ByteOrder};
+use crate::node::node::{page_size, BPlusError};
+use crate::store::{FileStore, PageStore};
+
+//物理空间按扇区分配, 每个压缩页占用连续的若干扇区
+const SECTOR: u64 = 512;
+const MAGIC: [u8; 2] = *b"CP";
+//扇区组头: magic(2) state(1) codec(1) sectors(4) logical(8) generation(8) len(4)
+const RUN_HEADER: usize = 28;
+const RUN_USED: u8 = 1;
+const RUN_FREE: u8 = 2;
+//压缩后没有变小时原样保存
+const CODEC_RAW: u8 = 0;
+#[cfg(feature = "lz4")]
+const CODEC_LZ4: u8 = 1;
+#[cfg(feature = "zstd")]
+const CODEC_ZSTD: u8 = 2;
+
+/// 页压缩算法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageCodec {
+    #[cfg(feature = "lz4")]
+    Lz4,
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+}
+
+impl PageCodec {
+    //compress 返回 (codec, 数据), 压缩后没有变小时返回原数据
+    fn compress(&self, page: &[u8]) -> Result<(u8, Vec<u8>)> {
+        let (codec, data) = match self {
+            #[cfg(feature = "lz4")]
+            PageCodec::Lz4 => (CODEC_LZ4, lz4_flex::block::compress(page)),
+            #[cfg(feature = "zstd")]
+            PageCodec::Zstd { level } => (CODEC_ZSTD, zstd::bulk::compress(page, *level)?),
+        };
+        if data.len() >= page.len() {
+            return Ok((CODEC_RAW, page.to_vec()));
+        }
+        Ok((codec, data))
+    }
+}
+
+//decompress 按扇区组头中的 codec 解压, 同一个文件中可以混合不同的 codec
+fn decompress(codec: u8, data: &[u8], page: &mut [u8]) -> Result<()> {
+    let len = match codec {
+        CODEC_RAW => {
+            let len = data.len().min(page.len());
+            page[..len].copy_from_slice(&data[..len]);
+            data.len()
+        }
+        #[cfg(feature = "lz4")]
+        CODEC_LZ4 => lz4_flex::block::decompress_into(data, page).map_err(|e| BPlusError::NodeError(e.to_string()))?,
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => zstd::bulk::decompress_to_buffer(data, page)?,
+        other => return Err(BPlusError::NodeError(format!("unknown page codec {}", other)).into()),
+    };
+    if len != page.len() {
+        return Err(BPlusError::NodeError(format!("decompressed page length {}", len)).into());
+    }
+    Ok(())
+}
+
+//Run 一组连续扇区, logical 为 None 时空闲
+struct Run {
+    sectors: u64,
+    logical: Option<u64>,
+}
+
+struct State {
+    //起始扇区 -> 扇区组, 包含空闲的
+    runs: BTreeMap<u64, Run>,
+    //空闲扇区组 (扇区数, 起始扇区), 分配时找最小的足够大的
+    free: BTreeSet<(u64, u64)>,
+    //逻辑页 -> (起始扇区, generation), 不持锁读取前后比较, 变化时说明页被改写
+    map: HashMap<u64, (u64, u64)>,
+    //逻辑长度
+    end: u64,
+    //物理长度(扇区)
+    sectors: u64,
+    generation: u64,
+}
+
+impl State {
+    fn insert_run(&mut self, start: u64, run: Run) {
+        if run.logical.is_none() {
+            self.free.insert((run.sectors, start));
+        }
+        self.runs.insert(start, run);
+    }
+
+    fn remove_run(&mut self, start: u64) -> Option<Run> {
+        let run = self.runs.remove(&start)?;
+        if run.logical.is_none() {
+            self.free.remove(&(run.sectors, start));
+        }
+        Some(run)
+    }
+}
+
+/// 压缩存储, 树看到的是固定大小的逻辑页, 写入时压缩后保存到变长的扇区组中
+/// 每个扇区组头记录逻辑页位置, 打开时扫描文件重建逻辑页到物理位置的映射
+pub struct CompressedStore<S = FileStore> {
+    inner: S,
+    codec: PageCodec,
+    state: Mutex<State>,
+}
+
+impl CompressedStore<FileStore> {
+    /// 打开或创建压缩文件, 新写入的页使用 codec 压缩
+    pub fn open(path: &str, codec: PageCodec) -> Result<Self> {
+        Self::new(FileStore::open(path)?, codec)
+    }
+
+    pub fn path(&self) -> &str {
+        self.inner.path()
+    }
+}
+
+impl<S: PageStore> CompressedStore<S> {
+    /// 在 inner 之上压缩, inner 中已有的数据必须是压缩存储写入的
+    pub fn new(inner: S, codec: PageCodec) -> Result<Self> {
+        let state = Self::scan(&inner)?;
+        Ok(CompressedStore {
+            inner,
+            codec,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// 压缩后占用的物理字节数
+    pub fn physical_len(&self) -> u64 {
+        self.state().sectors * SECTOR
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //scan 按扇区组头遍历文件, 同一个逻辑页有多个扇区组时保留 generation 最大的
+    fn scan(inner: &S) -> Result<State> {
+        let mut state = State {
+            runs: BTreeMap::new(),
+            free: BTreeSet::new(),
+            map: HashMap::new(),
+            end: 0,
+            sectors: 0,
+            generation: 0,
+        };
+        let mut stale = vec![];
+        let mut header = [0u8; RUN_HEADER];
+        while state.sectors * SECTOR < inner.len() {
+            if let Err(e) = inner.read_page(state.sectors * SECTOR, &mut header) {
+                //FileStore 的长度按页向上取整, 读到文件末尾时结束
+                if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof) {
+                    break;
+                }
+                return Err(e);
+            }
+            let sectors = BigEndian::read_u32(&header[4..8]) as u64;
+            //后面的扇区组无法定位, 继续打开会覆盖它们
+            if header[0..2] != MAGIC || sectors == 0 {
+                if state.sectors == 0 {
+                    return Err(BPlusError::NodeError("not a compressed tree file".to_string()).into());
+                }
+                return Err(BPlusError::NodeError(format!("corrupt run header at sector {}", state.sectors)).into());
+            }
+            let start = state.sectors;
+            let mut logical = None;
+            if header[2] == RUN_USED {
+                let page = BigEndian::read_u64(&header[8..16]);
+                let generation = BigEndian::read_u64(&header[16..24]);
+                state.generation = state.generation.max(generation);
+                match state.map.get(&page) {
+                    Some(&(_, g)) if g > generation => stale.push(start),
+                    _ => {
+                        if let Some((old, _)) = state.map.insert(page, (start, generation)) {
+                            stale.push(old);
+                        }
+                        state.end = state.end.max(page + page_size() as u64);
+                    }
+                }
+                logical = Some(page);
+            }
+            state.insert_run(start, Run { sectors, logical });
+            state.sectors += sectors;
+        }
+        //写入新位置后没来得及释放的旧扇区组
+        for start in stale {
+            Self::free_run(inner, &mut state, start)?;
+        }
+        Ok(state)
+    }
+
+    //read_run 读取并解压 start 处的扇区组
+    fn read_run(&self, start: u64, page: &mut [u8]) -> Result<()> {
+        let mut header = [0u8; RUN_HEADER];
+        self.inner.read_page(start * SECTOR, &mut header)?;
+        let len = BigEndian::read_u32(&header[24..28]) as usize;
+        let mut data = vec![0u8; len];
+        self.inner.read_page(start * SECTOR + RUN_HEADER as u64, &mut data)?;
+        decompress(header[3], &data, page)
+    }
+
+    //read_logical 读取并解压一个逻辑页, 没有写入过的页为 0
+    //读取和解压时不持有锁, 之后位置或 generation 变化说明读取期间被改写, 重新读取
+    fn read_logical(&self, logical: u64) -> Result<Vec<u8>> {
+        loop {
+            let mut page = vec![0u8; page_size()];
+            let Some(location) = self.state().map.get(&logical).copied() else {
+                return Ok(page);
+            };
+            let result = self.read_run(location.0, &mut page);
+            if self.state().map.get(&logical) == Some(&location) {
+                return result.map(|_| page);
+            }
+        }
+    }
+
+    //write_logical 压缩后写入, 原来的扇区组放得下时原地覆盖, 否则分配新的扇区组后释放原来的
+    fn write_logical(&self, state: &mut State, logical: u64, page: &[u8]) -> Result<()> {
+        let (codec, data) = self.codec.compress(page)?;
+        let needed = (RUN_HEADER + data.len()).div_ceil(SECTOR as usize) as u64;
+        let old = state.map.get(&logical).map(|(start, _)| *start);
+        let start = match old {
+            Some(start) if state.runs[&start].sectors >= needed => start,
+            _ => Self::allocate_run(&self.inner, state, needed, logical)?,
+        };
+        state.generation += 1;
+        let sectors = state.runs.get(&start).ok_or_else(|| BPlusError::NodeError("missing run".to_string()))?.sectors;
+        let mut buf = vec![0u8; (needed * SECTOR) as usize];
+        buf[0..2].copy_from_slice(&MAGIC);
+        buf[2] = RUN_USED;
+        buf[3] = codec;
+        BigEndian::write_u32(&mut buf[4..8], sectors as u32);
+        BigEndian::write_u64(&mut buf[8..16], logical);
+        BigEndian::write_u64(&mut buf[16..24], state.generation);
+        BigEndian::write_u32(&mut buf[24..28], data.len() as u32);
+        buf[RUN_HEADER..RUN_HEADER + data.len()].copy_from_slice(&data);
+        self.inner.write_page(start * SECTOR, &buf)?;
+        state.map.insert(logical, (start, state.generation));
+        if let Some(old) = old.filter(|old| *old != start) {
+            Self::free_run(&self.inner, state, old)?;
+        }
+        Ok(())
+    }
+
+    //allocate_run 使用最小的足够大的空闲扇区组, 多余的部分拆成新的空闲扇区组, 没有时追加到末尾
+    fn allocate_run(inner: &S, state: &mut State, needed: u64, logical: u64) -> Result<u64> {
+        let start = match state.free.range((needed, 0)..).next().copied() {
+            Some((sectors, start)) => {
+                state.remove_run(start);
+                if sectors > needed {
+                    Self::write_free(inner, start + needed, sectors - needed)?;
+                    state.insert_run(start + needed, Run { sectors: sectors - needed, logical: None });
+                }
+                start
+            }
+            None => {
+                state.sectors += needed;
+                state.sectors - needed
+            }
+        };
+        state.insert_run(start, Run { sectors: needed, logical: Some(logical) });
+        Ok(start)
+    }
+
+    //free_run 释放扇区组并和后面的空闲扇区组合并, 文件末尾的空闲扇区组直接截断
+    fn free_run(inner: &S, state: &mut State, start: u64) -> Result<()> {
+        let Some(mut run) = state.remove_run(start) else {
+            return Ok(());
+        };
+        if let Some(logical) = run.logical.take() {
+            if state.map.get(&logical).is_some_and(|(at, _)| *at == start) {
+                state.map.remove(&logical);
+            }
+        }
+        let next = start + run.sectors;
+        if state.runs.get(&next).is_some_and(|next| next.logical.is_none()) {
+            run.sectors += state.remove_run(next).map_or(0, |next| next.sectors);
+        }
+        if start + run.sectors == state.sectors {
+            state.sectors = start;
+            //前面的扇区组也是空闲的一起截断
+            while let Some((&prev, _)) = state.runs.last_key_value().filter(|(_, r)| r.logical.is_none()) {
+                state.remove_run(prev);
+                state.sectors = prev;
+            }
+            return inner.truncate(state.sectors * SECTOR);
+        }
+        Self::write_free(inner, start, run.sectors)?;
+        state.insert_run(start, run);
+        Ok(())
+    }
+
+    fn write_free(inner: &S, start: u64, sectors: u64) -> Result<()> {
+        let mut header = [0u8; RUN_HEADER];
+        header[0..2].copy_from_slice(&MAGIC);
+        header[2] = RUN_FREE;
+        BigEndian::write_u32(&mut header[4..8], sectors as u32);
+        inner.write_page(start * SECTOR, &header)
+    }
+}
+
+impl<S: PageStore> PageStore for CompressedStore<S> {
+    //read_page 按逻辑位置读取, 可以跨页
+    fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()> {
+        if seek + buf.len() as u64 > self.state().end {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("read beyond end at {}", seek)).into());
+        }
+        let page = page_size() as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = seek + done as u64;
+            let offset = (pos % page) as usize;
+            let len = (page as usize - offset).min(buf.len() - done);
+            let data = self.read_logical(pos - offset as u64)?;
+            buf[done..done + len].copy_from_slice(&data[offset..offset + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    //write_page 只写页的一部分时先解压原来的页再修改
+    fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+        let mut state = self.state();
+        let page = page_size() as u64;
+        let mut done = 0;
+        while done < data.len() {
+            let pos = seek + done as u64;
+            let offset = (pos % page) as usize;
+            let len = (page as usize - offset).min(data.len() - done);
+            let logical = pos - offset as u64;
+            let full = if len == page as usize {
+                data[done..done + len].to_vec()
+            } else {
+                let mut full = vec![0u8; page as usize];
+                if let Some(&(start, _)) = state.map.get(&logical) {
+                    self.read_run(start, &mut full)?;
+                }
+                full[offset..offset + len].copy_from_slice(&data[done..done + len]);
+                full
+            };
+            self.write_logical(&mut state, logical, &full)?;
+            state.end = state.end.max(logical + page);
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn allocate(&self, page_size: u64) -> u64 {
+        let mut state = self.state();
+        state.end += page_size;
+        state.end - page_size
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn len(&self) -> u64 {
+        self.state().end
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        let mut state = self.state();
+        let mut removed: Vec<u64> = state.map.iter().filter(|(logical, _)| **logical >= len).map(|(_, (start, _))| *start).collect();
+        //从后往前释放, 文件末尾的扇区组可以直接截断
+        removed.sort_unstable_by(|a, b| b.cmp(a));
+        for start in removed {
+            Self::free_run(&self.inner, &mut state, start)?;
+        }
+        state.end = len;
+        Ok(())
+    }
+
+    fn scratch(&self) -> Result<Self> {
+        Self::new(self.inner.scratch()?, self.codec)
+    }
+
+    fn replace(&self, other: Self) -> Result<u64> {
+        let CompressedStore { inner, state, .. } = other;
+        let mut current = self.state();
+        let syncs = self.inner.replace(inner)?;
+        *current = state.into_inner().unwrap_or_else(|e| e.into_inner());
+        Ok(syncs)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn is_transformed(&self) -> bool {
+        true
+    }
+
+    fn location(&self) -> Option<&str> {
+        self.inner.location()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use crate::temp_path;
+    use crate::node::node::page_size;
+    use crate::store::{CompressedStore, MemoryStore, PageCodec, PageStore};
+    use crate::tree::{Config, Tree};
+    use crate::ValueTest;
+    use super::SECTOR;
+
+    fn codecs() -> Vec<PageCodec> {
+        vec![
+            #[cfg(feature = "lz4")]
+            PageCodec::Lz4,
+            #[cfg(feature = "zstd")]
+            PageCodec::Zstd { level: 3 },
+        ]
+    }
+
+    #[test]
+    fn compressed_store() {
+        for codec in codecs() {
+            let store = CompressedStore::new(MemoryStore::new(), codec).unwrap();
+            let page = page_size() as u64;
+            let a = store.allocate(page);
+            let b = store.allocate(page);
+            store.write_page(a, &vec![1u8; page as usize]).unwrap();
+            store.write_page(b, &(0..page).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>()).unwrap();
+            //部分写入 跨页
+            store.write_page(b - 1, &[9, 9]).unwrap();
+            let mut buf = [0u8; 4];
+            store.read_page(b - 2, &mut buf).unwrap();
+            assert_eq!(buf, [1, 9, 9, 7]);
+            assert!(store.physical_len() < page);
+            assert!(store.read_page(2 * page, &mut buf).is_err());
+
+            //重新扫描得到同样的映射
+            let inner = MemoryStore::new();
+            inner.write_page(0, &store.inner.to_vec()).unwrap();
+            let reopened = CompressedStore::new(inner, codec).unwrap();
+            assert_eq!(reopened.len(), 2 * page);
+            let mut all = vec![0u8; 2 * page as usize];
+            reopened.read_page(0, &mut all).unwrap();
+            let mut expected = vec![0u8; 2 * page as usize];
+            store.read_page(0, &mut expected).unwrap();
+            assert_eq!(all, expected);
+
+            //不能压缩的页变大后移动到新的扇区组, 原来的位置被复用
+            let noise: Vec<u8> = (0..page).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+            store.write_page(a, &noise).unwrap();
+            store.write_page(a, &vec![2u8; page as usize]).unwrap();
+            store.truncate(page).unwrap();
+            assert_eq!(store.len(), page);
+            assert!(store.physical_len() < page);
+        }
+    }
+
+    #[test]
+    fn compressed_tree() {
+        for codec in codecs() {
+            let dir = tempfile::tempdir().unwrap();
+            let text = |i: u64| ValueTest { id: i as u32, data: format!("user {} logged in from the office network at {}", i % 37, i % 1000) };
+            let plain = Tree::<u64, ValueTest>::open(&temp_path(&dir, "compressed_plain.db")).unwrap();
+            let tree = Tree::<u64, ValueTest, CompressedStore>::open_compressed(&temp_path(&dir, "compressed_tree.db"), codec).unwrap();
+            for i in 0..3000u64 {
+                plain.insert(i, text(i)).unwrap();
+                tree.insert(i, text(i)).unwrap();
+            }
+            //两棵树内容相同
+            plain.insert(7, ValueTest { id: 7, data: "x".repeat(40000) }).unwrap();
+            tree.insert(7, ValueTest { id: 7, data: "x".repeat(40000) }).unwrap();
+            for i in (0..3000u64).step_by(3) {
+                plain.remove(&i).unwrap();
+                tree.remove(&i).unwrap();
+            }
+            assert!(tree.verify().unwrap().is_ok());
+            drop(tree);
+
+            let tree = Tree::<u64, ValueTest, CompressedStore>::open_compressed_with_config(&temp_path(&dir, "compressed_tree.db"), codec, Config { cache_pages: 0, ..Default::default() }).unwrap();
+            assert_eq!(tree.get(&7).unwrap().unwrap().data.len(), 40000);
+            assert_eq!(tree.get(&2997).unwrap(), None);
+            assert_eq!(tree.get(&2998).unwrap(), Some(text(2998)));
+            assert_eq!(tree.range(..).unwrap().count(), 2000);
+            tree.compact().unwrap();
+            plain.compact().unwrap();
+            assert!(tree.verify().unwrap().is_ok());
+            let physical = fs::metadata(temp_path(&dir, "compressed_tree.db")).unwrap().len();
+            let logical = fs::metadata(temp_path(&dir, "compressed_plain.db")).unwrap().len();
+            assert!(physical * 3 < logical, "{} {}", physical, logical);
+        }
+    }
+
+    #[test]
+    fn compressed_open_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = temp_path(&dir, "plain.db");
+        let plain = Tree::<u64, u64>::open(&path).unwrap();
+        plain.insert(1, 1).unwrap();
+        drop(plain);
+        let before = fs::read(&path).unwrap();
+        for codec in codecs() {
+            assert!(Tree::<u64, u64, CompressedStore>::open_compressed(&path, codec).is_err());
+        }
+        assert_eq!(fs::read(&path).unwrap(), before);
+
+        //中间的扇区组头损坏
+        let inner = MemoryStore::new();
+        let store = CompressedStore::new(inner, codecs()[0]).unwrap();
+        let page = page_size() as u64;
+        for i in 0..3 {
+            let seek = store.allocate(page);
+            store.write_page(seek, &vec![i as u8; page as usize]).unwrap();
+        }
+        let data = store.inner.to_vec();
+        let second = store.state().map.get(&page).unwrap().0;
+        let inner = MemoryStore::new();
+        inner.write_page(0, &data).unwrap();
+        inner.write_page(second * SECTOR, &[0u8; 2]).unwrap();
+        assert!(CompressedStore::new(inner, codecs()[0]).is_err());
+    }
+
+    #[test]
+    fn compressed_concurrent_read() {
+        let codec = codecs()[0];
+        let store = Arc::new(CompressedStore::new(MemoryStore::new(), codec).unwrap());
+        let page = page_size();
+        let a = store.allocate(page as u64);
+        let b = store.allocate(page as u64);
+        //压缩率不同的内容交替写入, 扇区组原地覆盖或者移动
+        let contents: Arc<Vec<Vec<u8>>> = Arc::new((0..=201u64).map(|i| {
+            if i.is_multiple_of(2) {
+                vec![i as u8; page]
+            } else {
+                (0..page as u64).map(|j| ((j ^ i).wrapping_mul(2654435761) >> 13) as u8).collect()
+            }
+        }).collect());
+        store.write_page(a, &contents[0]).unwrap();
+        store.write_page(b, &contents[0]).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4).map(|_| {
+            let (store, stop, contents) = (store.clone(), stop.clone(), contents.clone());
+            thread::spawn(move || {
+                let mut reads = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let mut buf = vec![0u8; page];
+                    store.read_page(a, &mut buf).unwrap();
+                    assert!(contents.contains(&buf));
+                    reads += 1;
+                }
+                reads
+            })
+        }).collect();
+        for i in 1..=200u64 {
+            store.write_page(a, &contents[i as usize]).unwrap();
+            store.write_page(b, &contents[i as usize + 1]).unwrap();
+        }
+        stop.store(true, Ordering::SeqCst);
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+    }
+}
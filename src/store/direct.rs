@@ -0,0 +1,301 @@
+use std::alloc::{self, Layout};
+use std::fs::{self, File, OpenOptions};
+use std::ops::{Deref, DerefMut};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use anyhow::Result;
+use crate::node::node::{page_size, BPlusError};
+use crate::store::file::{lock_file, sync_dir};
+use crate::store::PageStore;
+
+/// O_DIRECT 文件存储, 不经过内核页缓存, 只依赖树自己的页缓存
+/// 读写都按整页对齐, 要求 PAGE_SIZE 是直接 IO 偏移对齐(逻辑扇区大小)的整数倍
+pub struct DirectStore {
+    path: String,
+    //replace 时替换, 部分写入读改写时持有写锁
+    fd: RwLock<File>,
+    end: AtomicU64,
+    //缓冲区内存对齐
+    align: usize,
+    temporary: bool,
+}
+
+//AlignedBuf 按直接 IO 要求对齐的缓冲区
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize, align: usize) -> Result<Self> {
+        let layout = Layout::from_size_align(len.max(1), align)
+            .map_err(|e| BPlusError::Unaligned(format!("invalid buffer alignment {}: {}", align, e)))?;
+        // SAFETY: layout 大小不为 0
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Ok(AlignedBuf { ptr, layout })
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: ptr 指向 layout.size() 字节已初始化的内存
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: 同上, 且 &mut self 保证独占
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // SAFETY: ptr 由同一个 layout 分配
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+impl DirectStore {
+    /// 以 O_DIRECT 打开或创建文件, 检查页大小和文件长度是否按块对齐, 和 FileStore 一样持有排他锁
+    pub fn open(path: &str) -> Result<Self> {
+        let fd = Self::open_file(path)?;
+        lock_file(&fd, path, false)?;
+        let align = Self::check_alignment(&fd)?;
+        let len = fd.metadata()?.len();
+        Ok(DirectStore {
+            path: path.to_string(),
+            fd: RwLock::new(fd),
+            end: AtomicU64::new(len),
+            align,
+            temporary: false,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn open_file(path: &str) -> Result<File> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .write(true)
+            .read(true)
+            .truncate(false)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?)
+    }
+
+    //check_alignment 返回缓冲区内存对齐, 页大小或文件长度没有按直接 IO 偏移对齐时报错
+    fn check_alignment(fd: &File) -> Result<usize> {
+        let metadata = fd.metadata()?;
+        let (mem, sector) = Self::dio_align(fd, metadata.file_type().is_block_device());
+        let page = page_size();
+        if !sector.is_power_of_two() || !page.is_multiple_of(sector) {
+            return Err(BPlusError::Unaligned(format!("page size {} is not a multiple of sector size {}", page, sector)).into());
+        }
+        if !metadata.len().is_multiple_of(page as u64) {
+            return Err(BPlusError::Unaligned(format!("file length {} is not a multiple of page size {}", metadata.len(), page)).into());
+        }
+        let align = mem.max(sector);
+        if !align.is_power_of_two() {
+            return Err(BPlusError::Unaligned(format!("invalid buffer alignment {}", align)).into());
+        }
+        Ok(align)
+    }
+
+    //dio_align 直接 IO 的 (内存对齐, 偏移对齐)
+    //优先使用 statx 的 STATX_DIOALIGN, 块设备使用逻辑扇区大小, 都拿不到时按 4096 处理
+    fn dio_align(fd: &File, block_device: bool) -> (usize, usize) {
+        // SAFETY: statx 是 C 结构体, 全 0 是合法值
+        let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+        // SAFETY: 空路径加 AT_EMPTY_PATH 作用于 fd, stx 在调用期间有效
+        let ret = unsafe { libc::statx(fd.as_raw_fd(), c"".as_ptr(), libc::AT_EMPTY_PATH, libc::STATX_DIOALIGN, &mut stx) };
+        if ret == 0 && stx.stx_mask & libc::STATX_DIOALIGN != 0 && stx.stx_dio_offset_align != 0 {
+            return (stx.stx_dio_mem_align as usize, stx.stx_dio_offset_align as usize);
+        }
+        if block_device {
+            let mut sector: libc::c_int = 0;
+            // SAFETY: BLKSSZGET 写入一个 int
+            if unsafe { libc::ioctl(fd.as_raw_fd(), libc::BLKSSZGET, &mut sector) } == 0 && sector > 0 {
+                return (sector as usize, sector as usize);
+            }
+        }
+        (4096, 4096)
+    }
+
+    fn fd(&self) -> RwLockReadGuard<'_, File> {
+        self.fd.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn fd_mut(&self) -> RwLockWriteGuard<'_, File> {
+        self.fd.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //page_range 覆盖 [seek, seek + len) 的整页范围
+    fn page_range(seek: u64, len: usize) -> (u64, usize) {
+        let page = page_size() as u64;
+        let start = seek - seek % page;
+        let end = (seek + len as u64).div_ceil(page) * page;
+        (start, (end - start) as usize)
+    }
+
+    //read_tolerant 读取整页, 文件末尾之后的部分为 0, 用于部分写入
+    fn read_tolerant(fd: &File, buf: &mut [u8], seek: u64) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            match fd.read_at(&mut buf[done..], seek + done as u64)? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PageStore for DirectStore {
+    fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()> {
+        self.with_page(seek, buf.len(), |page| buf.copy_from_slice(page))
+    }
+
+    //with_page 读取覆盖的整页到对齐的缓冲区, 直接从缓冲区解码
+    fn with_page<R>(&self, seek: u64, len: usize, f: impl FnOnce(&[u8]) -> R) -> Result<R> {
+        let (start, size) = Self::page_range(seek, len);
+        let mut buf = AlignedBuf::new(size, self.align)?;
+        self.fd().read_exact_at(&mut buf, start)?;
+        let offset = (seek - start) as usize;
+        Ok(f(&buf[offset..offset + len]))
+    }
+
+    //write_page 整页写入直接提交, 部分写入先读出所在的页
+    fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+        let (start, size) = Self::page_range(seek, data.len());
+        let mut buf = AlignedBuf::new(size, self.align)?;
+        let fd = self.fd_mut();
+        let offset = (seek - start) as usize;
+        if offset != 0 || size != data.len() {
+            Self::read_tolerant(&fd, &mut buf, start)?;
+        }
+        buf[offset..offset + data.len()].copy_from_slice(data);
+        fd.write_all_at(&buf, start)?;
+        Ok(())
+    }
+
+    fn allocate(&self, page_size: u64) -> u64 {
+        self.end.fetch_add(page_size, Ordering::SeqCst)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.fd().sync_all()?;
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.end.load(Ordering::SeqCst)
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        self.fd_mut().set_len(len)?;
+        self.end.store(len, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn scratch(&self) -> Result<Self> {
+        let tmp = format!("{}.compact", self.path);
+        let _ = fs::remove_file(&tmp);
+        let mut store = DirectStore::open(&tmp)?;
+        store.temporary = true;
+        Ok(store)
+    }
+
+    fn replace(&self, mut other: Self) -> Result<u64> {
+        let mut fd = self.fd_mut();
+        fs::rename(&other.path, &self.path)?;
+        other.temporary = false;
+        sync_dir(&self.path)?;
+        //使用临时文件的 fd, 文件锁跟着保留
+        *fd = other.fd().try_clone()?;
+        self.end.store(fd.metadata()?.len(), Ordering::SeqCst);
+        Ok(1)
+    }
+
+    fn location(&self) -> Option<&str> {
+        (!self.temporary).then_some(self.path.as_str())
+    }
+}
+
+impl Drop for DirectStore {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::temp_path;
+    use crate::node::node::{page_size, BPlusError};
+    use crate::store::{DirectStore, PageStore};
+    use super::AlignedBuf;
+    use crate::tree::{Config, Tree};
+    use crate::ValueTest;
+
+    #[test]
+    fn direct_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DirectStore::open(&temp_path(&dir, "direct_store.db")).unwrap();
+        let page = page_size() as u64;
+        let seek = store.allocate(page);
+        store.write_page(seek, &vec![3u8; page as usize]).unwrap();
+        //部分写入 跨页
+        let next = store.allocate(page);
+        store.write_page(next - 2, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(fs::metadata(temp_path(&dir, "direct_store.db")).unwrap().len(), 2 * page);
+        let mut buf = [0u8; 6];
+        store.read_page(next - 3, &mut buf).unwrap();
+        assert_eq!(buf, [3, 1, 2, 3, 4, 0]);
+        assert!(store.with_page(next + page, 4, |_| ()).is_err());
+        //缓冲区按逻辑扇区对齐, 不是文件系统块大小
+        assert!(store.align.is_power_of_two() && (page as usize).is_multiple_of(store.align));
+        drop(store);
+
+        //不合法的对齐返回错误, 不会 panic
+        let err = AlignedBuf::new(page as usize, 3).err().unwrap();
+        assert!(matches!(err.downcast_ref::<BPlusError>(), Some(BPlusError::Unaligned(_))), "{}", err);
+
+        //文件长度没有按页对齐时拒绝打开
+        fs::write(temp_path(&dir, "direct_store.db"), [0u8; 100]).unwrap();
+        let err = DirectStore::open(&temp_path(&dir, "direct_store.db")).err().unwrap();
+        assert!(err.to_string().contains("not a multiple of page size"), "{}", err);
+    }
+
+    #[test]
+    fn direct_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = Tree::<u64, ValueTest, DirectStore>::open_direct_with_config(&temp_path(&dir, "direct_tree.db"), Config { cache_pages: 16, ..Default::default() }).unwrap();
+        for i in 0..2000u64 {
+            tree.insert(i, ValueTest { id: i as u32, data: "d".repeat(if i == 500 { 20000 } else { 20 }) }).unwrap();
+        }
+        for i in (0..2000u64).step_by(3) {
+            tree.remove(&i).unwrap();
+        }
+        tree.compact().unwrap();
+        assert_eq!(tree.get(&500).unwrap().unwrap().data.len(), 20000);
+        assert_eq!(tree.range(..).unwrap().count(), 1333);
+        assert!(tree.verify().unwrap().is_ok());
+        drop(tree);
+        //普通方式打开同一个文件
+        let tree = Tree::<u64, ValueTest>::open(&temp_path(&dir, "direct_tree.db")).unwrap();
+        assert_eq!(tree.get(&1).unwrap().unwrap().id, 1);
+    }
+}
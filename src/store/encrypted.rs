@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::Aes256Gcm;
+use anyhow::Result;
+use byteorder::{BigEndian, ByteOrder};
+use chacha20poly1305::ChaCha20Poly1305;
+use crate::node::node::{page_size, BPlusError};
+use crate::store::{FileStore, PageStore};
+
+//文件开头的超级块: magic(8) cipher(1) reserved(8), 占用一个槽位
+const MAGIC: [u8; 8] = *b"BPTENC01";
+//槽位头: key id(4) counter(8) tag(16), 后面是加密后的整页
+const SLOT_HEADER: usize = 28;
+//计数器每次预留的数量, 超级块记录预留的上限, 崩溃后从上限继续保证 nonce 不重复
+const COUNTER_RESERVE: u64 = 1 << 16;
+
+/// 页加密算法, 都是 256 位密钥的认证加密
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn id(&self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn seal(&self, key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &mut [u8]) -> Result<[u8; 16]> {
+        match self {
+            Cipher::Aes256Gcm => seal::<Aes256Gcm>(key, nonce, aad, data),
+            Cipher::ChaCha20Poly1305 => seal::<ChaCha20Poly1305>(key, nonce, aad, data),
+        }
+    }
+
+    fn open(&self, key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &mut [u8], tag: &[u8]) -> Result<()> {
+        match self {
+            Cipher::Aes256Gcm => open::<Aes256Gcm>(key, nonce, aad, data, tag),
+            Cipher::ChaCha20Poly1305 => open::<ChaCha20Poly1305>(key, nonce, aad, data, tag),
+        }
+    }
+}
+
+fn seal<C: AeadInPlace + KeyInit>(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &mut [u8]) -> Result<[u8; 16]> {
+    let cipher = C::new_from_slice(key).map_err(|e| BPlusError::NodeError(e.to_string()))?;
+    let tag = cipher.encrypt_in_place_detached(nonce.as_slice().into(), aad, data)
+        .map_err(|_| BPlusError::NodeError("page encryption failed".to_string()))?;
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&tag);
+    Ok(out)
+}
+
+fn open<C: AeadInPlace + KeyInit>(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &mut [u8], tag: &[u8]) -> Result<()> {
+    let cipher = C::new_from_slice(key).map_err(|e| BPlusError::NodeError(e.to_string()))?;
+    cipher.decrypt_in_place_detached(nonce.as_slice().into(), aad, data, tag.into())
+        .map_err(|_| BPlusError::NodeError("page authentication failed".to_string()).into())
+}
+
+/// 密钥来源, 每个密钥有一个 id, 页头记录加密时使用的 id
+pub trait KeyProvider: Send + Sync {
+    /// 新写入使用的密钥 id
+    fn current(&self) -> u32;
+    /// id 对应的 256 位密钥, 读取旧密钥加密的页时也会调用
+    fn key(&self, id: u32) -> Result<[u8; 32]>;
+}
+
+/// 保存在内存中的密钥, rotate 之后新写入使用新密钥, 旧密钥保留用于读取
+pub struct StaticKeys {
+    keys: RwLock<HashMap<u32, [u8; 32]>>,
+    current: AtomicU32,
+}
+
+impl StaticKeys {
+    pub fn new(id: u32, key: [u8; 32]) -> Self {
+        StaticKeys {
+            keys: RwLock::new(HashMap::from([(id, key)])),
+            current: AtomicU32::new(id),
+        }
+    }
+
+    /// 加入新密钥并作为当前密钥
+    pub fn rotate(&self, id: u32, key: [u8; 32]) {
+        self.keys.write().unwrap_or_else(|e| e.into_inner()).insert(id, key);
+        self.current.store(id, Ordering::SeqCst);
+    }
+}
+
+impl KeyProvider for StaticKeys {
+    fn current(&self) -> u32 {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    fn key(&self, id: u32) -> Result<[u8; 32]> {
+        self.keys.read().unwrap_or_else(|e| e.into_inner()).get(&id).copied()
+            .ok_or_else(|| BPlusError::NodeError(format!("unknown key id {}", id)).into())
+    }
+}
+
+//Counter 写入计数器, next 达到 reserved 时先更新超级块
+struct Counter {
+    next: u64,
+    reserved: u64,
+}
+
+/// 加密存储, 每个逻辑页加密后保存在固定大小的槽位中, 槽位头记录密钥 id、写入计数器和认证标签
+/// nonce 由页号和写入计数器组成, 附加数据包含页位置, 页被移动到其他位置时认证失败
+pub struct EncryptedStore<S = FileStore> {
+    inner: S,
+    keys: Arc<dyn KeyProvider>,
+    cipher: Cipher,
+    //逻辑长度
+    end: AtomicU64,
+    //inner 中已经写入槽位的页数, 这些槽位都经过加密, 之后的页还没有写入过
+    slots: AtomicU64,
+    counter: Mutex<Counter>,
+    //重新加密和读取互斥, 避免读到写了一半的槽位
+    io: RwLock<()>,
+}
+
+impl EncryptedStore<FileStore> {
+    /// 打开或创建加密文件, 已有文件的算法必须和 cipher 相同
+    pub fn open(path: &str, keys: Arc<dyn KeyProvider>, cipher: Cipher) -> Result<Self> {
+        Self::new(FileStore::open(path)?, keys, cipher)
+    }
+
+    pub fn path(&self) -> &str {
+        self.inner.path()
+    }
+}
+
+impl<S: PageStore> EncryptedStore<S> {
+    /// 在 inner 之上加密, inner 为空时写入超级块
+    pub fn new(inner: S, keys: Arc<dyn KeyProvider>, cipher: Cipher) -> Result<Self> {
+        let slot = Self::slot_size();
+        let mut reserved = 0;
+        let mut end = 0;
+        let mut slots = 0;
+        if inner.len() == 0 {
+            Self::write_super(&inner, cipher, 0)?;
+        } else {
+            let mut header = [0u8; 17];
+            inner.read_page(0, &mut header)?;
+            if header[0..8] != MAGIC {
+                return Err(BPlusError::NodeError("not an encrypted tree file".to_string()).into());
+            }
+            if header[8] != cipher.id() {
+                return Err(BPlusError::NodeError(format!("file was encrypted with cipher {}", header[8])).into());
+            }
+            reserved = BigEndian::read_u64(&header[9..17]);
+            slots = (inner.len() / slot).saturating_sub(1);
+            end = slots * page_size() as u64;
+        }
+        Ok(EncryptedStore {
+            inner,
+            keys,
+            cipher,
+            end: AtomicU64::new(end),
+            slots: AtomicU64::new(slots),
+            counter: Mutex::new(Counter { next: reserved, reserved }),
+            io: RwLock::new(()),
+        })
+    }
+
+    fn slot_size() -> u64 {
+        (page_size() + SLOT_HEADER) as u64
+    }
+
+    fn write_super(inner: &S, cipher: Cipher, reserved: u64) -> Result<()> {
+        let mut header = [0u8; 17];
+        header[0..8].copy_from_slice(&MAGIC);
+        header[8] = cipher.id();
+        BigEndian::write_u64(&mut header[9..17], reserved);
+        inner.write_page(0, &header)
+    }
+
+    fn io(&self) -> RwLockReadGuard<'_, ()> {
+        self.io.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn io_mut(&self) -> RwLockWriteGuard<'_, ()> {
+        self.io.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //next_counter 超过预留上限时先把新的上限写入超级块并 fsync
+    fn next_counter(&self) -> Result<u64> {
+        let mut counter = self.counter.lock().unwrap_or_else(|e| e.into_inner());
+        if counter.next >= counter.reserved {
+            let reserved = counter.next + COUNTER_RESERVE;
+            Self::write_super(&self.inner, self.cipher, reserved)?;
+            self.inner.sync()?;
+            counter.reserved = reserved;
+        }
+        counter.next += 1;
+        Ok(counter.next)
+    }
+
+    //nonce 页号(4) + 计数器(8)
+    fn nonce(logical: u64, counter: u64) -> Result<[u8; 12]> {
+        let index = u32::try_from(logical / page_size() as u64)
+            .map_err(|_| BPlusError::NodeError(format!("page {} beyond encrypted range", logical)))?;
+        let mut nonce = [0u8; 12];
+        BigEndian::write_u32(&mut nonce[0..4], index);
+        BigEndian::write_u64(&mut nonce[4..12], counter);
+        Ok(nonce)
+    }
+
+    //aad 页位置和密钥 id
+    fn aad(logical: u64, key_id: u32) -> [u8; 12] {
+        let mut aad = [0u8; 12];
+        BigEndian::write_u64(&mut aad[0..8], logical);
+        BigEndian::write_u32(&mut aad[8..12], key_id);
+        aad
+    }
+
+    //read_slot 读取并解密一个逻辑页, 返回 (页, 密钥 id), 已写入槽位之后的页为 0
+    //已写入范围内的槽位都必须通过认证, 计数器为 0 的槽位是被清零或截断的数据
+    fn read_slot(&self, logical: u64) -> Result<(Vec<u8>, Option<u32>)> {
+        let page = page_size();
+        if logical / page as u64 >= self.slots.load(Ordering::SeqCst) {
+            return Ok((vec![0u8; page], None));
+        }
+        let mut slot = vec![0u8; SLOT_HEADER + page];
+        let seek = (logical / page as u64 + 1) * Self::slot_size();
+        self.inner.read_page(seek, &mut slot)?;
+        let key_id = BigEndian::read_u32(&slot[0..4]);
+        let counter = BigEndian::read_u64(&slot[4..12]);
+        if counter == 0 {
+            return Err(BPlusError::NodeError(format!("unauthenticated page {}", logical)).into());
+        }
+        let (header, data) = slot.split_at_mut(SLOT_HEADER);
+        let key = self.keys.key(key_id)?;
+        self.cipher.open(&key, &Self::nonce(logical, counter)?, &Self::aad(logical, key_id), data, &header[12..28])
+            .map_err(|e| BPlusError::NodeError(format!("{} at page {}", e, logical)))?;
+        slot.drain(..SLOT_HEADER);
+        Ok((slot, Some(key_id)))
+    }
+
+    //write_slot 使用当前密钥和新的计数器加密整页
+    fn write_slot(&self, logical: u64, page: &[u8]) -> Result<()> {
+        let key_id = self.keys.current();
+        let key = self.keys.key(key_id)?;
+        let counter = self.next_counter()?;
+        let mut slot = vec![0u8; SLOT_HEADER + page.len()];
+        slot[SLOT_HEADER..].copy_from_slice(page);
+        let tag = self.cipher.seal(&key, &Self::nonce(logical, counter)?, &Self::aad(logical, key_id), &mut slot[SLOT_HEADER..])?;
+        BigEndian::write_u32(&mut slot[0..4], key_id);
+        BigEndian::write_u64(&mut slot[4..12], counter);
+        slot[12..28].copy_from_slice(&tag);
+        self.inner.write_page((logical / page.len() as u64 + 1) * Self::slot_size(), &slot)
+    }
+
+    //fill_slots 写入 logical 之前先把中间没有写入的页加密为 0, 已写入范围内不留下未认证的槽位
+    fn fill_slots(&self, logical: u64) -> Result<()> {
+        let page = page_size() as u64;
+        let zero = vec![0u8; page as usize];
+        for i in self.slots.load(Ordering::SeqCst)..logical / page {
+            self.write_slot(i * page, &zero)?;
+            self.slots.store(i + 1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// 用当前密钥重新加密一个页, 页已经是当前密钥或没有写入过时返回 false
+    pub fn reencrypt_page(&self, seek: u64) -> Result<bool> {
+        let _io = self.io_mut();
+        if seek >= self.len() {
+            return Ok(false);
+        }
+        let (page, key_id) = self.read_slot(seek)?;
+        match key_id {
+            Some(id) if id != self.keys.current() => {
+                self.write_slot(seek, &page)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// 还没有使用当前密钥加密的页数, 读取失败时返回错误
+    pub fn stale_pages(&self) -> Result<u64> {
+        let _io = self.io();
+        let current = self.keys.current();
+        let mut header = [0u8; 12];
+        let mut stale = 0;
+        for i in 0..self.slots.load(Ordering::SeqCst) {
+            let seek = (i + 1) * Self::slot_size();
+            self.inner.read_page(seek, &mut header)?;
+            if BigEndian::read_u64(&header[4..12]) == 0 {
+                return Err(BPlusError::NodeError(format!("unauthenticated page {}", i * page_size() as u64)).into());
+            }
+            if BigEndian::read_u32(&header[0..4]) != current {
+                stale += 1;
+            }
+        }
+        Ok(stale)
+    }
+}
+
+impl<S: PageStore> PageStore for EncryptedStore<S> {
+    //read_page 按逻辑位置读取, 可以跨页
+    fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()> {
+        let _io = self.io();
+        if seek + buf.len() as u64 > self.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("read beyond end at {}", seek)).into());
+        }
+        let page = page_size() as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = seek + done as u64;
+            let offset = (pos % page) as usize;
+            let len = (page as usize - offset).min(buf.len() - done);
+            let (data, _) = self.read_slot(pos - offset as u64)?;
+            buf[done..done + len].copy_from_slice(&data[offset..offset + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    //write_page 只写页的一部分时先解密原来的页, 修改后整页重新加密
+    fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+        let _io = self.io_mut();
+        let page = page_size() as u64;
+        let mut done = 0;
+        while done < data.len() {
+            let pos = seek + done as u64;
+            let offset = (pos % page) as usize;
+            let len = (page as usize - offset).min(data.len() - done);
+            let logical = pos - offset as u64;
+            self.fill_slots(logical)?;
+            if len == page as usize {
+                self.write_slot(logical, &data[done..done + len])?;
+            } else {
+                let (mut full, _) = self.read_slot(logical)?;
+                full[offset..offset + len].copy_from_slice(&data[done..done + len]);
+                self.write_slot(logical, &full)?;
+            }
+            self.slots.fetch_max(logical / page + 1, Ordering::SeqCst);
+            self.end.fetch_max(logical + page, Ordering::SeqCst);
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn allocate(&self, page_size: u64) -> u64 {
+        self.end.fetch_add(page_size, Ordering::SeqCst)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn len(&self) -> u64 {
+        self.end.load(Ordering::SeqCst)
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        let _io = self.io_mut();
+        let pages = len.div_ceil(page_size() as u64);
+        self.inner.truncate((pages + 1) * Self::slot_size())?;
+        self.end.store(len, Ordering::SeqCst);
+        self.slots.fetch_min(pages, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn scratch(&self) -> Result<Self> {
+        Self::new(self.inner.scratch()?, self.keys.clone(), self.cipher)
+    }
+
+    //replace 之后使用 other 的计数器, 两者中较大的预留上限写入超级块
+    fn replace(&self, other: Self) -> Result<u64> {
+        let _io = self.io_mut();
+        let mut counter = self.counter.lock().unwrap_or_else(|e| e.into_inner());
+        let other_counter = other.counter.lock().unwrap_or_else(|e| e.into_inner()).next;
+        let next = counter.next.max(other_counter);
+        let end = other.len();
+        let slots = other.slots.load(Ordering::SeqCst);
+        Self::write_super(&other.inner, self.cipher, next + COUNTER_RESERVE)?;
+        other.inner.sync()?;
+        let syncs = self.inner.replace(other.inner)?;
+        *counter = Counter { next, reserved: next + COUNTER_RESERVE };
+        self.end.store(end, Ordering::SeqCst);
+        self.slots.store(slots, Ordering::SeqCst);
+        Ok(syncs + 1)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn is_transformed(&self) -> bool {
+        true
+    }
+
+    fn location(&self) -> Option<&str> {
+        self.inner.location()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use crate::temp_path;
+    use crate::node::node::page_size;
+    use crate::store::{Cipher, EncryptedStore, MemoryStore, PageStore, StaticKeys};
+
+    #[test]
+    fn encrypted_store() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+            let keys = Arc::new(StaticKeys::new(1, [7u8; 32]));
+            let store = EncryptedStore::new(MemoryStore::new(), keys.clone(), cipher).unwrap();
+            let page = page_size() as u64;
+            let a = store.allocate(page);
+            let b = store.allocate(page);
+            //分配未写入
+            let mut buf = [1u8; 4];
+            store.read_page(b, &mut buf).unwrap();
+            assert_eq!(buf, [0u8; 4]);
+            store.write_page(a, &vec![5u8; page as usize]).unwrap();
+            store.write_page(b - 1, &[9, 9]).unwrap();
+            store.read_page(b - 2, &mut buf).unwrap();
+            assert_eq!(buf, [5, 9, 9, 0]);
+            //明文不会出现在存储中
+            assert!(!store.inner.to_vec().windows(64).any(|w| w.iter().all(|b| *b == 5)));
+
+            //密钥轮换后旧页仍然可读, 重新加密后不再需要旧密钥
+            keys.rotate(2, [8u8; 32]);
+            assert_eq!(store.stale_pages().unwrap(), 2);
+            assert!(store.reencrypt_page(a).unwrap());
+            assert!(!store.reencrypt_page(a).unwrap());
+            assert_eq!(store.stale_pages().unwrap(), 1);
+            store.read_page(a, &mut buf).unwrap();
+            assert_eq!(buf, [5u8; 4]);
+
+            //错误的密钥和被篡改的页都不能读取
+            let inner = MemoryStore::new();
+            inner.write_page(0, &store.inner.to_vec()).unwrap();
+            let wrong = EncryptedStore::new(inner, Arc::new(StaticKeys::new(2, [0u8; 32])), cipher).unwrap();
+            assert_eq!(wrong.len(), 2 * page);
+            assert!(wrong.read_page(a, &mut buf).is_err());
+            let slot = page + 28;
+            store.inner.write_page(slot + 100, &[0]).unwrap();
+            assert!(store.read_page(a, &mut buf).is_err());
+            assert!(EncryptedStore::new(MemoryStore::new(), keys.clone(), cipher).is_ok());
+        }
+    }
+
+    #[test]
+    fn encrypted_unwritten() {
+        let keys = Arc::new(StaticKeys::new(1, [7u8; 32]));
+        let store = EncryptedStore::new(MemoryStore::new(), keys.clone(), Cipher::Aes256Gcm).unwrap();
+        let page = page_size() as u64;
+        let slot = page + 28;
+        let a = store.allocate(page);
+        let b = store.allocate(page);
+        //跳过 a 写入 b, a 的槽位也被加密
+        store.write_page(b, &[3u8; 4]).unwrap();
+        let mut buf = [1u8; 4];
+        store.read_page(a, &mut buf).unwrap();
+        assert_eq!(buf, [0u8; 4]);
+        assert_eq!(store.stale_pages().unwrap(), 0);
+        keys.rotate(2, [8u8; 32]);
+        assert_eq!(store.stale_pages().unwrap(), 2);
+
+        //已写入范围内被清零的槽位不能当作没有写入的页
+        store.inner.write_page(slot, &vec![0u8; slot as usize]).unwrap();
+        assert!(store.read_page(a, &mut buf).is_err());
+        assert!(store.stale_pages().is_err());
+        let reopened = EncryptedStore::new(MemoryStore::new(), keys.clone(), Cipher::Aes256Gcm).unwrap();
+        reopened.inner.write_page(0, &store.inner.to_vec()).unwrap();
+        let reopened = EncryptedStore::new(reopened.inner, keys.clone(), Cipher::Aes256Gcm).unwrap();
+        assert!(reopened.read_page(a, &mut buf).is_err());
+
+        //截断的槽位读取失败时返回错误
+        store.inner.truncate(2 * slot + 100).unwrap();
+        assert!(store.stale_pages().is_err());
+    }
+
+    #[test]
+    fn encrypted_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        use std::time::Duration;
+        use crate::tree::{Config, Tree};
+        use crate::ValueTest;
+
+        let keys = Arc::new(StaticKeys::new(1, [3u8; 32]));
+        let tree = Tree::<u64, ValueTest, EncryptedStore>::open_encrypted(&temp_path(&dir, "encrypted_tree.db"), keys.clone(), Cipher::Aes256Gcm).unwrap();
+        for i in 0..1000u64 {
+            tree.insert(i, ValueTest { id: i as u32, data: format!("secret {}", i).repeat(if i == 500 { 3000 } else { 1 }) }).unwrap();
+        }
+        for i in (0..1000u64).step_by(3) {
+            tree.remove(&i).unwrap();
+        }
+        tree.compact().unwrap();
+        assert!(tree.verify().unwrap().is_ok());
+        assert!(!fs::read(temp_path(&dir, "encrypted_tree.db")).unwrap().windows(9).any(|w| w == b"secret 10"));
+
+        //后台重新加密期间继续写入
+        keys.rotate(2, [4u8; 32]);
+        let tree = Arc::new(tree);
+        let handle = Tree::spawn_rekey(tree.clone(), 4, Duration::from_millis(1));
+        for i in 1000..1200u64 {
+            tree.insert(i, ValueTest { id: i as u32, data: "new".to_string() }).unwrap();
+        }
+        let progress = handle.join().unwrap();
+        assert!(progress.done && progress.rewritten > 0);
+        assert_eq!(tree.store.stale_pages().unwrap(), 0);
+        drop(tree);
+
+        //只需要新密钥
+        let tree = Tree::<u64, ValueTest, EncryptedStore>::open_encrypted_with_config(&temp_path(&dir, "encrypted_tree.db"), Arc::new(StaticKeys::new(2, [4u8; 32])), Cipher::Aes256Gcm, Config { cache_pages: 0, ..Default::default() }).unwrap();
+        assert_eq!(tree.get(&500).unwrap().unwrap().data.len(), "secret 500".len() * 3000);
+        assert_eq!(tree.range(..).unwrap().count(), 866);
+        assert!(tree.verify().unwrap().is_ok());
+        drop(tree);
+        assert!(Tree::<u64, ValueTest, EncryptedStore>::open_encrypted(&temp_path(&dir, "encrypted_tree.db"), Arc::new(StaticKeys::new(2, [4u8; 32])), Cipher::ChaCha20Poly1305).is_err());
+    }
+}
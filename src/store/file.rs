@@ -0,0 +1,150 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use anyhow::Result;
+use crate::node::node::{page_size, BPlusError};
+use crate::store::PageStore;
+
+/// 文件存储, 默认的后端
+pub struct FileStore {
+    path: String,
+    fd: Mutex<File>,
+    //文件末尾 新页分配位置
+    end: AtomicU64,
+    //临时文件 没有被 replace 时删除
+    temporary: bool,
+    read_only: bool,
+}
+
+impl FileStore {
+    /// 打开或创建文件, 持有排他的文件锁, 其他进程已经打开时返回 Locked
+    pub fn open(path: &str) -> Result<Self> {
+        let fd = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .read(true)
+            .truncate(false)
+            .open(path)?;
+        lock_file(&fd, path, false)?;
+        Self::from_file(path, fd, false)
+    }
+
+    /// 只读打开已有的文件, 持有共享的文件锁, 可以和其他只读打开共存
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        let fd = OpenOptions::new().read(true).open(path)?;
+        lock_file(&fd, path, true)?;
+        Self::from_file(path, fd, true)
+    }
+
+    fn from_file(path: &str, fd: File, read_only: bool) -> Result<Self> {
+        let page = page_size() as u64;
+        let len = fd.metadata()?.len();
+        Ok(FileStore {
+            path: path.to_string(),
+            fd: Mutex::new(fd),
+            end: AtomicU64::new(len.div_ceil(page) * page),
+            temporary: false,
+            read_only,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub(crate) fn file(&self) -> MutexGuard<'_, File> {
+        self.fd.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl PageStore for FileStore {
+    fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()> {
+        let mut fd = self.file();
+        fd.seek(SeekFrom::Start(seek))?;
+        fd.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+        let mut fd = self.file();
+        fd.seek(SeekFrom::Start(seek))?;
+        fd.write_all(data)?;
+        Ok(())
+    }
+
+    fn allocate(&self, page_size: u64) -> u64 {
+        self.end.fetch_add(page_size, Ordering::SeqCst)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file().sync_all()?;
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.end.load(Ordering::SeqCst)
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        self.file().set_len(len)?;
+        self.end.store(len, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn scratch(&self) -> Result<Self> {
+        let tmp = format!("{}.compact", self.path);
+        let _ = fs::remove_file(&tmp);
+        let mut store = FileStore::open(&tmp)?;
+        store.temporary = true;
+        Ok(store)
+    }
+
+    //replace 临时文件 rename 到当前路径后使用它的 fd, 文件锁跟着 fd 保留
+    fn replace(&self, mut other: Self) -> Result<u64> {
+        fs::rename(&other.path, &self.path)?;
+        other.temporary = false;
+        sync_dir(&self.path)?;
+        let fd = other.file().try_clone()?;
+        let len = fd.metadata()?.len();
+        *self.file() = fd;
+        self.end.store(len, Ordering::SeqCst);
+        Ok(1)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn location(&self) -> Option<&str> {
+        (!self.temporary).then_some(self.path.as_str())
+    }
+}
+
+//lock_file 不等待的文件锁, 已经被其他打开持有时返回 Locked
+pub(crate) fn lock_file(fd: &File, path: &str, shared: bool) -> Result<()> {
+    let result = if shared { fd.try_lock_shared() } else { fd.try_lock() };
+    match result {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(BPlusError::Locked(path.to_string()).into()),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+//sync_dir rename 之后持久化所在目录
+pub(crate) fn sync_dir(path: &str) -> Result<()> {
+    if let Some(dir) = Path::new(path).parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
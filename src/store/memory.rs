@@ -0,0 +1,140 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use anyhow::Result;
+use crate::store::PageStore;
+
+/// 内存存储, 页格式和文件完全相同, 用于测试和临时数据
+#[derive(Default)]
+pub struct MemoryStore {
+    data: RwLock<Vec<u8>>,
+    //新页分配位置 分配后还没写入的页读出来是 0
+    end: AtomicU64,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 全部数据的拷贝, 可以写入文件后用 FileStore 打开
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut data = self.data.read().unwrap_or_else(|e| e.into_inner()).clone();
+        data.resize(self.len() as usize, 0);
+        data
+    }
+}
+
+impl PageStore for MemoryStore {
+    fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()> {
+        let start = seek as usize;
+        if seek + buf.len() as u64 > self.len() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let data = self.data.read().unwrap_or_else(|e| e.into_inner());
+        let available = data.len().saturating_sub(start).min(buf.len());
+        if available > 0 {
+            buf[..available].copy_from_slice(&data[start..start + available]);
+        }
+        buf[available..].fill(0);
+        Ok(())
+    }
+
+    fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+        let start = seek as usize;
+        let mut buf = self.data.write().unwrap_or_else(|e| e.into_inner());
+        if buf.len() < start + data.len() {
+            buf.resize(start + data.len(), 0);
+        }
+        buf[start..start + data.len()].copy_from_slice(data);
+        self.end.fetch_max((start + data.len()) as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn allocate(&self, page_size: u64) -> u64 {
+        self.end.fetch_add(page_size, Ordering::SeqCst)
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.end.load(Ordering::SeqCst)
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        self.data.write().unwrap_or_else(|e| e.into_inner()).truncate(len as usize);
+        self.end.store(len, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn scratch(&self) -> Result<Self> {
+        Ok(MemoryStore::new())
+    }
+
+    fn replace(&self, other: Self) -> Result<u64> {
+        let len = other.len();
+        *self.data.write().unwrap_or_else(|e| e.into_inner()) = other.data.into_inner().unwrap_or_else(|e| e.into_inner());
+        self.end.store(len, Ordering::SeqCst);
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    use crate::temp_path;
+    use crate::store::{MemoryStore, PageStore};
+    use crate::tree::Tree;
+    use crate::ValueTest;
+
+    #[test]
+    fn memory_store() {
+        let store = MemoryStore::new();
+        assert_eq!(store.allocate(16), 0);
+        assert_eq!(store.allocate(16), 16);
+        let mut buf = [1u8; 16];
+        //分配未写入
+        store.read_page(16, &mut buf).unwrap();
+        assert_eq!(buf, [0u8; 16]);
+        store.write_page(20, &[7, 7]).unwrap();
+        store.read_page(16, &mut buf).unwrap();
+        assert_eq!(&buf[..6], &[0, 0, 0, 0, 7, 7]);
+        assert!(store.read_page(24, &mut buf).is_err());
+        store.truncate(16).unwrap();
+        assert_eq!(store.len(), 16);
+        assert!(store.read_page(16, &mut buf).is_err());
+    }
+
+    #[test]
+    fn memory_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = Arc::new(Tree::<u64, ValueTest, MemoryStore>::in_memory().unwrap());
+        let handles: Vec<_> = (0..4u64).map(|t| {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                for i in (t..800).step_by(4) {
+                    tree.insert(i, ValueTest { id: i as u32, data: "m".repeat(if i.is_multiple_of(100) { 30000 } else { 30 }) }).unwrap();
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        for i in 0..800u64 {
+            assert_eq!(tree.get(&i).unwrap().unwrap().id, i as u32);
+        }
+        assert!(tree.verify().unwrap().is_ok());
+        tree.compact().unwrap();
+        assert_eq!(tree.range(..).unwrap().count(), 800);
+
+        //内存中的格式和文件相同
+        fs::write(temp_path(&dir, "memory.db"), tree.store.to_vec()).unwrap();
+        let file = Tree::<u64, ValueTest>::open(&temp_path(&dir, "memory.db")).unwrap();
+        assert_eq!(file.get(&100).unwrap().unwrap().data.len(), 30000);
+        assert!(file.verify().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use anyhow::Result;
+use memmap2::Mmap;
+use crate::store::{FileStore, PageStore};
+
+/// 读取走内存映射的文件存储, 写入 分配 sync 仍然由 FileStore 完成
+/// 文件只能由当前进程修改, 其他进程截断文件时访问映射会 SIGBUS
+pub struct MmapStore {
+    file: FileStore,
+    //映射文件已写入的部分, 空文件时为 None
+    map: RwLock<Option<Mmap>>,
+}
+
+impl MmapStore {
+    /// 打开或创建文件并映射
+    pub fn open(path: &str) -> Result<Self> {
+        let store = MmapStore {
+            file: FileStore::open(path)?,
+            map: RwLock::new(None),
+        };
+        store.remap(&mut store.map_mut())?;
+        Ok(store)
+    }
+
+    pub fn path(&self) -> &str {
+        self.file.path()
+    }
+
+    fn map(&self) -> RwLockReadGuard<'_, Option<Mmap>> {
+        self.map.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn map_mut(&self) -> RwLockWriteGuard<'_, Option<Mmap>> {
+        self.map.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //remap 按当前文件长度重新映射, 调用方持有写锁, 没有读者在使用旧映射
+    fn remap(&self, map: &mut Option<Mmap>) -> Result<()> {
+        *map = None;
+        let fd = self.file.file();
+        if fd.metadata()?.len() == 0 {
+            return Ok(());
+        }
+        // SAFETY: 文件只由当前进程通过 self.file 修改, 截断前先释放映射
+        *map = Some(unsafe { Mmap::map(&*fd)? });
+        Ok(())
+    }
+}
+
+//mapped 映射范围内时返回页数据
+fn mapped(map: &Option<Mmap>, seek: u64, len: usize) -> Option<&[u8]> {
+    let map = map.as_ref()?;
+    let start = usize::try_from(seek).ok()?;
+    map.get(start..start.checked_add(len)?)
+}
+
+impl PageStore for MmapStore {
+    fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()> {
+        let len = buf.len();
+        self.with_page(seek, len, |page| buf.copy_from_slice(page))
+    }
+
+    //with_page 直接使用映射的数据, 文件增长超出映射时重新映射
+    fn with_page<R>(&self, seek: u64, len: usize, f: impl FnOnce(&[u8]) -> R) -> Result<R> {
+        {
+            let map = self.map();
+            if let Some(page) = mapped(&map, seek, len) {
+                return Ok(f(page));
+            }
+        }
+        let mut map = self.map_mut();
+        if mapped(&map, seek, len).is_none() {
+            self.remap(&mut map)?;
+        }
+        match mapped(&map, seek, len) {
+            Some(page) => Ok(f(page)),
+            //还没有写入文件的页, 按文件读取返回同样的错误
+            None => {
+                let mut buf = vec![0u8; len];
+                self.file.read_page(seek, &mut buf)?;
+                Ok(f(&buf))
+            }
+        }
+    }
+
+    //write_page 写入文件, 共享映射中的数据随页缓存一起更新
+    fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+        self.file.write_page(seek, data)
+    }
+
+    fn allocate(&self, page_size: u64) -> u64 {
+        self.file.allocate(page_size)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync()
+    }
+
+    fn len(&self) -> u64 {
+        self.file.len()
+    }
+
+    //truncate 先释放映射, 避免访问截掉的部分
+    fn truncate(&self, len: u64) -> Result<()> {
+        let mut map = self.map_mut();
+        *map = None;
+        self.file.truncate(len)?;
+        self.remap(&mut map)
+    }
+
+    fn scratch(&self) -> Result<Self> {
+        Ok(MmapStore {
+            file: self.file.scratch()?,
+            map: RwLock::new(None),
+        })
+    }
+
+    fn replace(&self, other: Self) -> Result<u64> {
+        let mut map = self.map_mut();
+        *map = None;
+        let syncs = self.file.replace(other.file)?;
+        self.remap(&mut map)?;
+        Ok(syncs)
+    }
+
+    fn location(&self) -> Option<&str> {
+        self.file.location()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::temp_path;
+    use crate::node::node::page_size;
+    use crate::store::{MmapStore, PageStore};
+    use crate::tree::Tree;
+    use crate::ValueTest;
+
+    #[test]
+    fn mmap_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MmapStore::open(&temp_path(&dir, "mmap_store.db")).unwrap();
+        let page = page_size() as u64;
+        let seek = store.allocate(page);
+        store.write_page(seek, &vec![7u8; page as usize]).unwrap();
+        //写入后文件增长, 读取时重新映射
+        assert_eq!(store.with_page(seek, 4, |b| b.to_vec()).unwrap(), vec![7u8; 4]);
+        store.write_page(seek + 1, &[9]).unwrap();
+        assert_eq!(store.with_page(seek, 3, |b| b.to_vec()).unwrap(), vec![7, 9, 7]);
+        let next = store.allocate(page);
+        assert!(store.with_page(next, 4, |_| ()).is_err());
+        store.truncate(0).unwrap();
+        assert!(store.with_page(seek, 4, |_| ()).is_err());
+    }
+
+    #[test]
+    fn mmap_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = Tree::<u64, ValueTest, MmapStore>::open_mmap(&temp_path(&dir, "mmap_tree.db")).unwrap();
+        for i in 0..3000u64 {
+            tree.insert(i, ValueTest { id: i as u32, data: "m".repeat(if i % 1000 == 1 { 30000 } else { 20 }) }).unwrap();
+        }
+        for i in (0..3000u64).step_by(2) {
+            tree.remove(&i).unwrap();
+        }
+        for i in 0..3000u64 {
+            assert_eq!(tree.get(&i).unwrap().map(|v| v.id), if i % 2 == 0 { None } else { Some(i as u32) });
+        }
+        assert_eq!(tree.range(..).unwrap().count(), 1500);
+        tree.compact().unwrap();
+        assert_eq!(tree.get(&1001).unwrap().unwrap().data.len(), 30000);
+        assert!(tree.verify().unwrap().is_ok());
+        assert_eq!(tree.metrics().cache_hits, 0);
+    }
+}
@@ -0,0 +1,28 @@
+#[allow(clippy::module_inception)]
+mod store;
+mod file;
+mod memory;
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(all(feature = "direct", target_os = "linux"))]
+mod direct;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+mod compressed;
+#[cfg(feature = "encrypt")]
+mod encrypted;
+pub use store::PageStore;
+pub use file::FileStore;
+pub(crate) use file::sync_dir;
+pub use memory::MemoryStore;
+#[cfg(feature = "mmap")]
+pub use mmap::MmapStore;
+#[cfg(all(feature = "direct", target_os = "linux"))]
+pub use direct::DirectStore;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub use uring::UringStore;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+pub use compressed::{CompressedStore, PageCodec};
+#[cfg(feature = "encrypt")]
+pub use encrypted::{Cipher, EncryptedStore, KeyProvider, StaticKeys};
@@ -0,0 +1,135 @@
+use anyhow::Result;
+
+/// 页存储后端, 树只通过它读写页, 页内格式由 node 编码
+/// 读写位置按字节计算, 可以只读写页头中的一部分(flag prev next)
+pub trait PageStore: Send + Sync + Sized + 'static {
+    /// 从 seek 位置读取 buf.len() 字节
+    fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// 读取 seek 位置 len 字节后调用 f, 映射文件的存储可以直接提供数据不拷贝
+    fn with_page<R>(&self, seek: u64, len: usize, f: impl FnOnce(&[u8]) -> R) -> Result<R> {
+        let mut buf = vec![0u8; len];
+        self.read_page(seek, &mut buf)?;
+        Ok(f(&buf))
+    }
+
+    /// 批量读取多个位置的 len 字节, 支持异步 IO 的存储可以一次提交所有读取
+    fn read_pages(&self, seeks: &[u64], len: usize) -> Result<Vec<Vec<u8>>> {
+        seeks.iter().map(|&seek| {
+            let mut buf = vec![0u8; len];
+            self.read_page(seek, &mut buf)?;
+            Ok(buf)
+        }).collect()
+    }
+
+    /// 只读打开的存储, 树拒绝所有修改
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// 页在存储中经过压缩或加密, 文件中的内容和读到的页不同, 在线备份不支持这类存储
+    fn is_transformed(&self) -> bool {
+        false
+    }
+
+    /// 持久存储的文件路径, 树在旁边保存页修改序号等附加信息, 内存和临时存储返回 None
+    fn location(&self) -> Option<&str> {
+        None
+    }
+
+    /// 从 seek 位置写入 data
+    fn write_page(&self, seek: u64, data: &[u8]) -> Result<()>;
+
+    /// 在末尾分配一个页, 返回页的位置
+    fn allocate(&self, page_size: u64) -> u64;
+
+    /// 已写入的数据持久化
+    fn sync(&self) -> Result<()>;
+
+    /// 存储长度, 包含已分配还没写入的页
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 截断到 len
+    fn truncate(&self, len: u64) -> Result<()>;
+
+    /// 创建一个同类型的空存储, compact 时在里面重建树
+    fn scratch(&self) -> Result<Self>;
+
+    /// 用 other 的内容替换当前存储, other 已经 sync, 返回替换时执行的 fsync 次数
+    fn replace(&self, other: Self) -> Result<u64>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use anyhow::Result;
+    use crate::temp_path;
+    use crate::store::{FileStore, PageStore};
+    use crate::tree::{Config, Tree};
+
+    //Counting 统计写入次数的存储, 验证其他后端可以直接接入
+    struct Counting {
+        inner: FileStore,
+        writes: AtomicU64,
+    }
+
+    impl PageStore for Counting {
+        fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()> {
+            self.inner.read_page(seek, buf)
+        }
+
+        fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.write_page(seek, data)
+        }
+
+        fn allocate(&self, page_size: u64) -> u64 {
+            self.inner.allocate(page_size)
+        }
+
+        fn sync(&self) -> Result<()> {
+            self.inner.sync()
+        }
+
+        fn len(&self) -> u64 {
+            self.inner.len()
+        }
+
+        fn truncate(&self, len: u64) -> Result<()> {
+            self.inner.truncate(len)
+        }
+
+        fn scratch(&self) -> Result<Self> {
+            Ok(Counting { inner: self.inner.scratch()?, writes: AtomicU64::new(0) })
+        }
+
+        fn replace(&self, other: Self) -> Result<u64> {
+            self.inner.replace(other.inner)
+        }
+    }
+
+    #[test]
+    fn custom_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Counting { inner: FileStore::open(&temp_path(&dir, "store.db")).unwrap(), writes: AtomicU64::new(0) };
+        let tree = Tree::<u64, u64, Counting>::with_store(store, Config::default()).unwrap();
+        for i in 0..2000u64 {
+            tree.insert(i, i + 1).unwrap();
+        }
+        assert!(tree.store.writes.load(Ordering::SeqCst) > 2000);
+        tree.compact().unwrap();
+        assert!(!std::path::Path::new(&temp_path(&dir, "store.db.compact")).exists());
+        for i in 0..2000u64 {
+            assert_eq!(tree.get(&i).unwrap(), Some(i + 1));
+        }
+        drop(tree);
+        //同一个文件用默认的 FileStore 打开
+        let tree = Tree::<u64, u64>::open(&temp_path(&dir, "store.db")).unwrap();
+        assert_eq!(tree.get(&1999).unwrap(), Some(2000));
+        assert!(tree.verify().unwrap().is_ok());
+    }
+}
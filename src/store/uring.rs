@@ -0,0 +1,214 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Mutex, MutexGuard};
+use anyhow::Result;
+use io_uring::{opcode, types, IoUring};
+use crate::store::{FileStore, PageStore};
+
+//一次提交的最大读取数
+const RING_ENTRIES: u32 = 64;
+
+/// 使用 io_uring 批量读取的文件存储, 单页读写和分配仍然由 FileStore 完成
+/// 范围遍历的预读和 get_many 同一层的页通过 read_pages 一次提交
+pub struct UringStore {
+    file: FileStore,
+    ring: Mutex<Ring>,
+}
+
+//Ring 每批读取使用不同的 tag 放在 user_data 的高 32 位, 不属于当前批次的完成事件被丢弃
+struct Ring {
+    ring: IoUring,
+    tag: u32,
+}
+
+impl Ring {
+    fn new() -> Result<Self> {
+        Ok(Ring { ring: IoUring::new(RING_ENTRIES)?, tag: 0 })
+    }
+
+    //wait 等待当前批次已经放入队列的 pending 个读取全部完成, 返回 (序号, 结果)
+    //EINTR 时重试, 其他错误时读取可能还在进行, 由调用方处理缓冲区
+    fn wait(&mut self, pending: usize) -> io::Result<Vec<(usize, i32)>> {
+        let mut completed = Vec::with_capacity(pending);
+        while completed.len() < pending {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+            for cqe in self.ring.completion() {
+                let data = cqe.user_data();
+                if (data >> 32) as u32 == self.tag && ((data as u32) as usize) < pending {
+                    completed.push((data as u32 as usize, cqe.result()));
+                }
+            }
+        }
+        Ok(completed)
+    }
+}
+
+impl UringStore {
+    /// 打开或创建文件, 内核不支持 io_uring 时返回错误
+    pub fn open(path: &str) -> Result<Self> {
+        Ok(UringStore {
+            file: FileStore::open(path)?,
+            ring: Mutex::new(Ring::new()?),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        self.file.path()
+    }
+
+    fn ring(&self) -> MutexGuard<'_, Ring> {
+        self.ring.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl PageStore for UringStore {
+    fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.read_page(seek, buf)
+    }
+
+    //read_pages 每批最多 RING_ENTRIES 个读取一起提交, 返回前等待已经提交的读取全部完成
+    fn read_pages(&self, seeks: &[u64], len: usize) -> Result<Vec<Vec<u8>>> {
+        let mut pages = vec![vec![0u8; len]; seeks.len()];
+        let mut ring = self.ring();
+        //持有文件锁, replace 不能在读取期间替换 fd
+        let fd = self.file.file();
+        let fd = types::Fd(fd.as_raw_fd());
+        for (batch, chunk) in pages.chunks_mut(RING_ENTRIES as usize).enumerate() {
+            let base = batch * RING_ENTRIES as usize;
+            ring.tag = ring.tag.wrapping_add(1);
+            let tag = (ring.tag as u64) << 32;
+            let mut pending = 0;
+            let mut error: Option<anyhow::Error> = None;
+            for (i, page) in chunk.iter_mut().enumerate() {
+                let entry = opcode::Read::new(fd, page.as_mut_ptr(), len as u32)
+                    .offset(seeks[base + i])
+                    .build()
+                    .user_data(tag | i as u64);
+                // SAFETY: 缓冲区在等待完成之前不会被释放或移动
+                if let Err(e) = unsafe { ring.ring.submission().push(&entry) } {
+                    error = Some(e.into());
+                    break;
+                }
+                pending += 1;
+            }
+            let completed = match ring.wait(pending) {
+                Ok(completed) => completed,
+                Err(e) => {
+                    //读取可能还在进行, 泄漏缓冲区和旧的 ring, 内核不会写入已经释放的内存
+                    let stuck = std::mem::replace(&mut *ring, Ring::new()?);
+                    std::mem::forget(stuck);
+                    std::mem::forget(pages);
+                    return Err(e.into());
+                }
+            };
+            if let Some(e) = error {
+                return Err(e);
+            }
+            let mut done = vec![false; chunk.len()];
+            for (i, read) in completed {
+                if std::mem::replace(&mut done[i], true) {
+                    return Err(io::Error::other("io_uring duplicate completion").into());
+                }
+                if read < 0 {
+                    return Err(io::Error::from_raw_os_error(-read).into());
+                }
+                //普通文件只有读到文件末尾时才会短读, 和 read_exact 返回同样的错误
+                if read as usize != len {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("failed to fill whole buffer at {}", seeks[base + i])).into());
+                }
+            }
+        }
+        Ok(pages)
+    }
+
+    fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+        self.file.write_page(seek, data)
+    }
+
+    fn allocate(&self, page_size: u64) -> u64 {
+        self.file.allocate(page_size)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync()
+    }
+
+    fn len(&self) -> u64 {
+        self.file.len()
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        self.file.truncate(len)
+    }
+
+    fn scratch(&self) -> Result<Self> {
+        Ok(UringStore {
+            file: self.file.scratch()?,
+            ring: Mutex::new(Ring::new()?),
+        })
+    }
+
+    fn replace(&self, other: Self) -> Result<u64> {
+        self.file.replace(other.file)
+    }
+
+    fn location(&self) -> Option<&str> {
+        self.file.location()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use io_uring::opcode;
+    use crate::temp_path;
+    use crate::node::node::page_size;
+    use crate::store::{PageStore, UringStore};
+    use crate::tree::{Config, Tree};
+
+    #[test]
+    fn uring_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = UringStore::open(&temp_path(&dir, "uring_store.db")).unwrap();
+        let page = page_size() as u64;
+        //超过一次提交的数量
+        let seeks: Vec<u64> = (0..100u64).map(|i| {
+            let seek = store.allocate(page);
+            store.write_page(seek, &vec![i as u8; page as usize]).unwrap();
+            seek
+        }).collect();
+        let reversed: Vec<u64> = seeks.iter().rev().copied().collect();
+        let pages = store.read_pages(&reversed, 8).unwrap();
+        assert_eq!(pages.len(), 100);
+        for (i, page) in pages.iter().enumerate() {
+            assert_eq!(page, &vec![(99 - i) as u8; 8]);
+        }
+        assert!(store.read_pages(&[0, 100 * page], 8).is_err());
+        //上一次留下的完成事件不会被当作这一次的结果
+        {
+            let mut ring = store.ring();
+            let nop = opcode::Nop::new().build().user_data(((ring.tag as u64) << 32) | 1);
+            unsafe { ring.ring.submission().push(&nop).unwrap() };
+            ring.ring.submit().unwrap();
+        }
+        let pages = store.read_pages(&seeks[..2], 8).unwrap();
+        assert_eq!(pages, vec![vec![0u8; 8], vec![1u8; 8]]);
+    }
+
+    #[test]
+    fn uring_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { fill_factor: 0.02, ..Default::default() };
+        let tree = Tree::<u64, u64, UringStore>::open_uring_with_config(&temp_path(&dir, "uring_tree.db"), config.clone()).unwrap();
+        tree.bulk_load((0..5000u64).map(|i| (i, i))).unwrap();
+        drop(tree);
+        let tree = Tree::<u64, u64, UringStore>::open_uring_with_config(&temp_path(&dir, "uring_tree.db"), config).unwrap();
+        assert_eq!(tree.get_many(&[4999, 1, 5000]).unwrap(), vec![Some(4999), Some(1), None]);
+        assert_eq!(tree.range(100..).unwrap().map(|r| r.unwrap().0).collect::<Vec<_>>(), (100..5000).collect::<Vec<_>>());
+        tree.compact().unwrap();
+        assert!(tree.verify().unwrap().is_ok());
+    }
+}
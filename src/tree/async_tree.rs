@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::ops::RangeBounds;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use anyhow::Result;
+use futures_core::Stream;
+use tokio::sync::mpsc;
+use tokio::task;
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::store::{FileStore, PageStore};
+use crate::tree::Tree;
+
+//范围遍历每次发送的数据条数
+const RANGE_BATCH: usize = 64;
+//范围遍历最多预先读取的批数
+const RANGE_CHANNEL: usize = 4;
+
+/// Tree 的异步封装, 每个操作放到 tokio 的阻塞线程池执行, 不阻塞异步任务
+/// 取消安全: 丢弃已经开始的 future 不会中断操作, 操作完整执行后结果被丢弃, 树不会停在中间状态
+pub struct AsyncTree<K, V, S: PageStore = FileStore> {
+    tree: Arc<Tree<K, V, S>>,
+}
+
+impl<K, V, S: PageStore> Clone for AsyncTree<K, V, S> {
+    fn clone(&self) -> Self {
+        AsyncTree {
+            tree: self.tree.clone(),
+        }
+    }
+}
+
+impl<K, V, S> AsyncTree<K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync + 'static,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync + 'static,
+    S: PageStore + 'static
+{
+    pub fn new(tree: Tree<K, V, S>) -> Self {
+        AsyncTree {
+            tree: Arc::new(tree),
+        }
+    }
+
+    /// 内部的同步树, 可以在阻塞线程中直接使用
+    pub fn tree(&self) -> &Arc<Tree<K, V, S>> {
+        &self.tree
+    }
+
+    //blocking 在阻塞线程池中执行 f
+    async fn blocking<R: Send + 'static>(&self, f: impl FnOnce(&Tree<K, V, S>) -> Result<R> + Send + 'static) -> Result<R> {
+        let tree = self.tree.clone();
+        task::spawn_blocking(move || f(&tree)).await?
+    }
+
+    pub async fn get(&self, key: K) -> Result<Option<V>> {
+        self.blocking(move |tree| tree.get(&key)).await
+    }
+
+    pub async fn insert(&self, key: K, value: V) -> Result<Option<V>> {
+        self.blocking(move |tree| tree.insert(key, value)).await
+    }
+
+    pub async fn remove(&self, key: K) -> Result<Option<V>> {
+        self.blocking(move |tree| tree.remove(&key)).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.blocking(|tree| tree.flush()).await
+    }
+
+    /// 范围遍历, 在阻塞线程中按批读取, 需要在 tokio 运行时中调用
+    /// 丢弃 stream 后读取线程在发送下一批时结束
+    pub fn range<R: RangeBounds<K> + Send + 'static>(&self, range: R) -> RangeStream<K, V> {
+        let (tx, rx) = mpsc::channel(RANGE_CHANNEL);
+        let tree = self.tree.clone();
+        task::spawn_blocking(move || {
+            let iter = match tree.range(range) {
+                Ok(iter) => iter,
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                    return;
+                }
+            };
+            let mut batch = Vec::with_capacity(RANGE_BATCH);
+            for entry in iter {
+                match entry {
+                    Ok(entry) => batch.push(entry),
+                    Err(e) => {
+                        //先发送已经读到的数据
+                        if !batch.is_empty() && tx.blocking_send(Ok(batch)).is_err() {
+                            return;
+                        }
+                        let _ = tx.blocking_send(Err(e));
+                        return;
+                    }
+                }
+                if batch.len() == RANGE_BATCH && tx.blocking_send(Ok(std::mem::take(&mut batch))).is_err() {
+                    return;
+                }
+            }
+            if !batch.is_empty() {
+                let _ = tx.blocking_send(Ok(batch));
+            }
+        });
+        RangeStream {
+            rx,
+            buffer: VecDeque::new(),
+        }
+    }
+}
+
+/// 异步范围遍历 由 AsyncTree::range 创建
+pub struct RangeStream<K, V> {
+    rx: mpsc::Receiver<Result<Vec<(K, V)>>>,
+    buffer: VecDeque<(K, V)>,
+}
+
+//没有需要固定的字段
+impl<K, V> Unpin for RangeStream<K, V> {}
+
+impl<K, V> Stream for RangeStream<K, V> {
+    type Item = Result<(K, V)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(entry) = this.buffer.pop_front() {
+                return Poll::Ready(Some(Ok(entry)));
+            }
+            match ready!(this.rx.poll_recv(cx)) {
+                Some(Ok(batch)) => this.buffer.extend(batch),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::pin::Pin;
+    use anyhow::Result;
+    use futures_core::Stream;
+    use tokio::runtime::Builder;
+    use crate::store::MemoryStore;
+    use crate::tree::{AsyncTree, RangeStream, Tree};
+
+    async fn next(stream: &mut RangeStream<u64, u64>) -> Option<Result<(u64, u64)>> {
+        poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    #[test]
+    fn async_tree() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let tree = AsyncTree::new(Tree::<u64, u64, MemoryStore>::in_memory().unwrap());
+            let tasks: Vec<_> = (0..4u64).map(|t| {
+                let tree = tree.clone();
+                tokio::spawn(async move {
+                    for i in (t..2000).step_by(4) {
+                        tree.insert(i, i * 10).await.unwrap();
+                    }
+                })
+            }).collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            assert_eq!(tree.get(7).await.unwrap(), Some(70));
+            assert_eq!(tree.remove(7).await.unwrap(), Some(70));
+            assert_eq!(tree.get(7).await.unwrap(), None);
+            tree.flush().await.unwrap();
+
+            let mut stream = tree.range(100..=300);
+            let mut keys = vec![];
+            while let Some(entry) = next(&mut stream).await {
+                keys.push(entry.unwrap().0);
+            }
+            assert_eq!(keys, (100..=300).collect::<Vec<u64>>());
+
+            //提前丢弃 stream, 读取线程结束后可以继续修改
+            let mut stream = tree.range(..);
+            assert_eq!(next(&mut stream).await.unwrap().unwrap(), (0, 0));
+            drop(stream);
+            tree.insert(5000, 1).await.unwrap();
+            assert!(tree.tree().verify().unwrap().is_ok());
+        });
+    }
+}
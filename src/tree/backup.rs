@@ -0,0 +1,419 @@
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::MutexGuard;
+use std::thread;
+use anyhow::Result;
+use byteorder::{BigEndian, ByteOrder};
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::node::node::{page_size, BPlusError};
+use crate::store::{sync_dir, FileStore, PageStore};
+use crate::tree::lsn::{lsn_path, read_lsn_file, write_lsn_file};
+use crate::tree::{Config, Tree};
+
+//每次持有读锁复制的页数
+const BACKUP_STEP: u64 = 64;
+
+//增量备份文件头: magic(8) since(8) lsn(8) len(8) count(8), 后面 count 个 (页位置(8), 页)
+const INCREMENTAL_MAGIC: [u8; 8] = *b"BPTINC01";
+const INCREMENTAL_HEADER: u64 = 40;
+
+//正在进行的备份, 开始时的页在被修改前先复制到备份文件
+pub(crate) struct BackupState {
+    file: File,
+    // 备份开始时的长度, 之后分配的页不属于备份
+    len: u64,
+    // 已经复制的页, 增量备份开始时没有修改过的页也标记为已复制
+    copied: Vec<bool>,
+    // 增量备份已经写入的页数, 全量备份为 None
+    incremental: Option<u64>,
+    // 修改前复制失败时记录错误, 不影响修改本身, 由 backup_to 返回
+    error: Option<String>,
+}
+
+impl<K, V> Tree<K, V, FileStore> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync
+{
+    /// 把全量备份复制到 path, 再按顺序应用增量备份, 完成后打开 path
+    /// 全量备份需要在开启 track_changes 时创建, 每个增量备份的 since 不能大于前一个备份的 lsn
+    pub fn restore(full: &str, incrementals: &[&str], path: &str, config: Config) -> Result<Self> {
+        let page = page_size() as u64;
+        let (clean, mut lsn, mut pages) = read_lsn_file(&lsn_path(full))?
+            .ok_or_else(|| BPlusError::NodeError(format!("{} has no lsn file, backup with track_changes enabled", full)))?;
+        if !clean {
+            return Err(BPlusError::NodeError(format!("incomplete lsn file for {}", full)).into());
+        }
+        let tmp = format!("{}.restore", path);
+        fs::copy(full, &tmp)?;
+        let file = OpenOptions::new().write(true).open(&tmp)?;
+        let result = (|| -> Result<()> {
+            for incremental in incrementals {
+                let source = File::open(incremental)?;
+                let mut header = [0u8; INCREMENTAL_HEADER as usize];
+                read_at(&source, &mut header, 0)?;
+                if header[0..8] != INCREMENTAL_MAGIC {
+                    return Err(BPlusError::NodeError(format!("{} is not an incremental backup", incremental)).into());
+                }
+                let since = BigEndian::read_u64(&header[8..16]);
+                let end = BigEndian::read_u64(&header[16..24]);
+                let len = BigEndian::read_u64(&header[24..32]);
+                let count = BigEndian::read_u64(&header[32..40]);
+                if since > lsn || end < lsn {
+                    return Err(BPlusError::NodeError(format!("{} covers lsn {}..{} but restored lsn is {}", incremental, since, end, lsn)).into());
+                }
+                file.set_len(len)?;
+                pages.resize((len / page) as usize, end);
+                let mut record = vec![0u8; 8 + page as usize];
+                for i in 0..count {
+                    read_at(&source, &mut record, INCREMENTAL_HEADER + i * (8 + page))?;
+                    let seek = BigEndian::read_u64(&record[0..8]);
+                    if seek >= len || !seek.is_multiple_of(page) {
+                        return Err(BPlusError::NodeError(format!("invalid page {} in {}", seek, incremental)).into());
+                    }
+                    write_at(&file, &record[8..], seek)?;
+                    pages[(seek / page) as usize] = end;
+                }
+                lsn = end;
+            }
+            file.sync_all()?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        //旧的 .lsn 文件不属于恢复的文件
+        let _ = fs::remove_file(lsn_path(path));
+        fs::rename(&tmp, path)?;
+        sync_dir(path)?;
+        write_lsn_file(&lsn_path(path), true, lsn, &pages)?;
+        Self::open_with_config(path, config)
+    }
+}
+
+impl<K, V, S> Tree<K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    /// 当前的修改序号, 没有开启 track_changes 时为 0
+    pub fn lsn(&self) -> u64 {
+        self.lsn.state().lsn
+    }
+
+    /// 在线备份到 path, 得到开始备份时的一致副本, 可以用 Tree::open 打开
+    /// 每次只在读锁下复制少量页, 期间读写正常进行, 被修改的页在修改前先复制原来的内容
+    /// 备份文件是普通的页格式, 压缩和加密的树返回错误, 不会把解密后的页写到磁盘上
+    /// 返回备份时的修改序号, 开启 track_changes 时同时写入 path 的 .lsn 文件, 作为增量备份的起点
+    pub fn backup_to(&self, path: &str) -> Result<u64> {
+        let (lsn, pages) = self.run_backup(path, None)?;
+        if self.lsn.enabled() {
+            write_lsn_file(&lsn_path(path), true, lsn, &pages)?;
+        }
+        Ok(lsn)
+    }
+
+    /// 增量备份, 只复制修改序号大于 since 的页, since 是上一次备份返回的序号
+    /// 需要开启 track_changes, 返回这次备份的序号, 由 Tree::restore 应用
+    pub fn backup_incremental(&self, path: &str, since: u64) -> Result<u64> {
+        if !self.lsn.enabled() {
+            return Err(BPlusError::NodeError("incremental backup needs Config::track_changes".to_string()).into());
+        }
+        if since > self.lsn() {
+            return Err(BPlusError::NodeError(format!("since {} is newer than lsn {}", since, self.lsn())).into());
+        }
+        Ok(self.run_backup(path, Some(since))?.0)
+    }
+
+    //run_backup 返回备份时的修改序号和每个页的序号
+    fn run_backup(&self, path: &str, since: Option<u64>) -> Result<(u64, Vec<u64>)> {
+        if self.store.is_transformed() {
+            return Err(BPlusError::NodeError("backup of a compressed or encrypted store is not supported".to_string()).into());
+        }
+        let tmp = format!("{}.backup", path);
+        let (len, lsn, pages) = {
+            let _guard = self.write_lock();
+            let mut backup = self.backup_state();
+            if backup.is_some() {
+                return Err(BPlusError::NodeError("backup already running".to_string()).into());
+            }
+            //正在进行的备份可能在写同一个临时文件, 检查后再打开
+            let file = OpenOptions::new().create(true).write(true).truncate(true).open(&tmp)?;
+            let len = self.store.len();
+            let count = (len / page_size() as u64) as usize;
+            let (lsn, mut pages) = {
+                let state = self.lsn.state();
+                (state.lsn, state.pages.clone())
+            };
+            pages.resize(count, lsn);
+            let copied = match since {
+                Some(since) => pages.iter().map(|page| *page <= since).collect(),
+                None => {
+                    file.set_len(len)?;
+                    vec![false; count]
+                }
+            };
+            *backup = Some(BackupState {
+                file,
+                len,
+                copied,
+                incremental: since.map(|_| 0),
+                error: None,
+            });
+            (len, lsn, pages)
+        };
+        let result = self.lsn.persist(lsn).and_then(|_| self.backup_pages(len));
+        let state = self.backup_state().take();
+        let state = state.ok_or_else(|| BPlusError::NodeError("backup state missing".to_string()))?;
+        let result = result.and_then(|_| match state.error {
+            Some(e) => Err(BPlusError::NodeError(format!("backup failed: {}", e)).into()),
+            None => Ok(()),
+        }).and_then(|_| {
+            if let (Some(since), Some(count)) = (since, state.incremental) {
+                let mut header = [0u8; INCREMENTAL_HEADER as usize];
+                header[0..8].copy_from_slice(&INCREMENTAL_MAGIC);
+                BigEndian::write_u64(&mut header[8..16], since);
+                BigEndian::write_u64(&mut header[16..24], lsn);
+                BigEndian::write_u64(&mut header[24..32], len);
+                BigEndian::write_u64(&mut header[32..40], count);
+                write_at(&state.file, &header, 0)?;
+            }
+            state.file.sync_all()?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        let _ = fs::remove_file(lsn_path(path));
+        fs::rename(&tmp, path)?;
+        sync_dir(path)?;
+        Ok((lsn, pages))
+    }
+
+    //backup_pages 按顺序复制还没有复制的页, 每步之间释放读锁
+    fn backup_pages(&self, len: u64) -> Result<()> {
+        let page = page_size() as u64;
+        let mut seek = 0;
+        while seek < len {
+            {
+                let _guard = self.read_lock();
+                let mut backup = self.backup_state();
+                let state = backup.as_mut().ok_or_else(|| BPlusError::NodeError("backup state missing".to_string()))?;
+                let end = (seek + BACKUP_STEP * page).min(len);
+                while seek < end {
+                    self.backup_copy(state, seek)?;
+                    seek += page;
+                }
+            }
+            //让等待的写操作先执行
+            thread::yield_now();
+        }
+        Ok(())
+    }
+
+    //backup_copy 页还没有复制时写入备份文件
+    fn backup_copy(&self, state: &mut BackupState, seek: u64) -> Result<()> {
+        let index = (seek / page_size() as u64) as usize;
+        if seek >= state.len || state.copied[index] {
+            return Ok(());
+        }
+        let mut data = vec![0u8; page_size()];
+        self.store.read_page(seek, &mut data)?;
+        match state.incremental.as_mut() {
+            None => write_at(&state.file, &data, seek)?,
+            Some(count) => {
+                let pos = INCREMENTAL_HEADER + *count * (8 + page_size() as u64);
+                write_at(&state.file, &seek.to_be_bytes(), pos)?;
+                write_at(&state.file, &data, pos + 8)?;
+                *count += 1;
+            }
+        }
+        state.copied[index] = true;
+        Ok(())
+    }
+
+    //backup_before_write 修改页之前调用, 持有写锁
+    pub(crate) fn backup_before_write(&self, seek: u64) {
+        let mut backup = self.backup_state();
+        if let Some(state) = backup.as_mut().filter(|s| s.error.is_none()) {
+            let page = seek - seek % page_size() as u64;
+            if let Err(e) = self.backup_copy(state, page) {
+                state.error = Some(e.to_string());
+            }
+        }
+    }
+
+    //backup_before_truncate 截断或替换存储之前复制剩下的所有页
+    pub(crate) fn backup_before_truncate(&self) {
+        let mut backup = self.backup_state();
+        if let Some(state) = backup.as_mut().filter(|s| s.error.is_none()) {
+            let page = page_size() as u64;
+            for seek in (0..state.len).step_by(page as usize) {
+                if let Err(e) = self.backup_copy(state, seek) {
+                    state.error = Some(e.to_string());
+                    return;
+                }
+            }
+        }
+    }
+
+    fn backup_state(&self) -> MutexGuard<'_, Option<BackupState>> {
+        self.backup.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+//read_at 从 pos 位置读满 buf
+fn read_at(mut file: &File, buf: &mut [u8], pos: u64) -> Result<()> {
+    file.seek(SeekFrom::Start(pos))?;
+    file.read_exact(buf)?;
+    Ok(())
+}
+
+//write_at 写入到 pos 位置
+fn write_at(mut file: &File, data: &[u8], pos: u64) -> Result<()> {
+    file.seek(SeekFrom::Start(pos))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::sync::Arc;
+    use std::thread;
+    use crate::temp_path;
+    use crate::node::node::page_size;
+    use crate::tree::backup::BackupState;
+    use crate::tree::lsn::{read_lsn_file, write_lsn_file};
+    use crate::tree::{Config, Tree};
+
+    #[test]
+    fn backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = Arc::new(Tree::<u64, u64>::open(&temp_path(&dir, "backup_src.db")).unwrap());
+        tree.bulk_load((0..500000u64).map(|i| (i * 2, i))).unwrap();
+        //备份期间从文件末尾往前插入奇数 key, 修改还没有复制的页, 中途整理文件
+        let inserted = |i: u64| (499999 - i * 7919 % 500000) * 2 + 1;
+        let writer = {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                for i in 0..200u64 {
+                    tree.insert(inserted(i), i).unwrap();
+                    if i == 5 {
+                        tree.compact().unwrap();
+                    }
+                }
+            })
+        };
+        tree.backup_to(&temp_path(&dir, "backup_dst.db")).unwrap();
+        writer.join().unwrap();
+        assert!(!fs::exists(temp_path(&dir, "backup_dst.db.backup")).unwrap());
+
+        //备份是某一时刻的状态: 插入的 key 是插入顺序的前缀
+        let backup = Tree::<u64, u64>::open(&temp_path(&dir, "backup_dst.db")).unwrap();
+        assert!(backup.verify().unwrap().is_ok());
+        let odd: Vec<u64> = backup.range(..).unwrap().map(|r| r.unwrap().0).filter(|k| k % 2 == 1).collect();
+        let mut expected: Vec<u64> = (0..odd.len() as u64).map(inserted).collect();
+        expected.sort();
+        assert_eq!(odd, expected);
+        assert_eq!(backup.range(..).unwrap().count(), 500000 + odd.len());
+        assert_eq!(tree.range(..).unwrap().count(), 500200);
+    }
+
+    #[test]
+    fn backup_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = Tree::<u64, u64>::open(&temp_path(&dir, "running_src.db")).unwrap();
+        tree.insert(1, 1).unwrap();
+        let tmp = temp_path(&dir, "running_dst.db.backup");
+        fs::write(&tmp, b"first backup").unwrap();
+        //模拟正在进行的备份
+        *tree.backup_state() = Some(BackupState {
+            file: File::open(&tmp).unwrap(),
+            len: 0,
+            copied: vec![],
+            incremental: None,
+            error: None,
+        });
+        assert!(tree.backup_to(&temp_path(&dir, "running_dst.db")).is_err());
+        assert_eq!(fs::read(&tmp).unwrap(), b"first backup");
+        tree.backup_state().take();
+        tree.backup_to(&temp_path(&dir, "running_dst.db")).unwrap();
+        assert_eq!(Tree::<u64, u64>::open(&temp_path(&dir, "running_dst.db")).unwrap().get(&1).unwrap(), Some(1));
+    }
+
+    #[cfg(feature = "encrypt")]
+    #[test]
+    fn backup_encrypted() {
+        use crate::store::{Cipher, EncryptedStore, StaticKeys};
+        use crate::ValueTest;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { track_changes: true, ..Default::default() };
+        let keys = Arc::new(StaticKeys::new(1, [3u8; 32]));
+        let tree = Tree::<u64, ValueTest, EncryptedStore>::open_encrypted_with_config(&temp_path(&dir, "encrypted_src.db"), keys, Cipher::Aes256Gcm, config).unwrap();
+        tree.insert(1, ValueTest { id: 1, data: "customer secret".repeat(10) }).unwrap();
+        assert!(tree.backup_to(&temp_path(&dir, "encrypted_dst.db")).is_err());
+        assert!(tree.backup_incremental(&temp_path(&dir, "encrypted_inc.db"), 0).is_err());
+        //目录中的任何文件都没有明文
+        for entry in fs::read_dir(dir.path()).unwrap() {
+            let data = fs::read(entry.unwrap().path()).unwrap();
+            assert!(!data.windows(15).any(|w| w == b"customer secret"));
+        }
+        assert!(!fs::exists(temp_path(&dir, "encrypted_dst.db")).unwrap());
+    }
+
+    #[test]
+    fn incremental_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { track_changes: true, ..Default::default() };
+        let tree = Tree::<u64, u64>::open_with_config(&temp_path(&dir, "inc_src.db"), config.clone()).unwrap();
+        tree.bulk_load((0..200000u64).map(|i| (i, i))).unwrap();
+        let full = tree.backup_to(&temp_path(&dir, "inc_full.db")).unwrap();
+        assert_eq!(full, tree.lsn());
+
+        //只修改少数页
+        for i in (0..200000u64).step_by(40000) {
+            tree.insert(i, 0).unwrap();
+        }
+        let first = tree.backup_incremental(&temp_path(&dir, "inc_1.db"), full).unwrap();
+        assert!(first > full);
+        assert!(fs::metadata(temp_path(&dir, "inc_1.db")).unwrap().len() * 20 < fs::metadata(temp_path(&dir, "inc_full.db")).unwrap().len());
+
+        //重新打开后继续记录, 文件变长
+        drop(tree);
+        let tree = Tree::<u64, u64>::open_with_config(&temp_path(&dir, "inc_src.db"), config.clone()).unwrap();
+        assert_eq!(tree.lsn(), first);
+        tree.remove(&5).unwrap();
+        for i in 200000..201000u64 {
+            tree.insert(i, i).unwrap();
+        }
+        let second = tree.backup_incremental(&temp_path(&dir, "inc_2.db"), first).unwrap();
+        let expected: Vec<(u64, u64)> = tree.range(..).unwrap().map(|r| r.unwrap()).collect();
+
+        let restored = Tree::<u64, u64>::restore(&temp_path(&dir, "inc_full.db"), &[&temp_path(&dir, "inc_1.db"), &temp_path(&dir, "inc_2.db")], &temp_path(&dir, "inc_restore.db"), config.clone()).unwrap();
+        assert!(restored.verify().unwrap().is_ok());
+        assert_eq!(restored.range(..).unwrap().map(|r| r.unwrap()).collect::<Vec<_>>(), expected);
+        assert_eq!(restored.lsn(), second);
+        drop(restored);
+        //缺少中间的增量备份
+        assert!(Tree::<u64, u64>::restore(&temp_path(&dir, "inc_full.db"), &[&temp_path(&dir, "inc_2.db")], &temp_path(&dir, "inc_restore.db"), config.clone()).is_err());
+
+        //没有正常关闭时所有页当作已修改
+        drop(tree);
+        let (clean, lsn, pages) = read_lsn_file(&temp_path(&dir, "inc_src.db.lsn")).unwrap().unwrap();
+        assert!(clean && lsn == second);
+        write_lsn_file(&temp_path(&dir, "inc_src.db.lsn"), false, lsn, &[]).unwrap();
+        let tree = Tree::<u64, u64>::open_with_config(&temp_path(&dir, "inc_src.db"), config).unwrap();
+        tree.backup_incremental(&temp_path(&dir, "inc_3.db"), second).unwrap();
+        let records = (fs::metadata(temp_path(&dir, "inc_3.db")).unwrap().len() - 40) / (8 + page_size() as u64);
+        assert_eq!(records, pages.len() as u64);
+        drop(tree);
+
+        let tree = Tree::<u64, u64>::open(&temp_path(&dir, "inc_src.db")).unwrap();
+        assert!(tree.backup_incremental(&temp_path(&dir, "inc_3.db"), second).is_err());
+        drop(tree);
+    }
+}
@@ -0,0 +1,421 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use anyhow::Result;
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::node::node::{page_size, BPlusError, ExtraData, Node, EXTRA_DATA, LEAF, MIDDLE_NODE, NEXT_OFFSET, PREV_OFFSET, VALID};
+use crate::store::PageStore;
+use crate::tree::Tree;
+
+/// 在线整理的进度
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DefragProgress {
+    // 移动的页数
+    pub moved: u64,
+    // 截断的字节数
+    pub truncated: u64,
+    // 本轮整理完成
+    pub done: bool,
+}
+
+impl DefragProgress {
+    fn add(&mut self, other: DefragProgress) {
+        self.moved += other.moved;
+        self.truncated += other.truncated;
+        self.done = other.done;
+    }
+}
+
+//两次整理之间保存的进度
+pub(crate) struct DefragState {
+    // 已经按顺序排好的叶子个数, 第 i 个叶子在第 i 页
+    ordered: u64,
+    // 叶子排序完成
+    leaf_done: bool,
+}
+
+/// 后台整理任务
+pub struct DefragHandle {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<Result<DefragProgress>>,
+}
+
+impl DefragHandle {
+    /// 停止后台整理 返回累计进度
+    pub fn stop(self) -> Result<DefragProgress> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.handle.join().map_err(|_| BPlusError::NodeError("defrag thread panicked".to_string()))?
+    }
+}
+
+impl<K, V, S> Tree<K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    /// 启动后台整理, 每次最多移动 pages_per_step 个页, 一轮完成后等待 interval 再开始下一轮
+    pub fn spawn_defrag(tree: Arc<Self>, pages_per_step: usize, interval: Duration) -> DefragHandle
+        where K: 'static, V: 'static, S: 'static
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut total = DefragProgress::default();
+            while !flag.load(Ordering::SeqCst) {
+                let progress = tree.defrag_step(pages_per_step)?;
+                total.add(progress);
+                if progress.done {
+                    thread::sleep(interval);
+                } else {
+                    thread::yield_now();
+                }
+            }
+            Ok(total)
+        });
+        DefragHandle {
+            stop,
+            handle,
+        }
+    }
+
+    /// 在线整理一步, 期间独占树结构, 两步之间读写可以正常进行
+    /// 先把叶子按 next 顺序移动到文件开头, 再把文件尾部的页移动到空闲页并截断文件
+    pub fn defrag_step(&self, max_moves: usize) -> Result<DefragProgress> {
+        self.check_writable()?;
+        let progress = {
+            let _guard = self.write_lock();
+            self.defrag_step_locked(max_moves)?
+        };
+        if progress.moved > 0 || progress.truncated > 0 {
+            self.commit()?;
+        }
+        Ok(progress)
+    }
+
+    fn defrag_step_locked(&self, max_moves: usize) -> Result<DefragProgress> {
+        let mut defrag = self.defrag.lock().unwrap_or_else(|e| e.into_inner());
+        if defrag.is_none() {
+            *defrag = Some(DefragState {
+                ordered: 0,
+                leaf_done: false,
+            });
+        }
+        let state = defrag.as_mut().ok_or_else(|| BPlusError::NodeError("defrag state".to_string()))?;
+        let page = page_size() as u64;
+        let max_moves = max_moves as u64;
+        let mut progress = DefragProgress::default();
+
+        //叶子按 next 顺序排列
+        while !state.leaf_done && progress.moved < max_moves {
+            let seek = match self.next_unordered_leaf(state)? {
+                Some(seek) => seek,
+                None => {
+                    state.leaf_done = true;
+                    break;
+                }
+            };
+            let target = (state.ordered + 1) * page;
+            if seek != target {
+                let target_free = self.free_pages().contains(&target) || self.read_flag(target)? & VALID != VALID;
+                if !target_free {
+                    //目标位置的页先移走
+                    let slot = self.free_pages().range(target + page..).next().copied();
+                    let slot = match slot {
+                        Some(slot) => {
+                            self.free_pages().remove(&slot);
+                            slot
+                        }
+                        None => self.store.allocate(page),
+                    };
+                    self.relocate(target, slot)?;
+                    progress.moved += 1;
+                    //剩余次数不够移动叶子, 下一步 target 已经空出
+                    if progress.moved >= max_moves {
+                        break;
+                    }
+                }
+                //target 马上被叶子使用, 不进入空闲列表
+                self.free_pages().remove(&target);
+                self.relocate(seek, target)?;
+                progress.moved += 1;
+            }
+            state.ordered += 1;
+        }
+
+        //尾部页移动到空闲位置 截断文件
+        let mut tail_done = false;
+        while state.leaf_done && progress.moved < max_moves {
+            let end = self.store.len();
+            if end <= page {
+                tail_done = true;
+                break;
+            }
+            let last = end - page;
+            if self.free_pages().remove(&last) || self.read_flag(last)? & VALID != VALID {
+                self.truncate(last)?;
+                progress.truncated += page;
+                continue;
+            }
+            let first = self.free_pages().first().copied();
+            let slot = match first {
+                Some(slot) if slot < last && (self.read_flag(last)? & LEAF) != LEAF => slot,
+                _ => {
+                    tail_done = true;
+                    break;
+                }
+            };
+            self.free_pages().remove(&slot);
+            self.relocate(last, slot)?;
+            progress.moved += 1;
+        }
+
+        if tail_done {
+            progress.done = true;
+            *defrag = None;
+        }
+        Ok(progress)
+    }
+
+    //next_unordered_leaf 已排好的最后一个叶子的下一个叶子
+    fn next_unordered_leaf(&self, state: &mut DefragState) -> Result<Option<u64>> {
+        let page = page_size() as u64;
+        if state.ordered > 0 {
+            let cursor = state.ordered * page;
+            let header = self.read_page(cursor)?;
+            let prev = u64::from_be_bytes(header[PREV_OFFSET as usize..PREV_OFFSET as usize + 8].try_into()?);
+            let next = u64::from_be_bytes(header[NEXT_OFFSET as usize..NEXT_OFFSET as usize + 8].try_into()?);
+            //cursor 仍是第 ordered 个叶子: prev 为上一个已排好的叶子, next 的 prev 指回 cursor
+            let linked = header[0] & (LEAF | VALID) == LEAF | VALID
+                && prev == (state.ordered - 1) * page
+                && (next == 0 || self.leaf_prev(next)? == Some(cursor));
+            if linked {
+                return Ok(if next == 0 { None } else { Some(next) });
+            }
+            //两步之间树被修改, 从头开始
+            state.ordered = 0;
+        }
+        let first = self.first_leaf()?;
+        Ok(if first.seek_start == 0 { None } else { Some(first.seek_start) })
+    }
+
+    //leaf_prev 叶子的 prev, 不是有效叶子时返回 None
+    fn leaf_prev(&self, seek: u64) -> Result<Option<u64>> {
+        let header = self.read_page(seek)?;
+        if header[0] & (LEAF | VALID) != LEAF | VALID {
+            return Ok(None);
+        }
+        Ok(Some(u64::from_be_bytes(header[PREV_OFFSET as usize..PREV_OFFSET as usize + 8].try_into()?)))
+    }
+
+    //relocate 页从 from 移动到 to, 修改父节点 key_seek 兄弟节点 prev next 以及额外数据页链表
+    fn relocate(&self, from: u64, to: u64) -> Result<()> {
+        let data = self.read_page(from)?;
+        let flag = data[0];
+        let prev = u64::from_be_bytes(data[PREV_OFFSET as usize..PREV_OFFSET as usize + 8].try_into()?);
+        let next = u64::from_be_bytes(data[NEXT_OFFSET as usize..NEXT_OFFSET as usize + 8].try_into()?);
+        if flag & (LEAF | MIDDLE_NODE) != 0 {
+            let key = self.first_key(from)?;
+            let (mut path, _) = self.search_path(&key)?;
+            let (parent, child) = path.iter_mut()
+                .find(|(n, i)| n.key_seek.as_ref().map(|s| s[*i]) == Some(from))
+                .ok_or_else(|| BPlusError::NodeError("parent not found".to_string()))?;
+            if let Some(key_seek) = parent.key_seek.as_mut() {
+                key_seek[*child] = to;
+            }
+            self.write_node(parent)?;
+            if flag & LEAF == LEAF {
+                if prev != 0 {
+                    self.set_next(prev, to)?;
+                }
+                if next != 0 {
+                    self.set_prev(next, to)?;
+                }
+            }
+        } else if flag & EXTRA_DATA == EXTRA_DATA {
+            if prev != 0 {
+                let mut prev_data = self.read_page(prev)?;
+                ExtraData::data_extra_set_next::<K>(&mut prev_data, to)?;
+                self.write_page(prev, &prev_data)?;
+            } else {
+                //链表第一页 修改所属叶子中的位置
+                let key = ExtraData::data_extra_key::<K>(&data)?;
+                let mut leaf = self.search_leaf(&key)?;
+                let index = leaf.key.as_ref()
+                    .and_then(|k| k.iter().position(|k| k.as_ref() == &key))
+                    .ok_or_else(|| BPlusError::NodeError("extra owner not found".to_string()))?;
+                if let Some(Some(extra)) = leaf.extra_data.as_mut().and_then(|e| e.get_mut(index)) {
+                    extra.seek = to;
+                }
+                self.write_node(&mut leaf)?;
+            }
+            if next != 0 {
+                self.set_prev(next, to)?;
+            }
+        } else {
+            return Err(BPlusError::NodeError(format!("can not relocate page {}", from)).into());
+        }
+        self.write_page(to, &data)?;
+        self.free_page(from)
+    }
+
+    //first_key 节点子树中最小的 key
+    fn first_key(&self, seek: u64) -> Result<K> {
+        let mut node: Node<K, V> = self.read_node(seek)?;
+        while !node.is_leaf() {
+            let child = node.key_seek.as_ref()
+                .and_then(|s| s.first().copied())
+                .ok_or_else(|| BPlusError::NodeError("not key seek".to_string()))?;
+            node = self.read_node(child)?;
+        }
+        node.key.as_ref()
+            .and_then(|k| k.first())
+            .map(|k| k.as_ref().clone())
+            .ok_or_else(|| BPlusError::NodeError("empty leaf".to_string()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::node::node::page_size;
+    use crate::store::{MemoryStore, PageStore};
+    use crate::tree::Tree;
+    use crate::ValueTest;
+
+    fn value(i: u64) -> ValueTest {
+        //部分数据带额外数据页
+        let len = if i.is_multiple_of(10) { 20000 } else { 100 };
+        ValueTest {
+            id: i as u32,
+            data: "d".repeat(len),
+        }
+    }
+
+    fn assert_leaf_ordered<S: PageStore>(tree: &Tree<u64, ValueTest, S>) {
+        let page = page_size() as u64;
+        let mut node = tree.first_leaf().unwrap();
+        assert_eq!(node.seek_start, page);
+        while node.next != 0 {
+            assert_eq!(node.next, node.seek_start + page);
+            node = tree.read_node(node.next).unwrap();
+        }
+    }
+
+    #[test]
+    fn defrag_step() {
+        let tree = Tree::<u64, ValueTest, MemoryStore>::in_memory().unwrap();
+        for i in 0..1500u64 {
+            let k = (i * 7919) % 1500;
+            tree.insert(k, value(k)).unwrap();
+        }
+        //一部分额外数据页变成空闲页
+        for k in (0..1500u64).filter(|k| k.is_multiple_of(20)) {
+            tree.insert(k, value(k + 1)).unwrap();
+        }
+        let before = tree.store.len();
+        let mut moved = 0;
+        loop {
+            let progress = tree.defrag_step(16).unwrap();
+            assert!(progress.moved <= 16);
+            moved += progress.moved;
+            if progress.done {
+                break;
+            }
+        }
+        assert!(moved > 0);
+        assert_leaf_ordered(&tree);
+        let report = tree.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        assert!(tree.store.len() < before);
+        for k in 0..1500u64 {
+            let v = if k.is_multiple_of(20) { value(k + 1) } else { value(k) };
+            assert_eq!(tree.get(&k).unwrap(), Some(v));
+        }
+        //整理完成后再次整理没有需要移动的页
+        assert_eq!(tree.defrag_step(16).unwrap().moved, 0);
+    }
+
+    #[test]
+    fn defrag_budget() {
+        for max_moves in [1, 3] {
+            let tree = Tree::<u64, ValueTest, MemoryStore>::in_memory().unwrap();
+            for i in 0..600u64 {
+                let k = (i * 7919) % 600;
+                tree.insert(k, value(k)).unwrap();
+            }
+            loop {
+                let progress = tree.defrag_step(max_moves).unwrap();
+                assert!(progress.moved <= max_moves as u64);
+                if progress.done {
+                    break;
+                }
+            }
+            assert_leaf_ordered(&tree);
+            assert!(tree.verify().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn defrag_restart() {
+        let tree = Tree::<u64, ValueTest, MemoryStore>::in_memory().unwrap();
+        for i in 0..1500u64 {
+            let k = (i * 7919) % 1500;
+            tree.insert(k, value(k)).unwrap();
+        }
+        tree.defrag_step(16).unwrap();
+        let mut defrag = tree.defrag.lock().unwrap();
+        let state = defrag.as_mut().unwrap();
+        assert!(state.ordered > 1);
+        let page = page_size() as u64;
+        let cursor = state.ordered * page;
+        let next = tree.read_node(cursor).unwrap().next;
+        assert_eq!(tree.next_unordered_leaf(state).unwrap(), Some(next));
+        assert!(state.ordered > 1);
+
+        //cursor 的 prev 不是上一个已排好的叶子
+        tree.set_prev(cursor, 0).unwrap();
+        assert_eq!(tree.next_unordered_leaf(state).unwrap(), Some(page));
+        assert_eq!(state.ordered, 0);
+        tree.set_prev(cursor, cursor - page).unwrap();
+
+        //next 的 prev 没有指回 cursor
+        state.ordered = cursor / page;
+        tree.set_prev(next, 0).unwrap();
+        assert_eq!(tree.next_unordered_leaf(state).unwrap(), Some(page));
+        assert_eq!(state.ordered, 0);
+        tree.set_prev(next, cursor).unwrap();
+        drop(defrag);
+
+        loop {
+            if tree.defrag_step(16).unwrap().done {
+                break;
+            }
+        }
+        assert_leaf_ordered(&tree);
+        assert!(tree.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn defrag_background() {
+        let tree = Arc::new(Tree::<u64, ValueTest, MemoryStore>::in_memory().unwrap());
+        for i in 0..600u64 {
+            let k = (i * 7919) % 1200;
+            tree.insert(k, value(k)).unwrap();
+        }
+        let handle = Tree::spawn_defrag(tree.clone(), 4, Duration::from_millis(1));
+        for i in 600..1200u64 {
+            let k = (i * 7919) % 1200;
+            tree.insert(k, value(k)).unwrap();
+            assert_eq!(tree.get(&k).unwrap(), Some(value(k)));
+        }
+        let progress = handle.stop().unwrap();
+        assert!(progress.moved > 0);
+        assert!(tree.verify().unwrap().is_ok());
+        for k in 0..1200u64 {
+            assert_eq!(tree.get(&k).unwrap(), Some(value(k)));
+        }
+    }
+}
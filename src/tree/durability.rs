@@ -0,0 +1,335 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::store::PageStore;
+use crate::tree::metrics::Metrics;
+use crate::tree::Tree;
+
+/// 修改提交后什么时候 fsync
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    // 不主动 fsync, 由操作系统决定什么时候写回
+    #[default]
+    None,
+    // 每次修改返回前 fsync
+    Sync,
+    // 并发的提交合并为一次 fsync, 还有其他修改在进行时第一个提交最多等待 max_delay 收集它们
+    GroupCommit { max_delay: Duration },
+    // 打开时启动后台线程每隔 interval fsync, 最多丢失 interval 内的修改, 后台 fsync 失败时下一次提交返回错误
+    Periodic { interval: Duration },
+}
+
+//Syncer 记录提交和已经持久化的位置
+#[derive(Default)]
+pub(crate) struct Syncer {
+    state: Mutex<SyncState>,
+    cond: Condvar,
+    // 等待或持有写锁的修改数, 还没有提交
+    writers: AtomicUsize,
+}
+
+#[derive(Default)]
+struct SyncState {
+    // 提交序号
+    committed: u64,
+    // 已经 fsync 的提交序号
+    synced: u64,
+    // 有线程正在 fsync
+    syncing: bool,
+    // 停止后台线程
+    stop: bool,
+    // 后台 fsync 的错误, 由下一次提交返回
+    error: Option<anyhow::Error>,
+}
+
+impl Syncer {
+    fn state(&self) -> MutexGuard<'_, SyncState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //begin_write 开始修改, 写锁释放时调用 end_write
+    pub(crate) fn begin_write(&self) {
+        self.writers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    //end_write 最后一个修改结束时唤醒等待收集提交的 leader, 修改失败没有提交时也不会让它等到 max_delay
+    pub(crate) fn end_write(&self) {
+        if self.writers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _state = self.state();
+            self.cond.notify_all();
+        }
+    }
+
+    //spawn_periodic 后台线程每隔 interval 把提交的修改 fsync, 没有新的提交时不 fsync, 停止前再 fsync 一次
+    pub(crate) fn spawn_periodic<S: PageStore>(syncer: Arc<Syncer>, store: Arc<S>, metrics: Arc<Metrics>, interval: Duration) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            let deadline = Instant::now() + interval;
+            let mut state = syncer.state();
+            loop {
+                let now = Instant::now();
+                if state.stop || now >= deadline {
+                    break;
+                }
+                state = syncer.cond.wait_timeout(state, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+            }
+            let (stop, target) = (state.stop, state.committed);
+            drop(state);
+            if let Err(e) = syncer.sync_to(&*store, &metrics, target) {
+                syncer.state().error.get_or_insert(e);
+            }
+            if stop {
+                return;
+            }
+        })
+    }
+
+    //stop_periodic 停止后台线程并等待它退出
+    pub(crate) fn stop_periodic(&self, handle: JoinHandle<()>) {
+        self.state().stop = true;
+        self.cond.notify_all();
+        let _ = handle.join();
+    }
+
+    //sync_to 保证 target 之前的提交都已经 fsync, 同一时间只有一个线程 fsync, 其他线程等待后检查是否已经包含
+    fn sync_to<S: PageStore>(&self, store: &S, metrics: &Metrics, target: u64) -> Result<()> {
+        let mut state = self.state();
+        loop {
+            if state.synced >= target {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.cond.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.syncing = true;
+        //这次 fsync 包含之前所有的提交
+        let covered = state.committed;
+        drop(state);
+        let result = store.sync();
+        let mut state = self.state();
+        state.syncing = false;
+        if result.is_ok() {
+            Metrics::add(&metrics.fsyncs, 1);
+            state.synced = state.synced.max(covered);
+        }
+        self.cond.notify_all();
+        result
+    }
+}
+
+impl<K, V, S> Tree<K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    //commit 修改完成后调用, 释放写锁之后按 durability 决定是否等待 fsync
+    pub(crate) fn commit(&self) -> Result<()> {
+        let target = {
+            let mut state = self.syncer.state();
+            state.committed += 1;
+            if let Some(e) = state.error.take() {
+                return Err(e);
+            }
+            state.committed
+        };
+        //等待收集提交的 leader 重新检查
+        self.syncer.cond.notify_all();
+        match self.config().durability {
+            Durability::None | Durability::Periodic { .. } => Ok(()),
+            Durability::Sync => self.sync_to(target),
+            Durability::GroupCommit { max_delay } => self.group_commit(target, max_delay),
+        }
+    }
+
+    //sync_all 持久化所有已经提交的修改
+    pub(crate) fn sync_all(&self) -> Result<()> {
+        let target = {
+            let mut state = self.syncer.state();
+            if let Some(e) = state.error.take() {
+                return Err(e);
+            }
+            state.committed
+        };
+        self.sync_to(target)?;
+        //没有提交时也 fsync, 保证 flush 的语义
+        if target == 0 {
+            self.store.sync()?;
+            Metrics::add(&self.metrics.fsyncs, 1);
+        }
+        Ok(())
+    }
+
+    //mark_synced compact 等已经 fsync 的操作之后调用
+    pub(crate) fn mark_synced(&self) {
+        let mut state = self.syncer.state();
+        state.synced = state.committed;
+    }
+
+    //group_commit 第一个提交成为 leader, 还有其他修改在进行时最多等待 max_delay, 然后一次 fsync 所有提交, 其他提交等待结果
+    //只有一个写入者时不等待
+    fn group_commit(&self, target: u64, max_delay: Duration) -> Result<()> {
+        let mut state = self.syncer.state();
+        if !state.syncing && state.synced < target {
+            state.syncing = true;
+            let deadline = Instant::now() + max_delay;
+            //等待期间其他提交只增加 committed, 提交时唤醒 leader 检查
+            loop {
+                let now = Instant::now();
+                if now >= deadline || self.syncer.writers.load(Ordering::SeqCst) == 0 {
+                    break;
+                }
+                state = self.syncer.cond.wait_timeout(state, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+            }
+            state.syncing = false;
+            drop(state);
+            return self.sync_to(target);
+        }
+        drop(state);
+        self.sync_to(target)
+    }
+
+    fn sync_to(&self, target: u64) -> Result<()> {
+        self.syncer.sync_to(&*self.store, &self.metrics, target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use anyhow::{anyhow, Result};
+    use crate::temp_path;
+    use crate::store::{MemoryStore, PageStore};
+    use crate::tree::{Config, Durability, Tree};
+
+    fn open(path: &str, durability: Durability) -> Tree<u64, u64> {
+        Tree::<u64, u64>::open_with_config(path, Config { durability, ..Default::default() }).unwrap()
+    }
+
+    //wait_synced 等待后台线程 fsync 所有提交或者失败
+    fn wait_synced<S: PageStore>(tree: &Tree<u64, u64, S>) {
+        let mut state = tree.syncer.state();
+        while state.synced < state.committed && state.error.is_none() {
+            state = tree.syncer.cond.wait(state).unwrap();
+        }
+    }
+
+    //Failing fsync 总是失败的存储
+    struct Failing(MemoryStore);
+
+    impl PageStore for Failing {
+        fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()> {
+            self.0.read_page(seek, buf)
+        }
+
+        fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+            self.0.write_page(seek, data)
+        }
+
+        fn allocate(&self, page_size: u64) -> u64 {
+            self.0.allocate(page_size)
+        }
+
+        fn sync(&self) -> Result<()> {
+            Err(anyhow!("sync failed"))
+        }
+
+        fn len(&self) -> u64 {
+            self.0.len()
+        }
+
+        fn truncate(&self, len: u64) -> Result<()> {
+            self.0.truncate(len)
+        }
+
+        fn scratch(&self) -> Result<Self> {
+            Ok(Failing(self.0.scratch()?))
+        }
+
+        fn replace(&self, other: Self) -> Result<u64> {
+            self.0.replace(other.0)
+        }
+    }
+
+    #[test]
+    fn durability() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = open(&temp_path(&dir, "durability_none.db"), Durability::None);
+        for i in 0..10 {
+            tree.insert(i, i).unwrap();
+        }
+        assert_eq!(tree.metrics().fsyncs, 0);
+        tree.flush().unwrap();
+        assert_eq!(tree.metrics().fsyncs, 1);
+
+        let tree = open(&temp_path(&dir, "durability_sync.db"), Durability::Sync);
+        for i in 0..10 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.remove(&3).unwrap();
+        assert_eq!(tree.metrics().fsyncs, 11);
+        //没有新的提交 flush 不需要再 fsync
+        tree.flush().unwrap();
+        assert_eq!(tree.metrics().fsyncs, 11);
+    }
+
+    #[test]
+    fn periodic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = temp_path(&dir, "durability_periodic.db");
+        let tree = open(&path, Durability::Periodic { interval: Duration::from_millis(1) });
+        for i in 0..10 {
+            tree.insert(i, i).unwrap();
+        }
+        //提交不等待 fsync, 由打开时启动的后台线程完成
+        wait_synced(&tree);
+        assert!(tree.metrics().fsyncs >= 1);
+        //关闭时停止后台线程并 fsync 剩余的提交
+        tree.insert(10, 10).unwrap();
+        drop(tree);
+        let tree = open(&path, Durability::None);
+        assert_eq!(tree.get(&10).unwrap(), Some(10));
+
+        //后台 fsync 失败时下一次提交返回错误
+        let store = Failing(MemoryStore::new());
+        let config = Config { durability: Durability::Periodic { interval: Duration::from_millis(1) }, ..Default::default() };
+        let tree = Tree::<u64, u64, Failing>::with_store(store, config).unwrap();
+        tree.insert(1, 1).unwrap();
+        wait_synced(&tree);
+        assert!(tree.insert(2, 2).is_err());
+    }
+
+    #[test]
+    fn group_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        //只有一个写入者时不等待 max_delay
+        let tree = open(&temp_path(&dir, "group_commit_single.db"), Durability::GroupCommit { max_delay: Duration::from_secs(3600) });
+        for i in 0..10u64 {
+            tree.insert(i, i).unwrap();
+        }
+        assert_eq!(tree.metrics().fsyncs, 10);
+
+        let tree = Arc::new(open(&temp_path(&dir, "group_commit.db"), Durability::GroupCommit { max_delay: Duration::from_secs(3600) }));
+        let threads: Vec<_> = (0..8u64).map(|t| {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                for i in 0..10u64 {
+                    tree.insert(t * 100 + i, i).unwrap();
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        //80 次提交至少有一部分合并
+        let fsyncs = tree.metrics().fsyncs;
+        assert!(fsyncs > 0 && fsyncs <= 80, "{}", fsyncs);
+        assert_eq!(tree.range(..).unwrap().count(), 80);
+    }
+}
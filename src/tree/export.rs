@@ -0,0 +1,283 @@
+use std::fmt::{Debug, Display};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::RangeBounds;
+use std::str::FromStr;
+use anyhow::Result;
+use serde_json::{Map, Value};
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::node::node::BPlusError;
+use crate::store::PageStore;
+use crate::tree::Tree;
+
+/// 数据导出导入的文件格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    // 每行一个 {"key": .., "value": ..}
+    JsonLines,
+    // 第一行是 key,value 表头, 非字符串的值写入 json 文本
+    Csv,
+}
+
+/// key 和 value 与 json 值之间的转换, 导出导入时由调用方提供
+/// CSV 导入时字段总是以字符串传入, 实现需要能从字符串解析
+pub trait EntryFormat<K, V> {
+    fn key_to_json(&self, key: &K) -> Result<Value>;
+    fn value_to_json(&self, value: &V) -> Result<Value>;
+    fn key_from_json(&self, json: Value) -> Result<K>;
+    fn value_from_json(&self, json: Value) -> Result<V>;
+}
+
+/// 通过 Display 和 FromStr 转换, 导出为字符串
+pub struct DisplayFormat;
+
+impl DisplayFormat {
+    fn parse<T: FromStr>(json: Value) -> Result<T> {
+        let text = match json {
+            Value::String(s) => s,
+            other => other.to_string(),
+        };
+        text.parse().map_err(|_| BPlusError::NodeError(format!("cannot parse {:?}", text)).into())
+    }
+}
+
+impl<K: Display + FromStr, V: Display + FromStr> EntryFormat<K, V> for DisplayFormat {
+    fn key_to_json(&self, key: &K) -> Result<Value> {
+        Ok(Value::String(key.to_string()))
+    }
+
+    fn value_to_json(&self, value: &V) -> Result<Value> {
+        Ok(Value::String(value.to_string()))
+    }
+
+    fn key_from_json(&self, json: Value) -> Result<K> {
+        Self::parse(json)
+    }
+
+    fn value_from_json(&self, json: Value) -> Result<V> {
+        Self::parse(json)
+    }
+}
+
+/// 通过 serde 转换, 导出为对应的 json 结构
+#[cfg(feature = "serde")]
+pub struct SerdeFormat;
+
+#[cfg(feature = "serde")]
+impl SerdeFormat {
+    //from_json CSV 中的字符串先按字符串解析, 失败时当作 json 文本
+    fn from_json<T: serde::de::DeserializeOwned>(json: Value) -> Result<T> {
+        match json {
+            Value::String(s) => serde_json::from_value(Value::String(s.clone())).or_else(|_| serde_json::from_str(&s)).map_err(Into::into),
+            other => Ok(serde_json::from_value(other)?),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, V> EntryFormat<K, V> for SerdeFormat where
+    K: serde::Serialize + serde::de::DeserializeOwned,
+    V: serde::Serialize + serde::de::DeserializeOwned
+{
+    fn key_to_json(&self, key: &K) -> Result<Value> {
+        Ok(serde_json::to_value(key)?)
+    }
+
+    fn value_to_json(&self, value: &V) -> Result<Value> {
+        Ok(serde_json::to_value(value)?)
+    }
+
+    fn key_from_json(&self, json: Value) -> Result<K> {
+        Self::from_json(json)
+    }
+
+    fn value_from_json(&self, json: Value) -> Result<V> {
+        Self::from_json(json)
+    }
+}
+
+//csv_field 字符串原样写入, 其他值写入 json 文本
+fn csv_field(json: Value) -> String {
+    match json {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+impl<K, V, S> Tree<K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    /// 按 key 顺序把 range 内的数据导出到 writer, 返回导出的条数
+    pub fn export_data<R: RangeBounds<K>>(&self, range: R, format: DataFormat, entry: &impl EntryFormat<K, V>, writer: impl Write) -> Result<u64> {
+        let mut count = 0;
+        match format {
+            DataFormat::JsonLines => {
+                let mut writer = writer;
+                for item in self.range(range)? {
+                    let (k, v) = item?;
+                    let mut line = Map::new();
+                    line.insert("key".to_string(), entry.key_to_json(&k)?);
+                    line.insert("value".to_string(), entry.value_to_json(&v)?);
+                    serde_json::to_writer(&mut writer, &line)?;
+                    writer.write_all(b"\n")?;
+                    count += 1;
+                }
+                writer.flush()?;
+            }
+            DataFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                writer.write_record(["key", "value"])?;
+                for item in self.range(range)? {
+                    let (k, v) = item?;
+                    writer.write_record([csv_field(entry.key_to_json(&k)?), csv_field(entry.value_to_json(&v)?)])?;
+                    count += 1;
+                }
+                writer.flush()?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// 从 reader 导入 export_data 导出的数据, 返回导入的条数
+    /// 空树时按 key 递增的开头部分使用 bulk_load 构建, 从第一个乱序的数据开始逐条插入, 已有的 key 被覆盖
+    /// 出错时已经导入的数据保留
+    pub fn import_data(&self, format: DataFormat, entry: &impl EntryFormat<K, V>, reader: impl Read) -> Result<u64> {
+        let mut entries: Box<dyn Iterator<Item = Result<(K, V)>> + '_> = match format {
+            DataFormat::JsonLines => Box::new(BufReader::new(reader).lines().enumerate().filter(|(_, line)| {
+                !matches!(line, Ok(line) if line.trim().is_empty())
+            }).map(|(i, line)| {
+                let mut json: Value = serde_json::from_str(&line?)?;
+                let mut field = |name: &str| json.get_mut(name).map(Value::take)
+                    .ok_or_else(|| BPlusError::NodeError(format!("line {}: missing {}", i + 1, name)));
+                let (k, v) = (field("key")?, field("value")?);
+                Ok((entry.key_from_json(k)?, entry.value_from_json(v)?))
+            })),
+            DataFormat::Csv => Box::new(csv::Reader::from_reader(reader).into_records().map(|record| {
+                let record = record?;
+                let field = |i: usize| record.get(i).map(|s| Value::String(s.to_string()))
+                    .ok_or_else(|| BPlusError::NodeError(format!("csv record {:?} needs key and value", record.position())));
+                Ok((entry.key_from_json(field(0)?)?, entry.value_from_json(field(1)?)?))
+            })),
+        };
+        let mut count = 0;
+        if self.is_empty()? {
+            let mut sorted = Sorted { entries: &mut entries, last: None, rest: None };
+            count = self.bulk_load(&mut sorted)?;
+            match sorted.rest.take() {
+                Some(Err(e)) => return Err(e),
+                Some(Ok((k, v))) => {
+                    self.insert(k, v)?;
+                    count += 1;
+                }
+                None => {}
+            }
+        }
+        for item in entries {
+            let (k, v) = item?;
+            self.insert(k, v)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    //is_empty root 是没有数据的叶子
+    fn is_empty(&self) -> Result<bool> {
+        let _guard = self.read_lock();
+        let root = self.read_node(0)?;
+        Ok(root.is_leaf() && root.key_count == 0)
+    }
+}
+
+//Sorted 按 key 递增的开头部分, 遇到乱序的数据或错误时结束并保存在 rest
+struct Sorted<'a, I: ?Sized, K, V> {
+    entries: &'a mut I,
+    last: Option<K>,
+    rest: Option<Result<(K, V)>>,
+}
+
+impl<I: Iterator<Item = Result<(K, V)>> + ?Sized, K: PartialOrd + Clone, V> Iterator for Sorted<'_, I, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        if self.rest.is_some() {
+            return None;
+        }
+        match self.entries.next()? {
+            Ok((k, v)) if self.last.as_ref().is_none_or(|last| last < &k) => {
+                self.last = Some(k.clone());
+                Some((k, v))
+            }
+            other => {
+                self.rest = Some(other);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::MemoryStore;
+    use crate::tree::{DataFormat, DisplayFormat, Operation, Tree};
+
+    #[test]
+    fn export_import() {
+        let tree = Tree::<u64, String, MemoryStore>::in_memory().unwrap();
+        tree.bulk_load((0..3000u64).map(|i| (i, format!("v,\"{}\"\n", i)))).unwrap();
+        for format in [DataFormat::JsonLines, DataFormat::Csv] {
+            let mut out = vec![];
+            assert_eq!(tree.export_data(100..200, format, &DisplayFormat, &mut out).unwrap(), 100);
+            let mut all = vec![];
+            assert_eq!(tree.export_data(.., format, &DisplayFormat, &mut all).unwrap(), 3000);
+
+            //有序输入走 bulk_load
+            let dst = Tree::<u64, String, MemoryStore>::in_memory().unwrap();
+            assert_eq!(dst.import_data(format, &DisplayFormat, all.as_slice()).unwrap(), 3000);
+            assert_eq!(dst.metrics().latency(Operation::Insert).count, 0);
+            assert_eq!(dst.range(..).unwrap().map(|r| r.unwrap()).collect::<Vec<_>>(), tree.range(..).unwrap().map(|r| r.unwrap()).collect::<Vec<_>>());
+            //已有数据时逐条插入
+            assert_eq!(dst.import_data(format, &DisplayFormat, out.as_slice()).unwrap(), 100);
+            assert_eq!(dst.range(..).unwrap().count(), 3000);
+            drop(dst);
+        }
+
+        let mut out = vec![];
+        tree.export_data(1..3, DataFormat::JsonLines, &DisplayFormat, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "{\"key\":\"1\",\"value\":\"v,\\\"1\\\"\\n\"}\n{\"key\":\"2\",\"value\":\"v,\\\"2\\\"\\n\"}\n");
+
+        //乱序的部分逐条插入
+        let dst = Tree::<u64, String, MemoryStore>::in_memory().unwrap();
+        let csv = "key,value\n1,a\n5,b\n3,c\n9,d\n5,e\n";
+        assert_eq!(dst.import_data(DataFormat::Csv, &DisplayFormat, csv.as_bytes()).unwrap(), 5);
+        assert_eq!(dst.range(..).unwrap().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            vec![(1, "a".to_string()), (3, "c".to_string()), (5, "e".to_string()), (9, "d".to_string())]);
+        assert_eq!(dst.metrics().latency(Operation::Insert).count, 3);
+        //格式错误
+        assert!(dst.import_data(DataFormat::JsonLines, &DisplayFormat, "{\"key\":\"x\",\"value\":\"1\"}\n".as_bytes()).is_err());
+        assert!(dst.import_data(DataFormat::JsonLines, &DisplayFormat, "{\"key\":\"1\"}\n".as_bytes()).is_err());
+        drop(dst);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn export_serde() {
+        use crate::tree::SerdeFormat;
+        use crate::ValueTest;
+
+        let tree = Tree::<u64, ValueTest, _>::in_memory().unwrap();
+        for i in 0..100u64 {
+            tree.insert(i, ValueTest { id: i as u32, data: format!("d{}", i) }).unwrap();
+        }
+        let mut out = vec![];
+        tree.export_data(..1, DataFormat::JsonLines, &SerdeFormat, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "{\"key\":0,\"value\":{\"data\":\"d0\",\"id\":0}}\n");
+        for format in [DataFormat::JsonLines, DataFormat::Csv] {
+            let mut out = vec![];
+            tree.export_data(.., format, &SerdeFormat, &mut out).unwrap();
+            let dst = Tree::<u64, ValueTest, _>::in_memory().unwrap();
+            assert_eq!(dst.import_data(format, &SerdeFormat, out.as_slice()).unwrap(), 100);
+            assert_eq!(dst.get(&42).unwrap(), tree.get(&42).unwrap());
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt::Debug;
+use anyhow::Result;
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::node::node::{page_size, BPlusError, ExtraData, Node, EXTRA_DATA, LEAF, MIDDLE_NODE, NODE_FIXED_SIZE, ROOT, VALID};
+use crate::store::PageStore;
+use crate::tree::Tree;
+
+/// 一个页的解析结果, 用于调试和查看文件
+#[derive(Debug, Clone)]
+pub struct PageInfo<K> {
+    pub seek: u64,
+    pub flag: u8,
+    pub key_count: u64,
+    pub data_count: u64,
+    pub residual_storage_size: u64,
+    pub prev: u64,
+    pub next: u64,
+    // 额外数据页为所属数据的 key
+    pub keys: Vec<K>,
+    // 中间节点的子节点位置
+    pub key_seek: Vec<u64>,
+    // 叶子每条数据的额外数据页位置
+    pub extra: Vec<Option<u64>>,
+    // 额外数据页 (本页及之后数据长度, 本页数据长度)
+    pub extra_length: Option<(u64, u64)>,
+}
+
+impl<K> PageInfo<K> {
+    /// flag 各位的名字
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        if self.flag & VALID != VALID {
+            return vec!["INVALID"];
+        }
+        [(VALID, "VALID"), (ROOT, "ROOT"), (MIDDLE_NODE, "MIDDLE_NODE"), (LEAF, "LEAF"), (EXTRA_DATA, "EXTRA_DATA")]
+            .iter()
+            .filter(|(bit, _)| self.flag & bit == *bit)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl<K, V, S> Tree<K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    /// 解析 seek 位置的页头和 key, 不解析数据
+    pub fn inspect_page(&self, seek: u64) -> Result<PageInfo<K>> {
+        let _guard = self.read_lock();
+        self.page_info(seek)
+    }
+
+    pub(crate) fn page_info(&self, seek: u64) -> Result<PageInfo<K>> {
+        if !seek.is_multiple_of(page_size() as u64) || seek >= self.file_size() {
+            return Err(BPlusError::NodeError(format!("page {} out of file", seek)).into());
+        }
+        let page = self.read_page(seek)?;
+        Node::<K, V>::check_page(&page).map_err(BPlusError::NodeError)?;
+        let node = Node::<K, V>::new_node_header(seek, &page)?;
+        let mut info = PageInfo {
+            seek,
+            flag: node.flag,
+            key_count: node.key_count,
+            data_count: node.data_count,
+            residual_storage_size: node.residual_storage_size,
+            prev: node.prev,
+            next: node.next,
+            keys: node.key.iter().flatten().map(|k| k.as_ref().clone()).collect(),
+            key_seek: node.key_seek.clone().unwrap_or_default(),
+            extra: node.extra_data.iter().flatten().map(|e| e.as_ref().map(|e| e.seek)).collect(),
+            extra_length: None,
+        };
+        if node.flag & (VALID | EXTRA_DATA) == VALID | EXTRA_DATA && page.len() > NODE_FIXED_SIZE {
+            info.keys = vec![ExtraData::data_extra_key::<K>(&page)?];
+            info.extra_length = Some(ExtraData::data_extra_length(&page)?);
+        }
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::MemoryStore;
+    use crate::tree::Tree;
+    use crate::ValueTest;
+
+    #[test]
+    fn inspect_page() {
+        let tree = Tree::<u64, ValueTest, MemoryStore>::in_memory().unwrap();
+        tree.insert(3, ValueTest { id: 3, data: "i".repeat(20000) }).unwrap();
+        tree.insert(1, ValueTest { id: 1, data: "i".to_string() }).unwrap();
+        let root = tree.inspect_page(0).unwrap();
+        assert_eq!(root.flag_names(), vec!["VALID", "ROOT", "LEAF"]);
+        assert_eq!(root.keys, vec![1, 3]);
+        assert_eq!(root.extra[0], None);
+        let extra = tree.inspect_page(root.extra[1].unwrap()).unwrap();
+        assert_eq!(extra.flag_names(), vec!["VALID", "EXTRA_DATA"]);
+        assert_eq!(extra.keys, vec![3]);
+        assert!(extra.extra_length.unwrap().0 > extra.extra_length.unwrap().1);
+        assert!(tree.inspect_page(1).is_err());
+        assert!(tree.inspect_page(tree.file_size()).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::sync::{Mutex, MutexGuard};
+use anyhow::Result;
+use byteorder::{BigEndian, ByteOrder};
+use crate::node::node::{page_size, BPlusError};
+use crate::store::sync_dir;
+
+//.lsn 文件: magic(8) clean(1) lsn(8) count(8) 后面 count 个页的修改序号
+const MAGIC: [u8; 8] = *b"BPTLSN01";
+const HEADER: usize = 25;
+
+//LsnTracker 每个页最后一次修改的序号, 保存在树文件旁边的 .lsn 文件中
+//打开后文件标记为未正常关闭, 关闭时写入所有页的序号, 崩溃后重新打开时所有页当作已修改
+pub(crate) struct LsnTracker {
+    // 内存存储、只读打开和没有开启时为 None, 不写文件
+    path: Option<String>,
+    enabled: bool,
+    state: Mutex<LsnState>,
+}
+
+pub(crate) struct LsnState {
+    pub(crate) lsn: u64,
+    pub(crate) pages: Vec<u64>,
+}
+
+impl LsnTracker {
+    //open 读取 location 对应的 .lsn 文件, len 是树文件的长度
+    //没有开启时只在内存中记录, 已有的 .lsn 文件标记为未正常关闭, 之后再开启时所有页当作已修改
+    pub(crate) fn open(location: Option<&str>, len: u64, writable: bool, enabled: bool) -> Result<Self> {
+        let count = (len / page_size() as u64) as usize;
+        let path = location.map(lsn_path);
+        let saved = match &path {
+            Some(path) => read_lsn_file(path)?,
+            None => None,
+        };
+        if !enabled {
+            if let (Some(path), Some((_, lsn, _)), true) = (&path, &saved, writable) {
+                write_lsn_file(path, false, *lsn, &[])?;
+            }
+            return Ok(LsnTracker {
+                path: None,
+                enabled,
+                state: Mutex::new(LsnState { lsn: 0, pages: vec![] }),
+            });
+        }
+        let state = match saved {
+            Some((true, lsn, pages)) if pages.len() == count => LsnState { lsn, pages },
+            //上次没有正常关闭或者和树文件不一致
+            Some((_, lsn, _)) => LsnState { lsn: lsn + 1, pages: vec![lsn + 1; count] },
+            None if count == 0 => LsnState { lsn: 0, pages: vec![] },
+            //没有记录的已有文件, 所有页的序号为 1
+            None => LsnState { lsn: 1, pages: vec![1; count] },
+        };
+        let path = path.filter(|_| writable);
+        if let Some(path) = &path {
+            write_lsn_file(path, false, state.lsn, &[])?;
+        }
+        Ok(LsnTracker {
+            path,
+            enabled,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn state(&self) -> MutexGuard<'_, LsnState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //stamp 页被修改, 分配后还没有写入的页使用同一个序号
+    pub(crate) fn stamp(&self, seek: u64) {
+        if !self.enabled {
+            return;
+        }
+        let mut state = self.state();
+        state.lsn += 1;
+        let lsn = state.lsn;
+        let index = (seek / page_size() as u64) as usize;
+        if state.pages.len() <= index {
+            state.pages.resize(index + 1, lsn);
+        }
+        state.pages[index] = lsn;
+    }
+
+    pub(crate) fn truncate(&self, len: u64) {
+        if !self.enabled {
+            return;
+        }
+        self.state().pages.truncate(len.div_ceil(page_size() as u64) as usize);
+    }
+
+    //reset compact 替换文件后所有页都已修改
+    pub(crate) fn reset(&self, len: u64) {
+        if !self.enabled {
+            return;
+        }
+        let mut state = self.state();
+        state.lsn += 1;
+        state.pages = vec![state.lsn; (len / page_size() as u64) as usize];
+    }
+
+    //persist 把交给备份的序号持久化, 崩溃后新的序号一定更大
+    pub(crate) fn persist(&self, lsn: u64) -> Result<()> {
+        match &self.path {
+            Some(path) => write_lsn_file(path, false, lsn, &[]),
+            None => Ok(()),
+        }
+    }
+
+    //close 关闭树时调用, sync 把数据写入磁盘后才标记为正常关闭
+    //sync 失败时保持未正常关闭, 下次打开所有页当作已修改
+    pub(crate) fn close(&mut self, sync: impl FnOnce() -> Result<()>) {
+        if let Some(path) = self.path.take() {
+            if sync().is_ok() {
+                let state = self.state();
+                let _ = write_lsn_file(&path, true, state.lsn, &state.pages);
+            }
+        }
+    }
+}
+
+pub(crate) fn lsn_path(path: &str) -> String {
+    format!("{}.lsn", path)
+}
+
+//read_lsn_file 返回 (正常关闭, lsn, 页序号), 文件不存在时返回 None
+pub(crate) fn read_lsn_file(path: &str) -> Result<Option<(bool, u64, Vec<u64>)>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if data.len() < HEADER || data[0..8] != MAGIC {
+        return Err(BPlusError::NodeError(format!("invalid lsn file {}", path)).into());
+    }
+    let lsn = BigEndian::read_u64(&data[9..17]);
+    let count = BigEndian::read_u64(&data[17..25]) as usize;
+    let pages: Vec<u64> = data[HEADER..].chunks_exact(8).take(count).map(BigEndian::read_u64).collect();
+    //写了一半的文件不完整, 当作没有正常关闭
+    let clean = data[8] == 1 && pages.len() == count;
+    Ok(Some((clean, lsn, pages)))
+}
+
+//write_lsn_file 写入临时文件后替换, 任何时候都有一个完整的文件
+pub(crate) fn write_lsn_file(path: &str, clean: bool, lsn: u64, pages: &[u64]) -> Result<()> {
+    let mut data = vec![0u8; HEADER + pages.len() * 8];
+    data[0..8].copy_from_slice(&MAGIC);
+    data[8] = clean as u8;
+    BigEndian::write_u64(&mut data[9..17], lsn);
+    BigEndian::write_u64(&mut data[17..25], pages.len() as u64);
+    for (i, page) in pages.iter().enumerate() {
+        BigEndian::write_u64(&mut data[HEADER + i * 8..HEADER + i * 8 + 8], *page);
+    }
+    let tmp = format!("{}.tmp", path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::node::{page_size, BPlusError};
+    use crate::temp_path;
+    use super::{lsn_path, read_lsn_file, LsnTracker};
+
+    #[test]
+    fn lsn_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = temp_path(&dir, "lsn.db");
+        let len = 2 * page_size() as u64;
+        let mut tracker = LsnTracker::open(Some(&path), len, true, true).unwrap();
+        tracker.stamp(page_size() as u64);
+        //数据没有写入磁盘时不能标记为正常关闭
+        tracker.close(|| Err(BPlusError::NodeError("sync failed".to_string()).into()));
+        let (clean, lsn, _) = read_lsn_file(&lsn_path(&path)).unwrap().unwrap();
+        assert!(!clean);
+
+        let mut tracker = LsnTracker::open(Some(&path), len, true, true).unwrap();
+        assert_eq!(tracker.state().pages, vec![lsn + 1; 2]);
+        tracker.close(|| Ok(()));
+        assert_eq!(read_lsn_file(&lsn_path(&path)).unwrap().unwrap(), (true, lsn + 1, vec![lsn + 1; 2]));
+    }
+}
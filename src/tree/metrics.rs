@@ -0,0 +1,259 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// 树结构变化的回调, 用于接入自己的监控
+/// 回调在持有树的锁时调用, 不能再调用树的方法
+pub trait TreeObserver: Send + Sync {
+    /// 节点拆分, right 为新节点
+    fn on_split(&self, _left: u64, _right: u64) {}
+    /// 叶子合并, right 并入 left 后被释放
+    fn on_merge(&self, _left: u64, _right: u64) {}
+    /// 页从缓存中淘汰
+    fn on_evict(&self, _seek: u64) {}
+}
+
+/// 统计耗时的操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Get = 0,
+    Insert = 1,
+    Remove = 2,
+    Range = 3,
+}
+
+const OPERATION_COUNT: usize = 4;
+//耗时分档 第 0 档小于 1 微秒, 第 i 档为 [2^(i-1), 2^i) 微秒, 最后一档包含更大的耗时
+const LATENCY_BUCKETS: usize = 24;
+
+/// 耗时分布
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub count: u64,
+    pub total_micros: u64,
+    pub buckets: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    /// 平均耗时
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_micros(self.total_micros / self.count)
+    }
+
+    /// 分位数耗时的上界, q 取值 [0, 1]
+    pub fn quantile(&self, q: f64) -> Duration {
+        let target = (self.count as f64 * q.clamp(0.0, 1.0)).ceil() as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target && seen > 0 {
+                return Duration::from_micros(1 << i);
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// 运行统计的快照, 由 Tree::metrics 返回
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeMetrics {
+    // 从文件读取的页数, 不包含缓存命中
+    pub page_reads: u64,
+    pub page_writes: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    // 范围遍历和 get_many 批量预读的页数, 包含在 page_reads 中
+    pub prefetched_pages: u64,
+    pub splits: u64,
+    pub merges: u64,
+    // 分配的额外数据页数
+    pub overflow_allocations: u64,
+    // 调用存储 sync 和替换文件时同步目录的次数
+    pub fsyncs: u64,
+    latency: Vec<LatencyHistogram>,
+}
+
+impl TreeMetrics {
+    pub fn latency(&self, op: Operation) -> &LatencyHistogram {
+        &self.latency[op as usize]
+    }
+}
+
+//Metrics 树内部的计数器
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) page_reads: AtomicU64,
+    pub(crate) page_writes: AtomicU64,
+    pub(crate) cache_hits: AtomicU64,
+    pub(crate) cache_misses: AtomicU64,
+    pub(crate) prefetched_pages: AtomicU64,
+    pub(crate) splits: AtomicU64,
+    pub(crate) merges: AtomicU64,
+    pub(crate) overflow_allocations: AtomicU64,
+    pub(crate) fsyncs: AtomicU64,
+    latency: [Latency; OPERATION_COUNT],
+}
+
+#[derive(Default)]
+struct Latency {
+    count: AtomicU64,
+    total_micros: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl Metrics {
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record(&self, op: Operation, elapsed: Duration) {
+        let latency = &self.latency[op as usize];
+        let micros = elapsed.as_micros() as u64;
+        let bucket = ((u64::BITS - micros.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1);
+        latency.count.fetch_add(1, Ordering::Relaxed);
+        latency.total_micros.fetch_add(micros, Ordering::Relaxed);
+        latency.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> TreeMetrics {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        TreeMetrics {
+            page_reads: load(&self.page_reads),
+            page_writes: load(&self.page_writes),
+            cache_hits: load(&self.cache_hits),
+            cache_misses: load(&self.cache_misses),
+            prefetched_pages: load(&self.prefetched_pages),
+            splits: load(&self.splits),
+            merges: load(&self.merges),
+            overflow_allocations: load(&self.overflow_allocations),
+            fsyncs: load(&self.fsyncs),
+            latency: self.latency.iter().map(|l| LatencyHistogram {
+                count: load(&l.count),
+                total_micros: load(&l.total_micros),
+                buckets: l.buckets.iter().map(load).collect(),
+            }).collect(),
+        }
+    }
+}
+
+//Timer 离开作用域时记录操作耗时
+pub(crate) struct Timer<'a> {
+    metrics: &'a Metrics,
+    op: Operation,
+    start: Instant,
+}
+
+impl<'a> Timer<'a> {
+    pub(crate) fn new(metrics: &'a Metrics, op: Operation) -> Self {
+        Timer {
+            metrics,
+            op,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.metrics.record(self.op, self.start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::temp_path;
+    use crate::store::MemoryStore;
+    use crate::tree::metrics::Metrics;
+    use crate::tree::{Config, Operation, Tree, TreeObserver};
+
+    #[test]
+    fn latency_histogram() {
+        let metrics = Metrics::default();
+        for micros in [0, 1, 3, 100, 100, 5000] {
+            metrics.record(Operation::Get, Duration::from_micros(micros));
+        }
+        let snapshot = metrics.snapshot();
+        let get = snapshot.latency(Operation::Get);
+        assert_eq!(get.count, 6);
+        assert_eq!(get.mean(), Duration::from_micros(5204 / 6));
+        assert_eq!(get.buckets[0], 1);
+        assert_eq!(get.buckets[7], 2);
+        assert_eq!(get.quantile(0.5), Duration::from_micros(4));
+        assert_eq!(get.quantile(1.0), Duration::from_micros(1 << 13));
+        assert_eq!(snapshot.latency(Operation::Insert).count, 0);
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        split: AtomicU64,
+        merge: AtomicU64,
+        evict: AtomicU64,
+    }
+
+    impl TreeObserver for Counter {
+        fn on_split(&self, _left: u64, _right: u64) {
+            self.split.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_merge(&self, _left: u64, _right: u64) {
+            self.merge.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_evict(&self, _seek: u64) {
+            self.evict.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn metrics_observer() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { cache_pages: 4, ..Default::default() }).unwrap();
+        let counter = Arc::new(Counter::default());
+        tree.register_observer(counter.clone());
+        for i in 0..3000u64 {
+            tree.insert(i, i).unwrap();
+        }
+        for i in 0..3000u64 {
+            assert_eq!(tree.get(&i).unwrap(), Some(i));
+        }
+        for i in 0..2900u64 {
+            tree.remove(&i).unwrap();
+        }
+        let metrics = tree.metrics();
+        assert!(metrics.splits > 0);
+        assert_eq!(metrics.splits, counter.split.load(Ordering::SeqCst));
+        assert!(metrics.merges > 0);
+        assert_eq!(metrics.merges, counter.merge.load(Ordering::SeqCst));
+        assert!(counter.evict.load(Ordering::SeqCst) > 0);
+        //顺序读 root 和当前叶子一直在缓存中
+        assert!(metrics.cache_hits > metrics.cache_misses);
+        assert_eq!(metrics.cache_misses, metrics.page_reads);
+        assert_eq!(metrics.latency(Operation::Get).count, 3000);
+        assert_eq!(metrics.latency(Operation::Insert).count, 3000);
+        assert_eq!(metrics.latency(Operation::Remove).count, 2900);
+        assert!(tree.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn overflow_allocations() {
+        let tree = Tree::<u64, String, MemoryStore>::in_memory().unwrap();
+        tree.insert(1, "o".repeat(40000)).unwrap();
+        tree.compact().unwrap();
+        let metrics = tree.metrics();
+        assert_eq!(metrics.overflow_allocations, 3);
+        //新存储 sync 一次, 内存存储替换时没有目录需要同步
+        assert_eq!(metrics.fsyncs, 1);
+        assert_eq!(tree.get(&1).unwrap(), Some("o".repeat(40000)));
+
+        //文件存储替换后再同步目录
+        let dir = tempfile::tempdir().unwrap();
+        let tree = Tree::<u64, String>::open(&temp_path(&dir, "fsyncs.db")).unwrap();
+        tree.insert(1, "o".repeat(40000)).unwrap();
+        tree.compact().unwrap();
+        assert_eq!(tree.metrics().fsyncs, 2);
+    }
+}
@@ -1 +1,34 @@
-mod tree;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+mod tree;
+mod backup;
+mod defrag;
+mod durability;
+mod inspect;
+mod lsn;
+mod metrics;
+mod readahead;
+mod stats;
+mod structure;
+mod verify;
+#[cfg(feature = "tokio")]
+mod async_tree;
+#[cfg(feature = "encrypt")]
+mod rekey;
+#[cfg(feature = "export")]
+mod export;
+pub use tree::{Config, Range, ScanOptions, SplitPolicy, Tree};
+pub use defrag::{DefragHandle, DefragProgress};
+pub use durability::Durability;
+pub use inspect::PageInfo;
+pub use metrics::{LatencyHistogram, Operation, TreeMetrics, TreeObserver};
+pub use stats::TreeStats;
+pub use structure::StructureFormat;
+pub use verify::{VerifyReport, Violation};
+#[cfg(feature = "tokio")]
+pub use async_tree::{AsyncTree, RangeStream};
+#[cfg(feature = "encrypt")]
+pub use rekey::{RekeyHandle, RekeyProgress};
+#[cfg(feature = "export")]
+pub use export::{DataFormat, DisplayFormat, EntryFormat};
+#[cfg(all(feature = "export", feature = "serde"))]
+pub use export::SerdeFormat;
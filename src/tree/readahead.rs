@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use anyhow::Result;
+use crate::node::node::page_size;
+use crate::store::PageStore;
+use crate::tree::metrics::Metrics;
+
+//ReadAhead 后台预读线程, 遍历线程只提交要读取的叶子, 不等待读取完成
+pub(crate) struct ReadAhead {
+    tx: Option<Sender<Request>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct Request {
+    seeks: Vec<u64>,
+    reply: Sender<Result<Prefetched>>,
+}
+
+//Prefetched 一批预读的页, version 是读取时树的修改序号, 之后有修改时这些页作废
+pub(crate) struct Prefetched {
+    pub(crate) version: u64,
+    pub(crate) pages: Vec<(u64, Arc<Vec<u8>>)>,
+}
+
+//Channel 遍历接收预读结果的通道
+pub(crate) type Channel = (Sender<Result<Prefetched>>, Receiver<Result<Prefetched>>);
+
+impl ReadAhead {
+    //spawn 读取时持有树的读锁, 读到的页和 version 对应同一个树状态
+    pub(crate) fn spawn<S: PageStore>(store: Arc<S>, lock: Arc<RwLock<()>>, version: Arc<AtomicU64>, metrics: Arc<Metrics>) -> Self {
+        let (tx, rx) = mpsc::channel::<Request>();
+        let handle = thread::spawn(move || {
+            for request in rx {
+                let result = {
+                    let _guard = lock.read().unwrap_or_else(|e| e.into_inner());
+                    let version = version.load(Ordering::SeqCst);
+                    store.read_pages(&request.seeks, page_size()).map(|pages| Prefetched {
+                        version,
+                        pages: request.seeks.iter().copied().zip(pages.into_iter().map(Arc::new)).collect(),
+                    })
+                };
+                if result.is_ok() {
+                    Metrics::add(&metrics.page_reads, request.seeks.len() as u64);
+                    Metrics::add(&metrics.prefetched_pages, request.seeks.len() as u64);
+                }
+                //遍历已经结束时丢弃
+                let _ = request.reply.send(result);
+            }
+        });
+        ReadAhead {
+            tx: Some(tx),
+            handle: Some(handle),
+        }
+    }
+
+    //request 提交预读, 结果发送到 reply
+    pub(crate) fn request(&self, seeks: Vec<u64>, reply: Sender<Result<Prefetched>>) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(Request { seeks, reply });
+        }
+    }
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        //关闭通道后线程处理完剩余请求退出
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
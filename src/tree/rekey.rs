@@ -0,0 +1,97 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use anyhow::Result;
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::node::node::{page_size, BPlusError};
+use crate::store::{EncryptedStore, PageStore};
+use crate::tree::Tree;
+
+/// 重新加密的进度
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyProgress {
+    // 检查的页数
+    pub scanned: u64,
+    // 重新加密的页数
+    pub rewritten: u64,
+    // 所有页都已经使用当前密钥
+    pub done: bool,
+}
+
+/// 后台重新加密任务
+pub struct RekeyHandle {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<Result<RekeyProgress>>,
+}
+
+impl RekeyHandle {
+    /// 停止重新加密 返回累计进度
+    pub fn stop(self) -> Result<RekeyProgress> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.join()
+    }
+
+    /// 等待所有页重新加密完成
+    pub fn join(self) -> Result<RekeyProgress> {
+        self.handle.join().map_err(|_| BPlusError::NodeError("rekey thread panicked".to_string()))?
+    }
+}
+
+impl<K, V, S> Tree<K, V, EncryptedStore<S>> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    /// 密钥轮换后启动后台线程, 把旧密钥加密的页用当前密钥重新加密, 完成后 fsync
+    /// 每次最多处理 pages_per_step 个页, 两步之间等待 pause, 不阻塞树的读写
+    pub fn spawn_rekey(tree: Arc<Self>, pages_per_step: usize, pause: Duration) -> RekeyHandle
+        where K: 'static, V: 'static, S: 'static
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut progress = RekeyProgress::default();
+            let mut seek = 0;
+            while !flag.load(Ordering::SeqCst) {
+                let step = tree.rekey_step(seek, pages_per_step)?;
+                progress.scanned += step.scanned;
+                progress.rewritten += step.rewritten;
+                if step.done {
+                    tree.flush()?;
+                    progress.done = true;
+                    break;
+                }
+                seek += step.scanned * page_size() as u64;
+                thread::sleep(pause);
+            }
+            Ok(progress)
+        });
+        RekeyHandle {
+            stop,
+            handle,
+        }
+    }
+
+    /// 从 seek 开始重新加密最多 max_pages 个页, 到达文件末尾时 done
+    /// 期间新写入的页已经使用当前密钥, 不需要再处理
+    pub fn rekey_step(&self, seek: u64, max_pages: usize) -> Result<RekeyProgress> {
+        self.check_writable()?;
+        let page = page_size() as u64;
+        let mut progress = RekeyProgress::default();
+        let mut seek = seek;
+        while progress.scanned < max_pages.max(1) as u64 {
+            if seek >= self.store.len() {
+                progress.done = true;
+                break;
+            }
+            if self.store.reencrypt_page(seek)? {
+                progress.rewritten += 1;
+            }
+            progress.scanned += 1;
+            seek += page;
+        }
+        Ok(progress)
+    }
+}
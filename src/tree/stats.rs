@@ -0,0 +1,114 @@
+use std::fmt::Debug;
+use anyhow::Result;
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::node::node::{page_size, ExtraData, EXTRA_DATA, LEAF, MIDDLE_NODE, NODE_FIXED_SIZE, ROOT, VALID};
+use crate::store::PageStore;
+use crate::tree::Tree;
+
+/// 树的统计信息, 按页头扫描整个文件得到
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeStats {
+    // 只有 root 时为 1
+    pub height: u64,
+    pub root_pages: u64,
+    // 中间节点页数 包含 root
+    pub middle_pages: u64,
+    // 叶子页数 包含 root
+    pub leaf_pages: u64,
+    pub extra_pages: u64,
+    pub free_pages: u64,
+    pub entries: u64,
+    // 叶子数据区平均填充率 [0, 1]
+    pub avg_leaf_fill: f64,
+    // 叶子填充率分布, 第 i 档为 [i * 10%, (i + 1) * 10%), 满页在最后一档
+    pub leaf_fill_histogram: [u64; 10],
+    // 保存在额外数据页中的数据字节数
+    pub overflow_bytes: u64,
+    pub file_size: u64,
+}
+
+impl<K, V, S> Tree<K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    /// 统计页数 填充率 额外数据大小, 用于容量规划和判断是否需要 compact
+    pub fn stats(&self) -> Result<TreeStats> {
+        let _guard = self.read_lock();
+        let page = page_size() as u64;
+        let capacity = (page_size() - NODE_FIXED_SIZE) as f64;
+        let mut stats = TreeStats {
+            file_size: self.file_size(),
+            ..Default::default()
+        };
+        let mut fill_sum = 0.0;
+        for seek in (0..stats.file_size).step_by(page as usize) {
+            let data = self.read_page(seek)?;
+            let flag = data[0];
+            let header = |offset: usize| u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap_or([0; 8]));
+            if flag & VALID != VALID {
+                stats.free_pages += 1;
+                continue;
+            }
+            if flag & ROOT == ROOT {
+                stats.root_pages += 1;
+            }
+            if flag & EXTRA_DATA == EXTRA_DATA {
+                stats.extra_pages += 1;
+                stats.overflow_bytes += ExtraData::data_extra_length(&data)?.1;
+            } else if flag & MIDDLE_NODE == MIDDLE_NODE {
+                stats.middle_pages += 1;
+            } else if flag & LEAF == LEAF {
+                stats.leaf_pages += 1;
+                stats.entries += header(9);
+                //residual_storage_size 包含页头之后所有未使用的空间
+                let fill = (1.0 - header(17) as f64 / capacity).clamp(0.0, 1.0);
+                fill_sum += fill;
+                stats.leaf_fill_histogram[((fill * 10.0) as usize).min(9)] += 1;
+            }
+        }
+        if stats.leaf_pages > 0 {
+            stats.avg_leaf_fill = fill_sum / stats.leaf_pages as f64;
+        }
+        stats.height = 1;
+        let mut node = self.read_node(0)?;
+        while !node.is_leaf() {
+            let child = node.key_seek.as_ref().and_then(|s| s.first().copied()).unwrap_or(0);
+            node = self.read_node(child)?;
+            stats.height += 1;
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::node::page_size;
+    use crate::store::{MemoryStore, PageStore};
+    use crate::tree::{Config, Tree};
+    use crate::ValueTest;
+
+    #[test]
+    fn stats() {
+        let tree = Tree::<u64, ValueTest, MemoryStore>::in_memory_with_config(Config { fill_factor: 0.5, ..Default::default() }).unwrap();
+        let empty = tree.stats().unwrap();
+        assert_eq!((empty.height, empty.root_pages, empty.leaf_pages, empty.entries), (1, 1, 1, 0));
+        assert_eq!(empty.leaf_fill_histogram[0], 1);
+
+        tree.bulk_load((0..2000u64).map(|i| (i, ValueTest { id: i as u32, data: "t".repeat(if i == 7 { 40000 } else { 20 }) }))).unwrap();
+        tree.remove(&8).unwrap();
+        let stats = tree.stats().unwrap();
+        assert_eq!(stats.height, 2);
+        assert_eq!(stats.root_pages, 1);
+        assert_eq!(stats.middle_pages, 1);
+        assert_eq!(stats.entries, 1999);
+        assert_eq!(stats.extra_pages, 3);
+        assert_eq!(stats.overflow_bytes, 40004 - (256 - 8 - 16 - 8));
+        assert_eq!(stats.free_pages, 0);
+        assert_eq!(stats.file_size, tree.store.len());
+        assert_eq!(stats.file_size / page_size() as u64, stats.middle_pages + stats.leaf_pages + stats.extra_pages);
+        //按 0.5 填充
+        assert!(stats.avg_leaf_fill > 0.4 && stats.avg_leaf_fill < 0.6, "{}", stats.avg_leaf_fill);
+        assert_eq!(stats.leaf_fill_histogram.iter().sum::<u64>(), stats.leaf_pages);
+    }
+}
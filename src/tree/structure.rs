@@ -0,0 +1,188 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{Debug, Write};
+use anyhow::Result;
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::node::node::{EXTRA_DATA, LEAF, MIDDLE_NODE, ROOT};
+use crate::store::PageStore;
+use crate::tree::{PageInfo, Tree};
+
+/// 树结构导出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StructureFormat {
+    // Graphviz dot
+    Dot,
+    Json,
+}
+
+//dot 标签中最多显示的 key 个数
+const DOT_MAX_KEYS: usize = 8;
+
+impl<K, V, S> Tree<K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    /// 导出页之间的结构: 节点类型 key 子节点 叶子兄弟 额外数据页链表
+    pub fn export_structure(&self, format: StructureFormat) -> Result<String> {
+        let _guard = self.read_lock();
+        let pages = self.structure_pages()?;
+        Ok(match format {
+            StructureFormat::Dot => Self::structure_dot(&pages),
+            StructureFormat::Json => Self::structure_json(&pages),
+        })
+    }
+
+    //structure_pages 从 root 广度优先读取所有可达的页, 额外数据页跟在所属叶子后面
+    fn structure_pages(&self) -> Result<Vec<PageInfo<K>>> {
+        let mut pages = vec![];
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::from([0u64]);
+        while let Some(seek) = queue.pop_front() {
+            if !visited.insert(seek) {
+                continue;
+            }
+            let page = self.page_info(seek)?;
+            queue.extend(page.key_seek.iter().copied());
+            let firsts: Vec<u64> = page.extra.iter().flatten().copied().collect();
+            pages.push(page);
+            for first in firsts {
+                let mut next = first;
+                while next != 0 && visited.insert(next) {
+                    let extra = self.page_info(next)?;
+                    next = extra.next;
+                    pages.push(extra);
+                }
+            }
+        }
+        Ok(pages)
+    }
+
+    fn structure_dot(pages: &[PageInfo<K>]) -> String {
+        let mut out = String::from("digraph bptree {\n    node [shape=box, fontname=monospace];\n");
+        for page in pages {
+            let (kind, style) = page_kind(page.flag);
+            let label = if page.flag & EXTRA_DATA == EXTRA_DATA {
+                let (origin_length, len) = page.extra_length.unwrap_or((0, 0));
+                format!("{} @{}\\nkey {:?} {}/{}", kind, page.seek, page.keys.first(), len, origin_length)
+            } else {
+                format!("{} @{}\\n{}", kind, page.seek, dot_keys(&page.keys))
+            };
+            let _ = writeln!(out, "    p{} [label=\"{}\", {}];", page.seek, label.replace('"', "\\\""), style);
+            for child in page.key_seek.iter() {
+                let _ = writeln!(out, "    p{} -> p{};", page.seek, child);
+            }
+            if page.flag & LEAF == LEAF && page.next != 0 {
+                let _ = writeln!(out, "    p{} -> p{} [style=dashed, constraint=false];", page.seek, page.next);
+            }
+            for extra in page.extra.iter().flatten() {
+                let _ = writeln!(out, "    p{} -> p{} [style=dotted];", page.seek, extra);
+            }
+            if page.flag & EXTRA_DATA == EXTRA_DATA && page.next != 0 {
+                let _ = writeln!(out, "    p{} -> p{} [style=dotted];", page.seek, page.next);
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    fn structure_json(pages: &[PageInfo<K>]) -> String {
+        let mut out = String::from("{\"pages\":[");
+        for (i, page) in pages.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let keys: Vec<String> = page.keys.iter().map(|k| json_string(&format!("{:?}", k))).collect();
+            let flags: Vec<String> = page.flag_names().iter().map(|f| json_string(f)).collect();
+            let extra: Vec<String> = page.extra.iter().map(|e| e.map(|s| s.to_string()).unwrap_or_else(|| "null".to_string())).collect();
+            let _ = write!(
+                out,
+                "{{\"seek\":{},\"type\":{},\"flags\":[{}],\"keys\":[{}],\"children\":{:?},\"prev\":{},\"next\":{},\"extra\":[{}]",
+                page.seek, json_string(page_kind(page.flag).0), flags.join(","), keys.join(","), page.key_seek, page.prev, page.next, extra.join(","),
+            );
+            if let Some((origin_length, len)) = page.extra_length {
+                let _ = write!(out, ",\"origin_length\":{},\"length\":{}", origin_length, len);
+            }
+            out.push('}');
+        }
+        out.push_str("]}\n");
+        out
+    }
+}
+
+//page_kind 节点类型名字和 dot 样式
+fn page_kind(flag: u8) -> (&'static str, &'static str) {
+    if flag & EXTRA_DATA == EXTRA_DATA {
+        ("extra", "style=filled, fillcolor=lightgrey")
+    } else if flag & (ROOT | LEAF) == ROOT | LEAF {
+        ("root-leaf", "style=filled, fillcolor=lightblue")
+    } else if flag & ROOT == ROOT {
+        ("root", "style=filled, fillcolor=lightblue")
+    } else if flag & MIDDLE_NODE == MIDDLE_NODE {
+        ("middle", "style=solid")
+    } else if flag & LEAF == LEAF {
+        ("leaf", "style=rounded")
+    } else {
+        ("invalid", "style=filled, fillcolor=red")
+    }
+}
+
+//dot_keys key 太多时只显示开头和结尾
+fn dot_keys<K: Debug>(keys: &[K]) -> String {
+    if keys.len() <= DOT_MAX_KEYS {
+        return format!("{:?}", keys);
+    }
+    let half = DOT_MAX_KEYS / 2;
+    let head: Vec<String> = keys[..half].iter().map(|k| format!("{:?}", k)).collect();
+    let tail: Vec<String> = keys[keys.len() - half..].iter().map(|k| format!("{:?}", k)).collect();
+    format!("[{} .. {}] ({} keys)", head.join(", "), tail.join(", "), keys.len())
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::MemoryStore;
+    use crate::tree::{Config, StructureFormat, Tree};
+    use crate::ValueTest;
+
+    #[test]
+    fn export_structure() {
+        let tree = Tree::<u64, ValueTest, MemoryStore>::in_memory_with_config(Config { fill_factor: 0.05, ..Default::default() }).unwrap();
+        tree.bulk_load((0..100u64).map(|i| (i, ValueTest { id: i as u32, data: "s".repeat(if i == 50 { 20000 } else { 10 }) }))).unwrap();
+        let first = tree.first_leaf().unwrap();
+        let owner = tree.search_leaf(&50).unwrap();
+        let extra = owner.extra_data.unwrap().into_iter().flatten().next().unwrap().seek;
+
+        let dot = tree.export_structure(StructureFormat::Dot).unwrap();
+        assert!(dot.starts_with("digraph bptree {"));
+        assert!(dot.contains(&format!("p{} -> p{} [style=dashed, constraint=false];", first.seek_start, first.next)));
+        assert!(dot.contains(&format!("-> p{} [style=dotted];", extra)));
+        assert!(dot.contains("p0 [label=\"root @0"));
+
+        let json = tree.export_structure(StructureFormat::Json).unwrap();
+        assert!(json.starts_with("{\"pages\":[{\"seek\":0,\"type\":\"root\""));
+        assert!(json.contains(&format!("{{\"seek\":{},\"type\":\"extra\",\"flags\":[\"VALID\",\"EXTRA_DATA\"],\"keys\":[\"50\"]", extra)));
+        //额外数据页跟在所属叶子后面
+        let position = |seek: u64| json.find(&format!("{{\"seek\":{},", seek)).unwrap();
+        assert!(position(owner.seek_start) < position(extra));
+        //每个可达页出现一次
+        let report = tree.verify().unwrap();
+        assert_eq!(json.matches("\"seek\":").count() as u64, report.pages);
+    }
+}
@@ -1,10 +1,1898 @@
-use std::fs::File;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::num::NonZeroUsize;
+use std::ops::{Bound, Deref, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::JoinHandle;
+use anyhow::Result;
+use lru::LruCache;
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::node::node::{data_max_len, extra_capacity, leaf_entry_size, middle_max_key, page_size, BPlusError, ExtraData, Node, NEXT_OFFSET, NODE_FIXED_SIZE, PREV_OFFSET, ROOT, VALID};
+use crate::store::{FileStore, MemoryStore, PageStore};
+#[cfg(feature = "mmap")]
+use crate::store::MmapStore;
+#[cfg(all(feature = "direct", target_os = "linux"))]
+use crate::store::DirectStore;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+use crate::store::UringStore;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+use crate::store::{CompressedStore, PageCodec};
+#[cfg(feature = "encrypt")]
+use crate::store::{Cipher, EncryptedStore, KeyProvider};
+use crate::tree::backup::BackupState;
+use crate::tree::defrag::DefragState;
+use crate::tree::durability::{Durability, Syncer};
+use crate::tree::readahead::{Channel, ReadAhead};
+use crate::tree::lsn::LsnTracker;
+use crate::tree::metrics::{Metrics, Operation, Timer, TreeMetrics, TreeObserver};
 
+/// 节点拆分策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitPolicy {
+    // 对半拆分
+    Even,
+    // 左边保留 90%, 适合单调递增的 key
+    RightBiased,
+    // 在最右叶子末尾插入时只把新数据拆到右边, 其他情况对半拆分
+    Auto,
+}
+
+/// 树配置
+#[derive(Debug, Clone)]
+pub struct Config {
+    // 批量导入时页的目标填充率 (0, 1]
+    pub fill_factor: f64,
+    pub split_policy: SplitPolicy,
+    // 页缓存大小(页数), 0 不缓存
+    pub cache_pages: usize,
+    // 范围遍历连续读取叶子时预读的叶子数, 0 不预读
+    pub readahead_leaves: usize,
+    pub durability: Durability,
+    // 在树文件旁边的 .lsn 文件中记录每个页的修改序号, 增量备份需要开启
+    pub track_changes: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            fill_factor: 1.0,
+            split_policy: SplitPolicy::Even,
+            cache_pages: 1024,
+            readahead_leaves: 8,
+            durability: Durability::None,
+            track_changes: false,
+        }
+    }
+}
+
+/// 单次范围遍历的选项
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    // 预读的叶子数, None 使用 Config::readahead_leaves
+    pub readahead: Option<usize>,
+}
+
+//叶子数据区使用小于 1/MERGE_DIVISOR 时尝试和兄弟叶子合并
+const MERGE_DIVISOR: usize = 4;
+
+//沿 next 连续读取多少个叶子后认为是顺序遍历, 开始预读
+const READAHEAD_TRIGGER: usize = 2;
+
+//查找路径 (中间节点, 子节点下标)
+pub(crate) type SearchPath<K, V> = Vec<(Node<K, V>, usize)>;
+
+pub struct Tree<K, V, S: PageStore = FileStore> {
+    pub(crate) store: StoreSlot<S>,
+    config: Config,
+    //结构锁 读操作共享 修改结构独占, 后台预读线程读取时也持有读锁
+    lock: Arc<RwLock<()>>,
+    //修改序号 每次获得写锁时增加, 预读的页只在序号不变时使用
+    version: Arc<AtomicU64>,
+    //后台预读线程 第一次顺序遍历时启动
+    readahead: OnceLock<ReadAhead>,
+    //空闲页 打开时扫描无效页重建, allocate 优先使用最前面的空闲页
+    free: Mutex<BTreeSet<u64>>,
+    //后台整理进度
+    pub(crate) defrag: Mutex<Option<DefragState>>,
+    //正在进行的在线备份
+    pub(crate) backup: Mutex<Option<BackupState>>,
+    //页缓存 写入时同步更新
+    cache: Option<Mutex<LruCache<u64, Arc<Vec<u8>>>>>,
+    pub(crate) metrics: Arc<Metrics>,
+    observers: RwLock<Vec<Arc<dyn TreeObserver>>>,
+    pub(crate) syncer: Arc<Syncer>,
+    //Durability::Periodic 的后台 fsync 线程
+    periodic: Option<JoinHandle<()>>,
+    //页修改序号
+    pub(crate) lsn: LsnTracker,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+impl<K, V> Tree<K, V, FileStore> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync
+{
+    pub fn open(path: &str) -> Result<Self> {
+        Self::open_with_config(path, Config::default())
+    }
+
+    pub fn open_with_config(path: &str, config: Config) -> Result<Self> {
+        Self::with_store(FileStore::open(path)?, config)
+    }
+
+    /// 只读打开, 可以有多个只读打开同时存在, 有读写打开时返回 Locked, 所有修改返回 ReadOnly
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        Self::open_read_only_with_config(path, Config::default())
+    }
+
+    pub fn open_read_only_with_config(path: &str, config: Config) -> Result<Self> {
+        Self::with_store(FileStore::open_read_only(path)?, config)
+    }
+
+    pub fn path(&self) -> &str {
+        self.store.path()
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl<K, V> Tree<K, V, MmapStore> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync
+{
+    /// 通过内存映射读取页, 页数据直接从映射解码, 不使用页缓存
+    pub fn open_mmap(path: &str) -> Result<Self> {
+        Self::open_mmap_with_config(path, Config::default())
+    }
+
+    /// cache_pages 被忽略, 映射的页由操作系统缓存
+    pub fn open_mmap_with_config(path: &str, config: Config) -> Result<Self> {
+        Self::with_store(MmapStore::open(path)?, Config { cache_pages: 0, ..config })
+    }
+
+    pub fn path(&self) -> &str {
+        self.store.path()
+    }
+}
+
+#[cfg(all(feature = "direct", target_os = "linux"))]
+impl<K, V> Tree<K, V, DirectStore> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync
+{
+    /// 以 O_DIRECT 打开, 不经过内核页缓存, 读缓存只有 cache_pages
+    pub fn open_direct(path: &str) -> Result<Self> {
+        Self::open_direct_with_config(path, Config::default())
+    }
 
-pub struct Tree {
-    path: &'static str,
-    fd: Rc<RefCell<File>>,
+    pub fn open_direct_with_config(path: &str, config: Config) -> Result<Self> {
+        Self::with_store(DirectStore::open(path)?, config)
+    }
+
+    pub fn path(&self) -> &str {
+        self.store.path()
+    }
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+impl<K, V> Tree<K, V, UringStore> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync
+{
+    /// 使用 io_uring 批量预读, 范围遍历和 get_many 一次提交多个页的读取
+    pub fn open_uring(path: &str) -> Result<Self> {
+        Self::open_uring_with_config(path, Config::default())
+    }
+
+    pub fn open_uring_with_config(path: &str, config: Config) -> Result<Self> {
+        Self::with_store(UringStore::open(path)?, config)
+    }
+
+    pub fn path(&self) -> &str {
+        self.store.path()
+    }
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+impl<K, V> Tree<K, V, CompressedStore> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync
+{
+    /// 每页写入前用 codec 压缩, 读取后解压, 页缓存中保存的是解压后的页
+    pub fn open_compressed(path: &str, codec: PageCodec) -> Result<Self> {
+        Self::open_compressed_with_config(path, codec, Config::default())
+    }
+
+    pub fn open_compressed_with_config(path: &str, codec: PageCodec, config: Config) -> Result<Self> {
+        Self::with_store(CompressedStore::open(path, codec)?, config)
+    }
+
+    pub fn path(&self) -> &str {
+        self.store.path()
+    }
 }
 
+#[cfg(feature = "encrypt")]
+impl<K, V> Tree<K, V, EncryptedStore> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync
+{
+    /// 每页写入前加密, 读取时校验并解密, 密钥由 keys 提供
+    pub fn open_encrypted(path: &str, keys: Arc<dyn KeyProvider>, cipher: Cipher) -> Result<Self> {
+        Self::open_encrypted_with_config(path, keys, cipher, Config::default())
+    }
+
+    pub fn open_encrypted_with_config(path: &str, keys: Arc<dyn KeyProvider>, cipher: Cipher, config: Config) -> Result<Self> {
+        Self::with_store(EncryptedStore::open(path, keys, cipher)?, config)
+    }
+
+    pub fn path(&self) -> &str {
+        self.store.path()
+    }
+}
+
+impl<K, V> Tree<K, V, MemoryStore> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync
+{
+    /// 数据只保存在内存中的树
+    pub fn in_memory() -> Result<Self> {
+        Self::in_memory_with_config(Config::default())
+    }
+
+    pub fn in_memory_with_config(config: Config) -> Result<Self> {
+        Self::with_store(MemoryStore::new(), config)
+    }
+}
+
+//drop 先 fsync 数据再把 .lsn 标记为正常关闭, 否则断电后 .lsn 中的序号可能比磁盘上的页新
+impl<K, V, S: PageStore> Drop for Tree<K, V, S> {
+    fn drop(&mut self) {
+        self.readahead.take();
+        if let Some(handle) = self.periodic.take() {
+            self.syncer.stop_periodic(handle);
+        }
+        if let Some(store) = &self.store.0 {
+            self.lsn.close(|| store.sync());
+        }
+    }
+}
+
+//StoreSlot 树的存储, 和后台 fsync 线程共享, compact 从临时树中取出存储替换原存储
+pub(crate) struct StoreSlot<S>(Option<Arc<S>>);
+
+impl<S> StoreSlot<S> {
+    //take 临时树没有后台线程, 存储不会被共享
+    fn take(&mut self) -> S {
+        self.0.take().and_then(|store| Arc::into_inner(store)).expect("store taken")
+    }
+
+    fn shared(&self) -> Arc<S> {
+        self.0.clone().expect("store taken")
+    }
+}
+
+impl<S> Deref for StoreSlot<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        self.0.as_deref().expect("store taken")
+    }
+}
+
+impl<K, V, S> Tree<K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    /// 使用指定的存储后端打开树, 空存储时写入 root
+    pub fn with_store(store: S, config: Config) -> Result<Self> {
+        let lsn = LsnTracker::open(store.location(), store.len(), !store.is_read_only(), config.track_changes)?;
+        let mut tree = Tree {
+            store: StoreSlot(Some(Arc::new(store))),
+            config: config.clone(),
+            lock: Arc::new(RwLock::new(())),
+            version: Arc::new(AtomicU64::new(0)),
+            readahead: OnceLock::new(),
+            free: Mutex::new(BTreeSet::new()),
+            defrag: Mutex::new(None),
+            backup: Mutex::new(None),
+            cache: NonZeroUsize::new(config.cache_pages).map(|n| Mutex::new(LruCache::new(n))),
+            metrics: Arc::new(Metrics::default()),
+            observers: RwLock::new(vec![]),
+            syncer: Arc::new(Syncer::default()),
+            periodic: None,
+            lsn,
+            _k: PhantomData,
+            _v: PhantomData,
+        };
+        //新文件 root 在 0 位置, 初始状态是叶子
+        if tree.store.is_empty() {
+            tree.check_writable()?;
+            let mut root = Node::<K, V>::new_leaf(tree.allocate(), vec![], vec![]);
+            root.flag |= ROOT;
+            tree.write_node(&mut root)?;
+        } else if !tree.store.is_read_only() {
+            *tree.free_pages() = tree.scan_free()?;
+        }
+        if let (Durability::Periodic { interval }, false) = (config.durability, tree.store.is_read_only()) {
+            tree.periodic = Some(Syncer::spawn_periodic(tree.syncer.clone(), tree.store.shared(), tree.metrics.clone(), interval));
+        }
+        Ok(tree)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// 页大小
+    pub fn page_size() -> usize {
+        page_size()
+    }
+
+    /// 文件大小
+    pub fn file_size(&self) -> u64 {
+        self.store.len()
+    }
+
+    /// 运行统计
+    pub fn metrics(&self) -> TreeMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// 注册结构变化的回调
+    pub fn register_observer(&self, observer: Arc<dyn TreeObserver>) {
+        self.observers.write().unwrap_or_else(|e| e.into_inner()).push(observer);
+    }
+
+    fn notify(&self, f: impl Fn(&dyn TreeObserver)) {
+        for observer in self.observers.read().unwrap_or_else(|e| e.into_inner()).iter() {
+            f(observer.as_ref());
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let _timer = Timer::new(&self.metrics, Operation::Get);
+        let _guard = self.read_lock();
+        self.get_locked(key)
+    }
+
+    pub(crate) fn get_locked(&self, key: &K) -> Result<Option<V>> {
+        let leaf = self.search_leaf(key)?;
+        if let (Some(keys), Some(values)) = (&leaf.key, &leaf.value) {
+            if let Some(i) = keys.iter().position(|k| k.as_ref() == key) {
+                return Ok(Some(values[i].as_ref().clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// 批量查找, 按层读取所有 key 经过的节点, 同一层的页一次提交给存储
+    pub fn get_many(&self, keys: &[K]) -> Result<Vec<Option<V>>> {
+        let _timer = Timer::new(&self.metrics, Operation::Get);
+        let _guard = self.read_lock();
+        let mut result = vec![None; keys.len()];
+        //(key 下标, 当前层的页)
+        let mut level: Vec<(usize, u64)> = (0..keys.len()).map(|i| (i, 0)).collect();
+        while !level.is_empty() {
+            let mut seeks: Vec<u64> = level.iter().map(|(_, seek)| *seek).collect();
+            seeks.sort_unstable();
+            seeks.dedup();
+            self.prefetch(&seeks)?;
+            let mut nodes = HashMap::with_capacity(seeks.len());
+            for seek in seeks {
+                nodes.insert(seek, self.read_node(seek)?);
+            }
+            let mut next = Vec::with_capacity(level.len());
+            for (i, seek) in level {
+                let node = &nodes[&seek];
+                if node.is_leaf() {
+                    if let (Some(k), Some(values)) = (&node.key, &node.value) {
+                        if let Some(j) = k.iter().position(|k| k.as_ref() == &keys[i]) {
+                            result[i] = Some(values[j].as_ref().clone());
+                        }
+                    }
+                    continue;
+                }
+                let child = Self::child_index(node, &keys[i]);
+                let seek = node.key_seek.as_ref()
+                    .and_then(|s| s.get(child).copied())
+                    .ok_or_else(|| BPlusError::NodeError("not key seek".to_string()))?;
+                next.push((i, seek));
+            }
+            level = next;
+        }
+        Ok(result)
+    }
+
+    /// 按 key 范围顺序遍历, 每读一个叶子加一次读锁, 遍历期间的修改不一定可见
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<Range<'_, K, V, S>> {
+        self.range_with(range, ScanOptions::default())
+    }
+
+    /// 按 key 范围顺序遍历, 可以单独指定预读窗口, 导出大量数据时加大窗口
+    pub fn range_with<R: RangeBounds<K>>(&self, range: R, options: ScanOptions) -> Result<Range<'_, K, V, S>> {
+        let _timer = Timer::new(&self.metrics, Operation::Range);
+        let _guard = self.read_lock();
+        let (node, index) = match range.start_bound() {
+            Bound::Included(key) => {
+                let leaf = self.search_leaf(key)?;
+                let index = leaf.key.as_ref().map(|k| k.partition_point(|k| k.as_ref() < key)).unwrap_or(0);
+                (leaf, index)
+            }
+            Bound::Excluded(key) => {
+                let leaf = self.search_leaf(key)?;
+                let index = leaf.key.as_ref().map(|k| k.partition_point(|k| k.as_ref() <= key)).unwrap_or(0);
+                (leaf, index)
+            }
+            Bound::Unbounded => (self.first_leaf()?, 0),
+        };
+        Ok(Range {
+            tree: self,
+            node: Some(node),
+            index,
+            end: range.end_bound().cloned(),
+            readahead: options.readahead.unwrap_or(self.config.readahead_leaves),
+            sequential: 0,
+            ahead: VecDeque::new(),
+            pending: None,
+            staged: HashMap::new(),
+        })
+    }
+
+    /// 插入数据 key 已存在时替换并返回旧数据
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>> {
+        let _timer = Timer::new(&self.metrics, Operation::Insert);
+        self.check_writable()?;
+        let old = {
+            let _guard = self.write_lock();
+            self.insert_locked(key, value)?
+        };
+        self.commit()?;
+        Ok(old)
+    }
+
+    pub(crate) fn insert_locked(&self, key: K, value: V) -> Result<Option<V>> {
+        let (mut path, mut leaf) = self.search_path(&key)?;
+
+        let max_len = data_max_len::<K>();
+        let mut encode: Vec<u8> = vec![];
+        let encode_len = value.encode(&mut encode)?;
+        let extra = if encode_len > max_len {
+            Some(ExtraData {
+                seek: self.write_extra(&key, &encode[(max_len - 8) as usize..])?,
+                data: None,
+                next: None,
+            })
+        } else {
+            None
+        };
+
+        let keys = leaf.key.get_or_insert_with(Vec::new);
+        let index = keys.partition_point(|k| k.as_ref() < &key);
+        let mut old = None;
+        if index < keys.len() && keys[index].as_ref() == &key {
+            let (old_value, old_extra) = leaf.leaf_replace(index, value, extra);
+            if let Some(old_extra) = old_extra {
+                self.free_extra(old_extra.seek)?;
+            }
+            old = Some(old_value);
+        } else {
+            leaf.leaf_insert(index, key, value, extra);
+        }
+
+        let size = leaf.data_size()?;
+        if size.iter().sum::<usize>() <= page_size() - NODE_FIXED_SIZE {
+            self.write_node(&mut leaf)?;
+            return Ok(old);
+        }
+
+        //叶子拆分
+        let append = leaf.next == 0 && index + 1 == size.len();
+        let ratio = self.split_ratio(append);
+        let total = size.iter().sum::<usize>() as f64;
+        let mut at = 0;
+        let mut used = 0;
+        while at < size.len() && (used as f64) < total * ratio {
+            used += size[at];
+            at += 1;
+        }
+        let at = at.clamp(1, size.len() - 1);
+        let is_root = leaf.seek_start == 0;
+        if is_root {
+            leaf.seek_start = self.allocate();
+            leaf.flag &= !ROOT;
+        }
+        let right_seek = self.allocate();
+        let mut right = leaf.leaf_split_off(at, right_seek);
+        right.prev = leaf.seek_start;
+        right.next = leaf.next;
+        if leaf.next != 0 {
+            self.set_prev(leaf.next, right_seek)?;
+        }
+        leaf.next = right_seek;
+        let mut up = right.key.as_ref().map(|k| k[0].as_ref().clone())
+            .ok_or_else(|| BPlusError::NodeError("not key".to_string()))?;
+        self.write_node(&mut leaf)?;
+        self.write_node(&mut right)?;
+        Metrics::add(&self.metrics.splits, 1);
+        self.notify(|o| o.on_split(leaf.seek_start, right_seek));
+        if is_root {
+            return self.grow_root(up, leaf.seek_start, right_seek).map(|_| old);
+        }
+
+        //拆分向上传递
+        let mut up_seek = right_seek;
+        while let Some((mut parent, child)) = path.pop() {
+            parent.middle_insert(child, up, up_seek);
+            if parent.key_count as usize <= middle_max_key::<K>() {
+                self.write_node(&mut parent)?;
+                return Ok(old);
+            }
+            let count = parent.key_count as usize;
+            let at = ((count as f64 * self.split_ratio(append)) as usize).clamp(1, count - 2);
+            let is_root = parent.seek_start == 0;
+            if is_root {
+                parent.seek_start = self.allocate();
+                parent.flag &= !ROOT;
+            }
+            let right_seek = self.allocate();
+            let (key, mut right) = parent.middle_split_off(at, right_seek);
+            self.write_node(&mut parent)?;
+            self.write_node(&mut right)?;
+            Metrics::add(&self.metrics.splits, 1);
+            self.notify(|o| o.on_split(parent.seek_start, right_seek));
+            if is_root {
+                return self.grow_root(key, parent.seek_start, right_seek).map(|_| old);
+            }
+            up = key;
+            up_seek = right_seek;
+        }
+        Ok(old)
+    }
+
+    /// 删除数据 返回旧数据, 叶子删空后从父节点中移除
+    pub fn remove(&self, key: &K) -> Result<Option<V>> {
+        let _timer = Timer::new(&self.metrics, Operation::Remove);
+        self.check_writable()?;
+        let old = {
+            let _guard = self.write_lock();
+            self.remove_locked(key)?
+        };
+        self.commit()?;
+        Ok(old)
+    }
+
+    fn remove_locked(&self, key: &K) -> Result<Option<V>> {
+        let (path, mut leaf) = self.search_path(key)?;
+        let index = match leaf.key.as_ref().and_then(|k| k.iter().position(|k| k.as_ref() == key)) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let (old, extra) = leaf.leaf_remove(index);
+        if let Some(extra) = extra {
+            self.free_extra(extra.seek)?;
+        }
+        let used = leaf.data_size()?.iter().sum::<usize>();
+        if path.is_empty() || used >= (page_size() - NODE_FIXED_SIZE) / MERGE_DIVISOR {
+            self.write_node(&mut leaf)?;
+            return Ok(Some(old));
+        }
+        self.merge_leaf(path, leaf, used)?;
+        Ok(Some(old))
+    }
+
+    //merge_leaf 叶子数据过少时和同一父节点下的兄弟叶子合并, 右边叶子并入左边
+    fn merge_leaf(&self, mut path: SearchPath<K, V>, mut leaf: Node<K, V>, used: usize) -> Result<()> {
+        let (children, child) = match path.last() {
+            Some((parent, child)) => (parent.key_seek.clone().unwrap_or_default(), *child),
+            None => return self.write_node(&mut leaf),
+        };
+        let sibling = if child + 1 < children.len() { Some(child + 1) } else { child.checked_sub(1) };
+        if let Some(sibling) = sibling {
+            let other = self.read_node(children[sibling])?;
+            if used + other.data_size()?.iter().sum::<usize>() <= page_size() - NODE_FIXED_SIZE {
+                let (mut left, right) = if sibling > child { (leaf, other) } else { (other, leaf) };
+                let (right_seek, right_next) = (right.seek_start, right.next);
+                left.leaf_append(right);
+                if right_next != 0 {
+                    self.set_prev(right_next, left.seek_start)?;
+                }
+                self.write_node(&mut left)?;
+                self.free_page(right_seek)?;
+                Metrics::add(&self.metrics.merges, 1);
+                self.notify(|o| o.on_merge(left.seek_start, right_seek));
+                if let Some(last) = path.last_mut() {
+                    last.1 = child.max(sibling);
+                }
+                return self.remove_child(path);
+            }
+        }
+        if leaf.data_count > 0 {
+            return self.write_node(&mut leaf);
+        }
+
+        //没有兄弟的空叶子 从叶子链表中删除
+        if leaf.prev != 0 {
+            self.set_next(leaf.prev, leaf.next)?;
+        }
+        if leaf.next != 0 {
+            self.set_prev(leaf.next, leaf.prev)?;
+        }
+        self.free_page(leaf.seek_start)?;
+        self.remove_child(path)
+    }
+
+    //remove_child 从父节点删除子节点, 空的中间节点继续向上删除
+    fn remove_child(&self, mut path: SearchPath<K, V>) -> Result<()> {
+        while let Some((mut parent, child)) = path.pop() {
+            parent.middle_remove(child);
+            if parent.seek_start == 0 {
+                return self.shrink_root(parent);
+            }
+            if parent.key_seek.as_ref().is_some_and(|s| !s.is_empty()) {
+                return self.write_node(&mut parent);
+            }
+            self.free_page(parent.seek_start)?;
+        }
+        Ok(())
+    }
+
+    //shrink_root root 只剩一个子节点时 子节点移动到 0 位置, 树高减一
+    fn shrink_root(&self, mut root: Node<K, V>) -> Result<()> {
+        while !root.is_leaf() && root.key_seek.as_ref().map(|s| s.len()) == Some(1) {
+            let child = root.key_seek.as_ref().map(|s| s[0]).unwrap_or(0);
+            root = self.read_node(child)?;
+            self.free_page(child)?;
+            root.seek_start = 0;
+            root.flag |= ROOT;
+        }
+        //所有子节点都被删除
+        if !root.is_leaf() && root.key_seek.as_ref().is_none_or(|s| s.is_empty()) {
+            root = Node::new_leaf(0, vec![], vec![]);
+            root.flag |= ROOT;
+        }
+        self.write_node(&mut root)
+    }
+
+    //split_ratio 拆分后左边节点保留的比例
+    fn split_ratio(&self, append: bool) -> f64 {
+        match self.config.split_policy {
+            SplitPolicy::Even => 0.5,
+            SplitPolicy::RightBiased => 0.9,
+            SplitPolicy::Auto if append => 1.0,
+            SplitPolicy::Auto => 0.5,
+        }
+    }
+
+    //grow_root root 拆分后 在 0 位置写入新的 root
+    fn grow_root(&self, key: K, left: u64, right: u64) -> Result<()> {
+        let mut root = Node::<K, V>::new_middle(0, vec![Box::new(key)], vec![left, right]);
+        root.flag |= ROOT;
+        self.write_node(&mut root)
+    }
+
+    /// 从有序数据自底向上构建树, 只能用于空树
+    /// 叶子按顺序写入并维护 prev next, 按 fill_factor 填充页
+    pub fn bulk_load(&self, iter: impl Iterator<Item = (K, V)>) -> Result<u64> {
+        self.check_writable()?;
+        let count = {
+            let _guard = self.write_lock();
+            self.bulk_load_locked(iter)?
+        };
+        self.commit()?;
+        Ok(count)
+    }
+
+    fn bulk_load_locked(&self, iter: impl Iterator<Item = (K, V)>) -> Result<u64> {
+        let root = self.read_node(0)?;
+        if !root.is_leaf() || root.key_count > 0 {
+            return Err(BPlusError::NotEmpty().into());
+        }
+        let fill = if self.config.fill_factor > 0.0 && self.config.fill_factor <= 1.0 { self.config.fill_factor } else { 1.0 };
+        let leaf_limit = ((page_size() - NODE_FIXED_SIZE) as f64 * fill) as usize;
+        let max_len = data_max_len::<K>();
+
+        // 下一层节点 (第一个key, 页位置)
+        let mut children: Vec<(K, u64)> = vec![];
+        let mut leaf: Option<Node<K, V>> = None;
+        let mut used: usize = 0;
+        let mut count: u64 = 0;
+        let mut encode: Vec<u8> = vec![];
+        let mut last_key: Option<K> = None;
+        for (k, v) in iter {
+            if let Some(last) = &last_key {
+                if last >= &k {
+                    return Err(BPlusError::Unsorted().into());
+                }
+            }
+            last_key = Some(k.clone());
+            encode.clear();
+            let encode_len = v.encode(&mut encode)?;
+            let size = leaf_entry_size::<K>(encode_len);
+
+            //当前叶子写满 分配下一个叶子
+            //第一个叶子写满时才分配页, 只有一个叶子时直接写到 root 位置
+            if leaf.is_none() || (used > 0 && used + size > leaf_limit) {
+                let mut next = Node::<K, V>::new_leaf(0, vec![], vec![]);
+                if let Some(mut prev) = leaf.take() {
+                    if prev.seek_start == 0 {
+                        prev.seek_start = self.allocate();
+                        if let Some(first) = children.last_mut() {
+                            first.1 = prev.seek_start;
+                        }
+                    }
+                    next.seek_start = self.allocate();
+                    prev.next = next.seek_start;
+                    next.prev = prev.seek_start;
+                    self.write_node(&mut prev)?;
+                }
+                children.push((k.clone(), next.seek_start));
+                leaf = Some(next);
+                used = 0;
+            }
+
+            let extra = if encode_len > max_len {
+                Some(ExtraData {
+                    seek: self.write_extra(&k, &encode[(max_len - 8) as usize..])?,
+                    data: None,
+                    next: None,
+                })
+            } else {
+                None
+            };
+            if let Some(node) = leaf.as_mut() {
+                node.key.get_or_insert_with(Vec::new).push(Box::new(k));
+                node.value.get_or_insert_with(Vec::new).push(Box::new(v));
+                node.extra_data.get_or_insert_with(Vec::new).push(extra);
+                node.key_count += 1;
+                node.data_count += 1;
+            }
+            used += size;
+            count += 1;
+        }
+
+        let mut last = match leaf {
+            Some(node) => node,
+            None => return Ok(0),
+        };
+        //只有一个叶子 直接作为 root
+        if children.len() == 1 {
+            last.flag |= ROOT;
+            self.write_node(&mut last)?;
+            return Ok(count);
+        }
+        self.write_node(&mut last)?;
+
+        let max_children = middle_max_key::<K>() + 1;
+        let target = ((middle_max_key::<K>() as f64 * fill) as usize + 1).clamp(2, max_children);
+        while children.len() > target {
+            let mut parents: Vec<(K, u64)> = vec![];
+            let group = children.len().div_ceil(target).min(children.len() / 2).max(1);
+            let mut rest = children.into_iter();
+            for g in 0..group {
+                let size = rest.len() / (group - g);
+                let chunk: Vec<(K, u64)> = rest.by_ref().take(size).collect();
+                let seek = self.allocate();
+                parents.push((chunk[0].0.clone(), seek));
+                self.write_node(&mut Self::middle_from(seek, chunk))?;
+            }
+            children = parents;
+        }
+        let mut root = Self::middle_from(0, children);
+        root.flag |= ROOT;
+        self.write_node(&mut root)?;
+        Ok(count)
+    }
+
+    //middle_from 子节点列表转换成中间节点, 第一个子节点的 key 不需要保存
+    fn middle_from(seek: u64, children: Vec<(K, u64)>) -> Node<K, V> {
+        let mut key = Vec::with_capacity(children.len() - 1);
+        let mut key_seek = Vec::with_capacity(children.len());
+        for (i, (k, s)) in children.into_iter().enumerate() {
+            if i > 0 {
+                key.push(Box::new(k));
+            }
+            key_seek.push(s);
+        }
+        Node::new_middle(seek, key, key_seek)
+    }
+
+    /// 把已写入的页持久化到磁盘
+    pub fn flush(&self) -> Result<()> {
+        let _guard = self.read_lock();
+        self.sync_all()
+    }
+
+    /// 按 key 顺序把所有数据重写到新文件后替换原文件
+    /// 丢弃空闲页和无用的额外数据页, 叶子在文件中重新连续, 返回回收的字节数
+    pub fn compact(&self) -> Result<u64> {
+        self.check_writable()?;
+        let _guard = self.write_lock();
+        let old_len = self.store.len();
+        //新文件最后统一 fsync
+        let config = Config { durability: Durability::None, ..self.config.clone() };
+        let mut new = Tree::<K, V, S>::with_store(self.store.scratch()?, config)?;
+        let mut err = None;
+        let iter = LeafIter::new(self)?.map_while(|r| match r {
+            Ok(v) => Some(v),
+            Err(e) => {
+                err = Some(e);
+                None
+            }
+        });
+        new.bulk_load(iter)?;
+        if let Some(e) = err {
+            return Err(e);
+        }
+        new.store.sync()?;
+        Metrics::add(&self.metrics.fsyncs, 1);
+        self.backup_before_truncate();
+        let syncs = self.store.replace(new.store.take())?;
+        self.lsn.reset(self.store.len());
+        Metrics::add(&self.metrics.fsyncs, syncs);
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        }
+        //新文件没有空闲页
+        self.free_pages().clear();
+        *self.defrag.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.mark_synced();
+        Ok(old_len.saturating_sub(self.store.len()))
+    }
+
+    //first_leaf 最左边的叶子
+    pub(crate) fn first_leaf(&self) -> Result<Node<K, V>> {
+        let mut node = self.read_node(0)?;
+        while !node.is_leaf() {
+            let seek = node.key_seek.as_ref()
+                .and_then(|s| s.first().copied())
+                .ok_or_else(|| BPlusError::NodeError("not key seek".to_string()))?;
+            node = self.read_node(seek)?;
+        }
+        Ok(node)
+    }
+
+    //search_path 从 root 查找 key 所在叶子, 返回路径上的 (中间节点, 子节点下标)
+    pub(crate) fn search_path(&self, key: &K) -> Result<(SearchPath<K, V>, Node<K, V>)> {
+        let mut path: Vec<(Node<K, V>, usize)> = vec![];
+        let mut node = self.read_node(0)?;
+        while !node.is_leaf() {
+            let child = Self::child_index(&node, key);
+            let seek = node.key_seek.as_ref()
+                .and_then(|s| s.get(child).copied())
+                .ok_or_else(|| BPlusError::NodeError("not key seek".to_string()))?;
+            path.push((node, child));
+            node = self.read_node(seek)?;
+        }
+        Ok((path, node))
+    }
+
+    //search_leaf 从 root 查找 key 所在叶子
+    pub(crate) fn search_leaf(&self, key: &K) -> Result<Node<K, V>> {
+        let mut node = self.read_node(0)?;
+        while !node.is_leaf() {
+            let child = Self::child_index(&node, key);
+            let seek = node.key_seek.as_ref()
+                .and_then(|s| s.get(child).copied())
+                .ok_or_else(|| BPlusError::NodeError("not key seek".to_string()))?;
+            node = self.read_node(seek)?;
+        }
+        Ok(node)
+    }
+
+    //child_index 中间节点中 key 应该进入的子节点下标
+    fn child_index(node: &Node<K, V>, key: &K) -> usize {
+        match &node.key {
+            Some(keys) => keys.iter().take_while(|k| k.as_ref() <= key).count(),
+            None => 0,
+        }
+    }
+
+    //next_leaves key 所在叶子之后的最多 n 个叶子, 从查找路径向右收集
+    pub(crate) fn next_leaves(&self, key: &K, n: usize) -> Result<Vec<u64>> {
+        let (path, _) = self.search_path(key)?;
+        let mut leaves = vec![];
+        for (depth, (node, child)) in path.iter().rev().enumerate() {
+            if leaves.len() >= n {
+                break;
+            }
+            self.collect_leaves(node, child + 1, depth + 1, n, &mut leaves)?;
+        }
+        Ok(leaves)
+    }
+
+    //collect_leaves 收集 node 从 from 开始的子树中的叶子, depth 为 node 到叶子的层数
+    fn collect_leaves(&self, node: &Node<K, V>, from: usize, depth: usize, n: usize, leaves: &mut Vec<u64>) -> Result<()> {
+        for &child in node.key_seek.iter().flatten().skip(from) {
+            if leaves.len() >= n {
+                break;
+            }
+            if depth == 1 {
+                leaves.push(child);
+            } else {
+                self.collect_leaves(&self.read_node(child)?, 0, depth - 1, n, leaves)?;
+            }
+        }
+        Ok(())
+    }
+
+    //prefetch 批量读取不在缓存中的页放入缓存, 不使用缓存时不需要预读
+    pub(crate) fn prefetch(&self, seeks: &[u64]) -> Result<()> {
+        let Some(cache) = &self.cache else {
+            return Ok(());
+        };
+        let missing: Vec<u64> = {
+            let cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+            seeks.iter().filter(|seek| !cache.contains(*seek)).copied().collect()
+        };
+        if missing.len() < 2 {
+            return Ok(());
+        }
+        let pages = self.store.read_pages(&missing, page_size())?;
+        Metrics::add(&self.metrics.page_reads, missing.len() as u64);
+        Metrics::add(&self.metrics.prefetched_pages, missing.len() as u64);
+        for (seek, page) in missing.into_iter().zip(pages) {
+            self.cache_put(seek, Arc::new(page));
+        }
+        Ok(())
+    }
+
+    //node_from_page 从已经读取的页解码节点, 额外数据页仍然从存储读取
+    fn node_from_page(&self, seek: u64, page: &[u8]) -> Result<Node<K, V>> {
+        let mut node = Node::<K, V>::new_node_from_byte(seek, page)?;
+        if node.need_extra() {
+            self.read_extra(&mut node)?;
+            node.data_decode(page)?;
+        }
+        Ok(node)
+    }
+
+    pub(crate) fn read_node(&self, seek: u64) -> Result<Node<K, V>> {
+        let mut node = self.with_page(seek, |page| Node::<K, V>::new_node_from_byte(seek, page))??;
+        if node.need_extra() {
+            self.read_extra(&mut node)?;
+            self.with_page(seek, |page| node.data_decode(page))??;
+        }
+        Ok(node)
+    }
+
+    //read_extra 读取叶子所有额外数据页
+    fn read_extra(&self, node: &mut Node<K, V>) -> Result<()> {
+        if let Some(extra_data) = node.extra_data.as_mut() {
+            for extra in extra_data.iter_mut().flatten() {
+                let seek = extra.seek;
+                let (mut first, mut next) = self.with_page(seek, |b| ExtraData::data_extra_decode::<K>(b, seek))??;
+                let mut chain = vec![];
+                while next != 0 {
+                    let seek = next;
+                    let (data, n) = self.with_page(seek, |b| ExtraData::data_extra_decode::<K>(b, seek))??;
+                    chain.push(data);
+                    next = n;
+                }
+                first.next = Some(chain);
+                *extra = first;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_node(&self, node: &mut Node<K, V>) -> Result<()> {
+        let data = node.stop()?;
+        self.write_page(node.seek_start, &data)?;
+        node.is_change = false;
+        Ok(())
+    }
+
+    //write_extra 写入额外数据页链表 返回第一页位置
+    fn write_extra(&self, key: &K, data: &[u8]) -> Result<u64> {
+        let chunks: Vec<&[u8]> = data.chunks(extra_capacity::<K>()).collect();
+        let seeks: Vec<u64> = chunks.iter().map(|_| self.allocate()).collect();
+        Metrics::add(&self.metrics.overflow_allocations, seeks.len() as u64);
+        let mut origin_length = data.len() as u64;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let prev = if i > 0 { seeks[i - 1] } else { 0 };
+            let next = seeks.get(i + 1).copied().unwrap_or(0);
+            self.write_page(seeks[i], &ExtraData::data_extra_encode(origin_length, key, chunk, prev, next)?)?;
+            origin_length -= chunk.len() as u64;
+        }
+        Ok(seeks[0])
+    }
+
+    //free_extra 释放额外数据页链表
+    fn free_extra(&self, seek: u64) -> Result<()> {
+        let mut next = seek;
+        while next != 0 {
+            let (_, n) = ExtraData::data_extra_decode::<K>(&self.read_page(next)?, next)?;
+            self.free_page(next)?;
+            next = n;
+        }
+        Ok(())
+    }
+
+    //set_prev 只修改页头的 prev
+    pub(crate) fn set_prev(&self, seek: u64, prev: u64) -> Result<()> {
+        self.write_page(seek + PREV_OFFSET, &prev.to_be_bytes())
+    }
+
+    //set_next 只修改页头的 next
+    pub(crate) fn set_next(&self, seek: u64, next: u64) -> Result<()> {
+        self.write_page(seek + NEXT_OFFSET, &next.to_be_bytes())
+    }
+
+    //free_page 页标记为无效 进入空闲列表
+    pub(crate) fn free_page(&self, seek: u64) -> Result<()> {
+        self.write_page(seek, &vec![0; page_size()])?;
+        self.free_pages().insert(seek);
+        Ok(())
+    }
+
+    //allocate 先使用空闲页, 没有空闲页时文件变长
+    pub(crate) fn allocate(&self) -> u64 {
+        match self.free_pages().pop_first() {
+            Some(seek) => seek,
+            None => self.store.allocate(page_size() as u64),
+        }
+    }
+
+    pub(crate) fn free_pages(&self) -> MutexGuard<'_, BTreeSet<u64>> {
+        self.free.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //scan_free 扫描文件中所有无效页
+    fn scan_free(&self) -> Result<BTreeSet<u64>> {
+        let page = page_size() as u64;
+        let mut free = BTreeSet::new();
+        for seek in (page..self.store.len()).step_by(page as usize) {
+            if self.read_flag(seek)? & VALID != VALID {
+                free.insert(seek);
+            }
+        }
+        Ok(free)
+    }
+
+    //truncate 文件截断到 len
+    pub(crate) fn truncate(&self, len: u64) -> Result<()> {
+        self.backup_before_truncate();
+        self.store.truncate(len)?;
+        self.lsn.truncate(len);
+        self.free_pages().split_off(&len);
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+            let removed: Vec<u64> = cache.iter().map(|(seek, _)| *seek).filter(|seek| *seek >= len).collect();
+            for seek in removed {
+                cache.pop(&seek);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_page(&self, seek: u64) -> Result<Vec<u8>> {
+        Ok(Arc::unwrap_or_clone(self.cached_page(seek)?))
+    }
+
+    //with_page 读取页后调用 f, 不使用缓存时由存储直接提供页数据, mmap 不需要拷贝
+    pub(crate) fn with_page<R>(&self, seek: u64, f: impl FnOnce(&[u8]) -> R) -> Result<R> {
+        if self.cache.is_some() {
+            return Ok(f(&self.cached_page(seek)?));
+        }
+        Metrics::add(&self.metrics.page_reads, 1);
+        self.store.with_page(seek, page_size(), f)
+    }
+
+    //cached_page 先查缓存, 没有时从存储读取并放入缓存
+    fn cached_page(&self, seek: u64) -> Result<Arc<Vec<u8>>> {
+        if let Some(cache) = &self.cache {
+            if let Some(page) = cache.lock().unwrap_or_else(|e| e.into_inner()).get(&seek) {
+                Metrics::add(&self.metrics.cache_hits, 1);
+                return Ok(page.clone());
+            }
+            Metrics::add(&self.metrics.cache_misses, 1);
+        }
+        let mut data = vec![0u8; page_size()];
+        self.store.read_page(seek, &mut data)?;
+        Metrics::add(&self.metrics.page_reads, 1);
+        let data = Arc::new(data);
+        self.cache_put(seek, data.clone());
+        Ok(data)
+    }
+
+    fn is_cached(&self, seek: u64) -> bool {
+        self.cache.as_ref().is_some_and(|cache| cache.lock().unwrap_or_else(|e| e.into_inner()).contains(&seek))
+    }
+
+    //cache_put 页放入缓存 淘汰的页通知 observer
+    fn cache_put(&self, seek: u64, data: Arc<Vec<u8>>) {
+        if let Some(cache) = &self.cache {
+            let evicted = cache.lock().unwrap_or_else(|e| e.into_inner()).push(seek, data);
+            if let Some((evicted, _)) = evicted.filter(|(k, _)| *k != seek) {
+                self.notify(|o| o.on_evict(evicted));
+            }
+        }
+    }
+
+    //read_flag 只读取页的 flag
+    pub(crate) fn read_flag(&self, seek: u64) -> Result<u8> {
+        if let Some(cache) = &self.cache {
+            if let Some(page) = cache.lock().unwrap_or_else(|e| e.into_inner()).peek(&seek) {
+                return Ok(page[0]);
+            }
+        }
+        let mut flag = [0u8; 1];
+        self.store.read_page(seek, &mut flag)?;
+        Ok(flag[0])
+    }
+
+    pub(crate) fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+        self.backup_before_write(seek);
+        self.store.write_page(seek, data)?;
+        self.lsn.stamp(seek);
+        Metrics::add(&self.metrics.page_writes, 1);
+        let page = page_size() as u64;
+        if data.len() == page_size() && seek.is_multiple_of(page) {
+            self.cache_put(seek, Arc::new(data.to_vec()));
+        } else if let Some(cache) = &self.cache {
+            //只修改页的一部分 同步修改缓存
+            let offset = (seek % page) as usize;
+            if let Some(cached) = cache.lock().unwrap_or_else(|e| e.into_inner()).peek_mut(&(seek - offset as u64)) {
+                Arc::make_mut(cached)[offset..offset + data.len()].copy_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+
+    //check_writable 只读打开时拒绝修改
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.store.is_read_only() {
+            return Err(BPlusError::ReadOnly().into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_lock(&self) -> RwLockReadGuard<'_, ()> {
+        self.lock.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //write_lock 等待和持有写锁期间计入正在进行的修改, group commit 据此决定是否等待
+    pub(crate) fn write_lock(&self) -> WriteGuard<'_> {
+        self.syncer.begin_write();
+        let guard = self.lock.write().unwrap_or_else(|e| e.into_inner());
+        self.version.fetch_add(1, Ordering::SeqCst);
+        WriteGuard {
+            _guard: guard,
+            syncer: &self.syncer,
+        }
+    }
+
+    //read_ahead_worker 顺序遍历共用的后台预读线程
+    fn read_ahead_worker(&self) -> &ReadAhead {
+        self.readahead.get_or_init(|| ReadAhead::spawn(self.store.shared(), self.lock.clone(), self.version.clone(), self.metrics.clone()))
+    }
+}
+
+pub(crate) struct WriteGuard<'a> {
+    _guard: RwLockWriteGuard<'a, ()>,
+    syncer: &'a Syncer,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.syncer.end_write();
+    }
+}
+
+/// 范围遍历 由 Tree::range 创建
+pub struct Range<'a, K, V, S: PageStore = FileStore> {
+    tree: &'a Tree<K, V, S>,
+    node: Option<Node<K, V>>,
+    index: usize,
+    end: Bound<K>,
+    readahead: usize,
+    //已经沿 next 连续读取的叶子数
+    sequential: usize,
+    //已经提交预读的后续叶子, 只作为提示, 遍历仍然沿 next
+    ahead: VecDeque<u64>,
+    //后台预读的结果
+    pending: Option<Channel>,
+    //已经读到的叶子页 (修改序号, 页)
+    staged: HashMap<u64, (u64, Arc<Vec<u8>>)>,
+}
+
+impl<K, V, S> Iterator for Range<'_, K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.node.as_ref()?;
+            if let (Some(key), Some(value)) = (&node.key, &node.value) {
+                if self.index < key.len() {
+                    let k = key[self.index].as_ref();
+                    let in_range = match &self.end {
+                        Bound::Included(end) => k <= end,
+                        Bound::Excluded(end) => k < end,
+                        Bound::Unbounded => true,
+                    };
+                    if !in_range {
+                        self.node = None;
+                        return None;
+                    }
+                    self.index += 1;
+                    return Some(Ok((k.clone(), value[self.index - 1].as_ref().clone())));
+                }
+            }
+            let next = node.next;
+            self.index = 0;
+            if next == 0 {
+                self.node = None;
+                return None;
+            }
+            let _guard = self.tree.read_lock();
+            let last = node.key.as_ref().and_then(|k| k.last()).map(|k| k.as_ref().clone());
+            match self.read_ahead(next, last).and_then(|_| self.read_leaf(next)) {
+                Ok(n) => self.node = Some(n),
+                Err(e) => {
+                    self.node = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl<K, V, S> Range<'_, K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    //read_ahead 进入 next 叶子前调用, 顺序遍历时保持前面有 readahead 个叶子已经提交预读
+    //剩余不到一半时把下一段交给后台线程批量读取, 遍历线程不等待
+    fn read_ahead(&mut self, next: u64, last: Option<K>) -> Result<()> {
+        self.sequential += 1;
+        while self.ahead.front().is_some_and(|seek| *seek != next) {
+            self.ahead.pop_front();
+        }
+        self.ahead.pop_front();
+        if self.readahead == 0 || self.sequential < READAHEAD_TRIGGER || self.ahead.len() > self.readahead / 2 {
+            return Ok(());
+        }
+        let Some(last) = last else {
+            return Ok(());
+        };
+        let leaves = self.tree.next_leaves(&last, self.readahead + 1)?;
+        //树结构已经变化, 放弃预读
+        if leaves.first() != Some(&next) {
+            self.ahead.clear();
+            return Ok(());
+        }
+        //已经在缓存中和已经提交的页不会重复读取
+        let missing: Vec<u64> = leaves.iter().skip(1)
+            .filter(|seek| !self.ahead.contains(seek) && !self.staged.contains_key(seek) && !self.tree.is_cached(**seek))
+            .copied().collect();
+        if !missing.is_empty() {
+            let (tx, _) = self.pending.get_or_insert_with(mpsc::channel);
+            self.tree.read_ahead_worker().request(missing, tx.clone());
+        }
+        self.ahead = leaves.into_iter().skip(1).collect();
+        Ok(())
+    }
+
+    //read_leaf 优先使用已经完成的预读, 预读之后树被修改时重新读取
+    fn read_leaf(&mut self, seek: u64) -> Result<Node<K, V>> {
+        let version = self.tree.version.load(Ordering::SeqCst);
+        if let Some((_, rx)) = &self.pending {
+            //预读失败时忽略, 遍历时重新读取并返回错误
+            for batch in rx.try_iter().flatten() {
+                self.staged.extend(batch.pages.into_iter().map(|(seek, page)| (seek, (batch.version, page))));
+            }
+        }
+        self.staged.retain(|_, (v, _)| *v == version);
+        if let Some((_, page)) = self.staged.remove(&seek) {
+            let node = self.tree.node_from_page(seek, &page)?;
+            self.tree.cache_put(seek, page);
+            return Ok(node);
+        }
+        self.tree.read_node(seek)
+    }
+}
+
+//LeafIter 沿叶子 next 顺序遍历所有数据
+pub(crate) struct LeafIter<'a, K, V, S: PageStore> {
+    tree: &'a Tree<K, V, S>,
+    node: Option<Node<K, V>>,
+    index: usize,
+}
+
+impl<'a, K, V, S> LeafIter<'a, K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    pub(crate) fn new(tree: &'a Tree<K, V, S>) -> Result<Self> {
+        Ok(LeafIter {
+            tree,
+            node: Some(tree.first_leaf()?),
+            index: 0,
+        })
+    }
+}
+
+impl<K, V, S> Iterator for LeafIter<'_, K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.node.as_ref()?;
+            if let (Some(key), Some(value)) = (&node.key, &node.value) {
+                if self.index < key.len() {
+                    self.index += 1;
+                    return Some(Ok((key[self.index - 1].as_ref().clone(), value[self.index - 1].as_ref().clone())));
+                }
+            }
+            let next = node.next;
+            self.index = 0;
+            if next == 0 {
+                self.node = None;
+                return None;
+            }
+            match self.tree.read_node(next) {
+                Ok(n) => self.node = Some(n),
+                Err(e) => {
+                    self.node = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::ops::Bound;
+    use std::sync::mpsc;
+    use std::sync::{Condvar, Mutex};
+    use anyhow::Result;
+    use crate::temp_path;
+    use crate::node::node::{leaf_entry_size, middle_max_key, page_size, BPlusError, NODE_FIXED_SIZE};
+    use crate::store::{MemoryStore, PageStore};
+    use crate::tree::{Config, ScanOptions, SplitPolicy, Tree};
+    use super::READAHEAD_TRIGGER;
+    use crate::ValueTest;
+
+    //settle 等待后台预读线程处理完已经提交的请求, 线程按顺序处理请求
+    fn settle<S: PageStore>(tree: &Tree<u64, u64, S>) {
+        let (tx, rx) = mpsc::channel();
+        tree.read_ahead_worker().request(vec![], tx);
+        rx.recv().unwrap().unwrap();
+    }
+
+    //Gated 批量读取在打开之前一直阻塞的存储
+    #[derive(Default)]
+    struct Gated {
+        inner: MemoryStore,
+        open: Mutex<bool>,
+        cond: Condvar,
+    }
+
+    impl Gated {
+        fn open(&self) {
+            *self.open.lock().unwrap() = true;
+            self.cond.notify_all();
+        }
+    }
+
+    impl PageStore for Gated {
+        fn read_page(&self, seek: u64, buf: &mut [u8]) -> Result<()> {
+            self.inner.read_page(seek, buf)
+        }
+
+        fn read_pages(&self, seeks: &[u64], len: usize) -> Result<Vec<Vec<u8>>> {
+            let mut open = self.open.lock().unwrap();
+            while !*open {
+                open = self.cond.wait(open).unwrap();
+            }
+            self.inner.read_pages(seeks, len)
+        }
+
+        fn write_page(&self, seek: u64, data: &[u8]) -> Result<()> {
+            self.inner.write_page(seek, data)
+        }
+
+        fn allocate(&self, page_size: u64) -> u64 {
+            self.inner.allocate(page_size)
+        }
+
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn len(&self) -> u64 {
+            self.inner.len()
+        }
+
+        fn truncate(&self, len: u64) -> Result<()> {
+            self.inner.truncate(len)
+        }
+
+        fn scratch(&self) -> Result<Self> {
+            Ok(Gated::default())
+        }
+
+        fn replace(&self, other: Self) -> Result<u64> {
+            self.inner.replace(other.inner)
+        }
+    }
+
+    fn leaf_chain<S: PageStore>(tree: &Tree<u64, u64, S>) -> Vec<u64> {
+        let mut node = tree.read_node(0).unwrap();
+        while !node.is_leaf() {
+            node = tree.read_node(node.key_seek.as_ref().unwrap()[0]).unwrap();
+        }
+        let mut keys = vec![];
+        loop {
+            keys.extend(node.key.as_ref().unwrap().iter().map(|k| **k));
+            if node.next == 0 {
+                break;
+            }
+            let next = tree.read_node(node.next).unwrap();
+            assert_eq!(next.prev, node.seek_start);
+            node = next;
+        }
+        keys
+    }
+
+    #[test]
+    fn bulk_load() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory().unwrap();
+        assert_eq!(tree.bulk_load((0..100000u64).map(|i| (i * 2, i))).unwrap(), 100000);
+        for i in (0..100000u64).step_by(997) {
+            assert_eq!(tree.get(&(i * 2)).unwrap(), Some(i));
+            assert_eq!(tree.get(&(i * 2 + 1)).unwrap(), None);
+        }
+        assert_eq!(leaf_chain(&tree), (0..100000u64).map(|i| i * 2).collect::<Vec<u64>>());
+        //非空树不能导入
+        assert!(tree.bulk_load((0..1u64).map(|i| (i, i))).is_err());
+
+        //一个叶子放得下时直接写入 root, 不分配其他页
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory().unwrap();
+        assert_eq!(tree.bulk_load((0..10u64).map(|i| (i, i))).unwrap(), 10);
+        assert_eq!(tree.store.len(), page_size() as u64);
+        assert!(tree.read_node(0).unwrap().is_leaf());
+        assert_eq!(tree.stats().unwrap().free_pages, 0);
+        assert!(tree.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn bulk_load_fill_factor() {
+        let dir = tempfile::tempdir().unwrap();
+        let half = Tree::<u64, u64>::open_with_config(&temp_path(&dir, "bulk_load_fill.db"), Config { fill_factor: 0.5, ..Default::default() }).unwrap();
+        half.bulk_load((0..20000u64).map(|i| (i, i))).unwrap();
+        let full = Tree::<u64, u64>::open(&temp_path(&dir, "bulk_load_full.db")).unwrap();
+        full.bulk_load((0..20000u64).map(|i| (i, i))).unwrap();
+        let half_len = fs::metadata(temp_path(&dir, "bulk_load_fill.db")).unwrap().len();
+        let full_len = fs::metadata(temp_path(&dir, "bulk_load_full.db")).unwrap().len();
+        assert!(half_len > full_len * 3 / 2);
+        assert_eq!(half.get(&19999).unwrap(), Some(19999));
+        assert_eq!(leaf_chain(&half).len(), 20000);
+    }
+
+    #[test]
+    fn bulk_load_levels() {
+        //极低填充率 每个叶子几条数据 产生多层中间节点
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { fill_factor: 0.01, ..Default::default() }).unwrap();
+        tree.bulk_load((0..3000u64).map(|i| (i, i + 1))).unwrap();
+        let mut height = 1;
+        let mut node = tree.read_node(0).unwrap();
+        while !node.is_leaf() {
+            node = tree.read_node(node.key_seek.as_ref().unwrap()[0]).unwrap();
+            height += 1;
+        }
+        assert!(height >= 4);
+        for i in 0..3000u64 {
+            assert_eq!(tree.get(&i).unwrap(), Some(i + 1));
+        }
+        assert_eq!(leaf_chain(&tree), (0..3000u64).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn bulk_load_unsorted() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory().unwrap();
+        assert!(tree.bulk_load(vec![(1, 1), (3, 3), (2, 2)].into_iter()).is_err());
+    }
+
+    #[test]
+    fn bulk_load_extra_data() {
+        let tree = Tree::<u64, ValueTest, MemoryStore>::in_memory().unwrap();
+        let value = |i: u64| ValueTest {
+            id: i as u32,
+            data: "x".repeat((i as usize * 7919) % 40000),
+        };
+        tree.bulk_load((0..50u64).map(|i| (i, value(i)))).unwrap();
+        for i in 0..50u64 {
+            assert_eq!(tree.get(&i).unwrap(), Some(value(i)));
+        }
+    }
+
+    fn leaf_count<S: PageStore>(tree: &Tree<u64, u64, S>) -> usize {
+        let mut node = tree.read_node(0).unwrap();
+        while !node.is_leaf() {
+            node = tree.read_node(node.key_seek.as_ref().unwrap()[0]).unwrap();
+        }
+        let mut count = 1;
+        while node.next != 0 {
+            node = tree.read_node(node.next).unwrap();
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn insert() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory().unwrap();
+        //伪随机顺序插入
+        let keys: Vec<u64> = (0..2000u64).map(|i| (i * 7919) % 2000).collect();
+        for k in keys.iter() {
+            assert_eq!(tree.insert(*k, k + 1).unwrap(), None);
+        }
+        assert_eq!(tree.insert(5, 0).unwrap(), Some(6));
+        assert_eq!(tree.get(&5).unwrap(), Some(0));
+        for k in (0..2000u64).filter(|k| *k != 5) {
+            assert_eq!(tree.get(&k).unwrap(), Some(k + 1));
+        }
+        assert_eq!(leaf_chain(&tree), (0..2000u64).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn insert_extra_data() {
+        let tree = Tree::<u64, ValueTest, MemoryStore>::in_memory().unwrap();
+        let value = |i: u64, n: usize| ValueTest {
+            id: i as u32,
+            data: "y".repeat(n),
+        };
+        for i in 0..200u64 {
+            tree.insert(i, value(i, (i as usize * 131) % 20000)).unwrap();
+        }
+        //额外数据页替换成普通数据
+        assert_eq!(tree.insert(199, value(199, 3)).unwrap(), Some(value(199, (199 * 131) % 20000)));
+        for i in 0..199u64 {
+            assert_eq!(tree.get(&i).unwrap(), Some(value(i, (i as usize * 131) % 20000)));
+        }
+        assert_eq!(tree.get(&199).unwrap(), Some(value(199, 3)));
+    }
+
+    #[test]
+    fn split_policy() {
+        let count = |policy: SplitPolicy| {
+            let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { split_policy: policy, ..Default::default() }).unwrap();
+            for i in 0..2500u64 {
+                tree.insert(i, i).unwrap();
+            }
+            assert_eq!(leaf_chain(&tree), (0..2500u64).collect::<Vec<u64>>());
+            leaf_count(&tree)
+        };
+        let even = count(SplitPolicy::Even);
+        let right = count(SplitPolicy::RightBiased);
+        let auto = count(SplitPolicy::Auto);
+        //顺序插入 对半拆分的叶子只有一半数据
+        assert!(right * 4 / 3 < even);
+        assert!(auto <= right);
+        assert!(auto * 5 / 3 <= even);
+    }
+
+    #[test]
+    fn insert_middle_split() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory().unwrap();
+        //导入后 root 刚好写满, 再插入会拆分 root
+        let per_leaf = ((page_size() - NODE_FIXED_SIZE) / leaf_entry_size::<u64>(8)) as u64;
+        let count = (middle_max_key::<u64>() as u64 + 1) * per_leaf;
+        tree.bulk_load((0..count).map(|i| (i * 2, i))).unwrap();
+        assert_eq!(tree.read_node(0).unwrap().key_count as usize, middle_max_key::<u64>());
+        for i in (0..count).step_by(997) {
+            tree.insert(i * 2 + 1, i).unwrap();
+        }
+        let root = tree.read_node(0).unwrap();
+        assert!(root.key_count < 10);
+        assert!(!tree.read_node(root.key_seek.as_ref().unwrap()[0]).unwrap().is_leaf());
+        for i in (0..count).step_by(997) {
+            assert_eq!(tree.get(&(i * 2)).unwrap(), Some(i));
+            assert_eq!(tree.get(&(i * 2 + 1)).unwrap(), Some(i));
+        }
+        assert_eq!(leaf_chain(&tree).len() as u64, count + count.div_ceil(997));
+    }
+
+    #[test]
+    fn compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = Tree::<u64, ValueTest>::open(&temp_path(&dir, "compact.db")).unwrap();
+        let value = |i: u64, n: usize| ValueTest {
+            id: i as u32,
+            data: "c".repeat(n),
+        };
+        for i in 0..300u64 {
+            tree.insert(i, value(i, 20000)).unwrap();
+        }
+        //额外数据页全部变成空闲页
+        for i in 0..300u64 {
+            tree.insert(i, value(i, 10)).unwrap();
+        }
+        let before = fs::metadata(temp_path(&dir, "compact.db")).unwrap().len();
+        let reclaimed = tree.compact().unwrap();
+        let after = fs::metadata(temp_path(&dir, "compact.db")).unwrap().len();
+        assert_eq!(before - after, reclaimed);
+        assert!(after < before / 100);
+        for i in 0..300u64 {
+            assert_eq!(tree.get(&i).unwrap(), Some(value(i, 10)));
+        }
+        //叶子在文件中连续
+        let mut node = tree.first_leaf().unwrap();
+        while node.next != 0 {
+            assert_eq!(node.next, node.seek_start + page_size() as u64);
+            node = tree.read_node(node.next).unwrap();
+        }
+        tree.insert(300, value(300, 10)).unwrap();
+        assert_eq!(tree.get(&300).unwrap(), Some(value(300, 10)));
+        assert!(!std::path::Path::new(&temp_path(&dir, "compact.db.compact")).exists());
+    }
+
+    #[test]
+    fn remove() {
+        let tree = Tree::<u64, ValueTest, MemoryStore>::in_memory_with_config(Config { fill_factor: 0.05, ..Default::default() }).unwrap();
+        let value = |i: u64| ValueTest {
+            id: i as u32,
+            data: "r".repeat(if i.is_multiple_of(7) { 20000 } else { 10 }),
+        };
+        tree.bulk_load((0..1000u64).map(|i| (i, value(i)))).unwrap();
+        assert_eq!(tree.remove(&1000).unwrap(), None);
+        //删除一半 部分叶子被删空
+        for i in (0..1000u64).filter(|i| i % 4 != 0 || *i < 500) {
+            assert_eq!(tree.remove(&i).unwrap(), Some(value(i)));
+        }
+        let report = tree.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        for i in 0..1000u64 {
+            let expected = if i % 4 == 0 && i >= 500 { Some(value(i)) } else { None };
+            assert_eq!(tree.get(&i).unwrap(), expected);
+        }
+        //全部删除后 root 变回空叶子
+        for i in (500..1000u64).step_by(4) {
+            tree.remove(&i).unwrap();
+        }
+        let root = tree.read_node(0).unwrap();
+        assert!(root.is_leaf());
+        assert_eq!(root.key_count, 0);
+        assert!(tree.verify().unwrap().is_ok());
+        tree.insert(1, value(1)).unwrap();
+        assert_eq!(tree.get(&1).unwrap(), Some(value(1)));
+    }
+
+    #[test]
+    fn merge_leaf() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { fill_factor: 0.5, ..Default::default() }).unwrap();
+        tree.bulk_load((0..4000u64).map(|i| (i, i))).unwrap();
+        let leaves = leaf_count(&tree);
+        //每个叶子只剩 1/10 的数据, 相邻叶子合并
+        for i in (0..4000u64).filter(|i| i % 10 != 0) {
+            tree.remove(&i).unwrap();
+        }
+        assert!(tree.metrics().merges > 0);
+        assert!(leaf_count(&tree) < leaves / 2, "{} {}", leaf_count(&tree), leaves);
+        assert_eq!(leaf_chain(&tree), (0..4000u64).step_by(10).collect::<Vec<_>>());
+        assert!(tree.verify().unwrap().is_ok());
+
+        //兄弟叶子已满, 合并后超过一页时不合并
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { fill_factor: 1.0, ..Default::default() }).unwrap();
+        tree.bulk_load((0..4000u64).map(|i| (i, i))).unwrap();
+        let leaves = leaf_count(&tree);
+        let first = tree.first_leaf().unwrap().key.unwrap().len() as u64;
+        for i in 1..first {
+            tree.remove(&i).unwrap();
+        }
+        assert_eq!(tree.metrics().merges, 0);
+        assert_eq!(leaf_count(&tree), leaves);
+        assert_eq!(tree.first_leaf().unwrap().key_count, 1);
+        assert!(tree.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn free_pages() {
+        let tree = Tree::<u64, String, MemoryStore>::in_memory().unwrap();
+        tree.insert(1, "a".repeat(40000)).unwrap();
+        tree.insert(1, "b".repeat(40000)).unwrap();
+        //旧的额外数据页被下一次覆盖使用
+        let len = tree.store.len();
+        for i in 0..20u8 {
+            tree.insert(1, ((b'c' + i) as char).to_string().repeat(40000)).unwrap();
+            assert_eq!(tree.store.len(), len);
+        }
+        assert_eq!(tree.get(&1).unwrap(), Some("v".repeat(40000)));
+        assert!(tree.verify().unwrap().is_ok());
+
+        //重新打开时从无效页重建空闲列表
+        let dir = tempfile::tempdir().unwrap();
+        let path = temp_path(&dir, "free_pages.db");
+        {
+            let tree = Tree::<u64, String>::open(&path).unwrap();
+            tree.insert(1, "a".repeat(40000)).unwrap();
+            tree.insert(1, "b".repeat(40000)).unwrap();
+        }
+        let tree = Tree::<u64, String>::open(&path).unwrap();
+        let len = tree.store.len();
+        assert_eq!(tree.free_pages().len() as u64, 3);
+        tree.insert(1, "c".repeat(40000)).unwrap();
+        assert_eq!(tree.store.len(), len);
+        assert!(tree.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn page_cache() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { fill_factor: 0.05, cache_pages: 2, ..Default::default() }).unwrap();
+        tree.bulk_load((0..1000u64).map(|i| (i, i))).unwrap();
+        let first = tree.first_leaf().unwrap();
+        let (a, b) = (first.seek_start, first.next);
+        let c = tree.read_node(b).unwrap().next;
+        tree.cache.as_ref().unwrap().lock().unwrap().clear();
+        let before = tree.metrics();
+
+        //最近最少使用的页被淘汰
+        for seek in [a, b, a, c] {
+            tree.read_page(seek).unwrap();
+        }
+        let metrics = tree.metrics();
+        assert_eq!(metrics.cache_misses - before.cache_misses, 3);
+        assert_eq!(metrics.cache_hits - before.cache_hits, 1);
+        assert_eq!(metrics.page_reads - before.page_reads, 3);
+        assert!(tree.is_cached(a) && tree.is_cached(c) && !tree.is_cached(b));
+
+        //只修改页头时缓存同步修改
+        tree.set_next(a, 12345).unwrap();
+        let mut stored = vec![0u8; page_size()];
+        tree.store.read_page(a, &mut stored).unwrap();
+        assert_eq!(tree.read_page(a).unwrap(), stored);
+        assert_eq!(tree.read_node(a).unwrap().next, 12345);
+        tree.set_next(a, b).unwrap();
+        assert!(tree.verify().unwrap().is_ok());
+
+        //不使用缓存时每次都从存储读取
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { cache_pages: 0, ..Default::default() }).unwrap();
+        tree.insert(1, 1).unwrap();
+        for _ in 0..3 {
+            assert_eq!(tree.get(&1).unwrap(), Some(1));
+        }
+        let metrics = tree.metrics();
+        assert_eq!(metrics.cache_hits + metrics.cache_misses, 0);
+        assert!(metrics.page_reads >= 3);
+    }
+
+    #[test]
+    fn range() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { fill_factor: 0.05, ..Default::default() }).unwrap();
+        tree.bulk_load((0..1000u64).map(|i| (i * 2, i))).unwrap();
+        let keys = |r: Vec<(u64, u64)>| r.into_iter().map(|(k, _)| k).collect::<Vec<u64>>();
+        let all = tree.range(..).unwrap().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(all.len(), 1000);
+        assert_eq!(all[10], (20, 10));
+        assert_eq!(keys(tree.range(100..110).unwrap().collect::<Result<_>>().unwrap()), vec![100, 102, 104, 106, 108]);
+        assert_eq!(keys(tree.range(101..=110).unwrap().collect::<Result<_>>().unwrap()), vec![102, 104, 106, 108, 110]);
+        assert_eq!(keys(tree.range((Bound::Excluded(100), Bound::Included(104))).unwrap().collect::<Result<_>>().unwrap()), vec![102, 104]);
+        assert_eq!(tree.range(1990..).unwrap().count(), 5);
+        assert_eq!(tree.range(5000..).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn file_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = Tree::<u64, u64>::open(&temp_path(&dir, "file_lock.db")).unwrap();
+        tree.insert(1, 10).unwrap();
+        let locked = |r: Result<Tree<u64, u64>>| matches!(r.err().and_then(|e| e.downcast::<BPlusError>().ok()), Some(BPlusError::Locked(_)));
+        assert!(locked(Tree::<u64, u64>::open(&temp_path(&dir, "file_lock.db"))));
+        assert!(locked(Tree::<u64, u64>::open_read_only(&temp_path(&dir, "file_lock.db"))));
+        //compact 替换文件后仍然持有锁
+        tree.compact().unwrap();
+        assert!(locked(Tree::<u64, u64>::open(&temp_path(&dir, "file_lock.db"))));
+        drop(tree);
+
+        let reader = Tree::<u64, u64>::open_read_only(&temp_path(&dir, "file_lock.db")).unwrap();
+        let other = Tree::<u64, u64>::open_read_only(&temp_path(&dir, "file_lock.db")).unwrap();
+        assert_eq!(reader.get(&1).unwrap(), Some(10));
+        assert_eq!(other.range(..).unwrap().count(), 1);
+        assert!(locked(Tree::<u64, u64>::open(&temp_path(&dir, "file_lock.db"))));
+        let read_only = |r: Result<()>| matches!(r.err().and_then(|e| e.downcast::<BPlusError>().ok()), Some(BPlusError::ReadOnly()));
+        assert!(read_only(reader.insert(2, 20).map(|_| ())));
+        assert!(read_only(reader.remove(&1).map(|_| ())));
+        assert!(read_only(reader.compact().map(|_| ())));
+        assert!(read_only(reader.defrag_step(1).map(|_| ())));
+        assert!(reader.verify().unwrap().is_ok());
+        drop(reader);
+        drop(other);
+        assert!(Tree::<u64, u64>::open(&temp_path(&dir, "file_lock.db")).is_ok());
+        assert!(Tree::<u64, u64>::open_read_only(&temp_path(&dir, "file_lock_missing.db")).is_err());
+    }
+
+    #[test]
+    fn get_many() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { fill_factor: 0.02, ..Default::default() }).unwrap();
+        tree.bulk_load((0..5000u64).map(|i| (i * 2, i))).unwrap();
+        assert!(tree.stats().unwrap().height >= 3);
+        let keys = vec![9998, 0, 3, 4000, 4000, 20000, 7];
+        assert_eq!(tree.get_many(&keys).unwrap(), vec![Some(4999), Some(0), None, Some(2000), Some(2000), None, None]);
+        assert!(tree.get_many(&[]).unwrap().is_empty());
+
+    }
+
+    #[test]
+    fn range_readahead() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { fill_factor: 0.02, readahead_leaves: 0, ..Default::default() };
+        let tree = Tree::<u64, u64>::open_with_config(&temp_path(&dir, "range_readahead.db"), config.clone()).unwrap();
+        tree.bulk_load((0..5000u64).map(|i| (i, i))).unwrap();
+        let leaves = tree.stats().unwrap().leaf_pages;
+        drop(tree);
+
+        //不预读 每个叶子一次读取
+        let tree = Tree::<u64, u64>::open_with_config(&temp_path(&dir, "range_readahead.db"), config.clone()).unwrap();
+        assert_eq!(tree.range(..).unwrap().count(), 5000);
+        assert_eq!(tree.metrics().prefetched_pages, 0);
+        assert!(tree.metrics().cache_misses >= leaves);
+        drop(tree);
+
+        //单次遍历指定预读窗口, 每条数据后等待后台预读完成, 之后的叶子都不需要遍历线程读取
+        for cache_pages in [1024, 0] {
+            let tree = Tree::<u64, u64>::open_with_config(&temp_path(&dir, "range_readahead.db"), Config { cache_pages, ..config.clone() }).unwrap();
+            let mut scanned = vec![];
+            for entry in tree.range_with(.., ScanOptions { readahead: Some(32) }).unwrap() {
+                scanned.push(entry.unwrap().0);
+                settle(&tree);
+            }
+            assert_eq!(scanned, (0..5000).collect::<Vec<_>>());
+            let metrics = tree.metrics();
+            assert!(metrics.prefetched_pages >= leaves - READAHEAD_TRIGGER as u64 - 1, "{} {}", metrics.prefetched_pages, leaves);
+            if cache_pages > 0 {
+                assert!(metrics.cache_misses < leaves / 4, "{} {}", metrics.cache_misses, leaves);
+            } else {
+                //叶子只读一次, 其余是查找后续叶子时读取的中间节点
+                assert!(metrics.page_reads - metrics.prefetched_pages < leaves / 2, "{} {}", metrics.page_reads, leaves);
+            }
+        }
+
+        //短遍历不触发预读
+        let tree = Tree::<u64, u64>::open_with_config(&temp_path(&dir, "range_readahead.db"), Config { readahead_leaves: 8, ..config }).unwrap();
+        let first = tree.first_leaf().unwrap().key.unwrap().len() as u64;
+        assert_eq!(tree.range(..first + 1).unwrap().count() as u64, first + 1);
+        assert_eq!(tree.metrics().prefetched_pages, 0);
+    }
+
+    #[test]
+    fn range_readahead_async() {
+        let config = Config { fill_factor: 0.02, readahead_leaves: 8, cache_pages: 0, ..Default::default() };
+        let tree = Tree::<u64, u64, Gated>::with_store(Gated::default(), config).unwrap();
+        tree.bulk_load((0..5000u64).map(|i| (i, i))).unwrap();
+        //后台预读一直阻塞, 遍历不等待预读结果
+        assert_eq!(tree.range(..).unwrap().count(), 5000);
+        assert_eq!(tree.metrics().prefetched_pages, 0);
+        tree.store.open();
+        settle(&tree);
+        assert!(tree.metrics().prefetched_pages > 0);
+        //修改之后遍历不使用修改前预读的页
+        let mut range = tree.range(..).unwrap();
+        let mut scanned = vec![];
+        for _ in 0..1000 {
+            scanned.push(range.next().unwrap().unwrap().0);
+        }
+        settle(&tree);
+        tree.insert(100_000, 0).unwrap();
+        tree.remove(&3000).unwrap();
+        scanned.extend(range.map(|r| r.unwrap().0));
+        assert_eq!(scanned, (0..5000).filter(|i| *i != 3000).chain([100_000]).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Display};
+use anyhow::Result;
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::node::node::{data_max_len, page_size, ExtraData, Node, EXTRA_DATA, LEAF, MIDDLE_NODE, NEXT_OFFSET, PREV_OFFSET, ROOT, VALID};
+use crate::store::PageStore;
+use crate::tree::Tree;
+
+/// 结构检查发现的问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    // 页无法解析
+    Corrupt { seek: u64, reason: String },
+    // 页内 key 不是严格递增
+    KeyOrder { seek: u64, index: usize },
+    // key 超出父节点分隔 key 的范围
+    SeparatorBound { seek: u64, parent: u64, index: usize },
+    // 相邻叶子 key 顺序错误
+    SiblingOrder { left: u64, right: u64 },
+    // 叶子 prev next 指向错误
+    SiblingLink { seek: u64, field: &'static str, expected: u64, found: u64 },
+    // 叶子深度不一致
+    LeafDepth { seek: u64, depth: u64, expected: u64 },
+    // 页头计数错误
+    CountMismatch { seek: u64, key_count: u64, data_count: u64 },
+    // 额外数据页链表错误
+    ExtraChain { seek: u64, owner: u64, reason: String },
+    // 页被多次引用
+    MultipleReference { seek: u64 },
+    // 有效页不可达
+    Unreachable { seek: u64 },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Corrupt { seek, reason } => write!(f, "page {}: corrupt: {}", seek, reason),
+            Violation::KeyOrder { seek, index } => write!(f, "page {}: key {} out of order", seek, index),
+            Violation::SeparatorBound { seek, parent, index } => write!(f, "page {}: key {} out of parent {} bounds", seek, index, parent),
+            Violation::SiblingOrder { left, right } => write!(f, "leaf {} and leaf {} out of order", left, right),
+            Violation::SiblingLink { seek, field, expected, found } => write!(f, "leaf {}: {} is {}, expected {}", seek, field, found, expected),
+            Violation::LeafDepth { seek, depth, expected } => write!(f, "leaf {}: depth {}, expected {}", seek, depth, expected),
+            Violation::CountMismatch { seek, key_count, data_count } => write!(f, "page {}: key count {} data count {}", seek, key_count, data_count),
+            Violation::ExtraChain { seek, owner, reason } => write!(f, "extra page {} of leaf {}: {}", seek, owner, reason),
+            Violation::MultipleReference { seek } => write!(f, "page {}: referenced more than once", seek),
+            Violation::Unreachable { seek } => write!(f, "page {}: valid but unreachable", seek),
+        }
+    }
+}
+
+/// 结构检查结果
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    // 可达的页数 包含额外数据页
+    pub pages: u64,
+    pub leaves: u64,
+    // 树高 只有 root 时为 1
+    pub depth: u64,
+    pub violations: Vec<Violation>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+//检查过程中的叶子信息 (位置, prev, next, 第一个 key, 最后一个 key)
+type LeafInfo<K> = (u64, u64, u64, Option<K>, Option<K>);
+
+struct Verifier<'a, K, V, S: PageStore> {
+    tree: &'a Tree<K, V, S>,
+    end: u64,
+    visited: HashSet<u64>,
+    leaves: Vec<LeafInfo<K>>,
+    depth: Option<u64>,
+    violations: Vec<Violation>,
+}
+
+impl<K, V, S> Tree<K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    /// 检查树结构, 只读不修改, 发现的问题记录在报告中
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let _guard = self.read_lock();
+        let mut verifier = Verifier {
+            tree: self,
+            end: self.store.len(),
+            visited: HashSet::new(),
+            leaves: vec![],
+            depth: None,
+            violations: vec![],
+        };
+        verifier.node(0, 0, None, None, 1)?;
+        verifier.siblings();
+        verifier.unreachable()?;
+        Ok(VerifyReport {
+            pages: verifier.visited.len() as u64,
+            leaves: verifier.leaves.len() as u64,
+            depth: verifier.depth.unwrap_or(0),
+            violations: verifier.violations,
+        })
+    }
+}
+
+impl<K, V, S> Verifier<'_, K, V, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    V: EncodableU8 + DecodableU8 + Debug + Clone + Send + Sync,
+    S: PageStore
+{
+    //page 读取并检查被引用的页, 返回 None 表示已记录问题
+    fn page(&mut self, seek: u64) -> Result<Option<Vec<u8>>> {
+        if !seek.is_multiple_of(page_size() as u64) || seek >= self.end {
+            self.violations.push(Violation::Corrupt { seek, reason: "out of file".to_string() });
+            return Ok(None);
+        }
+        if !self.visited.insert(seek) {
+            self.violations.push(Violation::MultipleReference { seek });
+            return Ok(None);
+        }
+        let page = self.tree.read_page(seek)?;
+        if let Err(reason) = Node::<K, V>::check_page(&page) {
+            self.violations.push(Violation::Corrupt { seek, reason });
+            return Ok(None);
+        }
+        Ok(Some(page))
+    }
+
+    //node 深度优先检查子树, key 范围 [lo, hi)
+    fn node(&mut self, seek: u64, parent: u64, lo: Option<&K>, hi: Option<&K>, depth: u64) -> Result<()> {
+        let page = match self.page(seek)? {
+            Some(page) => page,
+            None => return Ok(()),
+        };
+        let flag = page[0];
+        if (flag & VALID) != VALID || (flag & EXTRA_DATA) == EXTRA_DATA || (flag & (LEAF | MIDDLE_NODE)) == 0 {
+            self.violations.push(Violation::Corrupt { seek, reason: format!("unexpected flag {:#010b}", flag) });
+            return Ok(());
+        }
+        if ((flag & ROOT) == ROOT) != (seek == 0) {
+            self.violations.push(Violation::Corrupt { seek, reason: "root flag".to_string() });
+        }
+        let node = match Node::<K, V>::new_node_header(seek, &page) {
+            Ok(node) => node,
+            Err(e) => {
+                self.violations.push(Violation::Corrupt { seek, reason: e.to_string() });
+                return Ok(());
+            }
+        };
+        let keys: Vec<&K> = node.key.iter().flatten().map(|k| k.as_ref()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            if i > 0 && keys[i - 1] >= *key {
+                self.violations.push(Violation::KeyOrder { seek, index: i });
+            }
+            if lo.is_some_and(|lo| *key < lo) || hi.is_some_and(|hi| *key >= hi) {
+                self.violations.push(Violation::SeparatorBound { seek, parent, index: i });
+            }
+        }
+
+        if node.is_leaf() {
+            if node.key_count != node.data_count {
+                self.violations.push(Violation::CountMismatch { seek, key_count: node.key_count, data_count: node.data_count });
+            }
+            match self.depth {
+                Some(expected) if expected != depth => self.violations.push(Violation::LeafDepth { seek, depth, expected }),
+                Some(_) => {}
+                None => self.depth = Some(depth),
+            }
+            let origin_lengths = match node.data_origin_lengths(&page) {
+                Ok(origin_lengths) => origin_lengths,
+                Err(e) => {
+                    self.violations.push(Violation::Corrupt { seek, reason: e.to_string() });
+                    return Ok(());
+                }
+            };
+            //页内保存 data_max_len - 8 字节, 其余在额外数据页
+            let inline = data_max_len::<K>() - 8;
+            let mut chain_ok = true;
+            for ((key, extra), origin_length) in keys.iter().zip(node.extra_data.iter().flatten()).zip(origin_lengths) {
+                if let Some(extra) = extra {
+                    chain_ok &= self.extra(seek, key, extra.seek, origin_length.saturating_sub(inline))?;
+                }
+            }
+            //额外数据页正常时再完整解析数据
+            if chain_ok {
+                if let Err(e) = self.tree.read_node(seek) {
+                    self.violations.push(Violation::Corrupt { seek, reason: e.to_string() });
+                }
+            }
+            self.leaves.push((seek, node.prev, node.next, keys.first().map(|k| (*k).clone()), keys.last().map(|k| (*k).clone())));
+            return Ok(());
+        }
+
+        if node.data_count != 0 {
+            self.violations.push(Violation::CountMismatch { seek, key_count: node.key_count, data_count: node.data_count });
+        }
+        let children = node.key_seek.clone().unwrap_or_default();
+        for (i, child) in children.iter().enumerate() {
+            let child_lo = if i == 0 { lo } else { keys.get(i - 1).copied() };
+            let child_hi = keys.get(i).copied().or(hi);
+            self.node(*child, seek, child_lo, child_hi, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    //extra 检查一条数据的额外数据页链表, expected 为叶子记录的额外数据总长度
+    fn extra(&mut self, owner: u64, key: &K, first: u64, expected: u64) -> Result<bool> {
+        let mut seek = first;
+        let mut prev = 0;
+        let mut remain = expected;
+        while seek != 0 {
+            let violation = |reason: &str| Violation::ExtraChain { seek, owner, reason: reason.to_string() };
+            let page = match self.page(seek)? {
+                Some(page) => page,
+                None => return Ok(false),
+            };
+            if page[0] != (EXTRA_DATA | VALID) {
+                self.violations.push(violation(&format!("unexpected flag {:#010b}", page[0])));
+                return Ok(false);
+            }
+            //解析失败记录问题, 不中断检查
+            let decoded = ExtraData::data_extra_key::<K>(&page).and_then(|page_key| {
+                let (origin_length, len) = ExtraData::data_extra_length(&page)?;
+                let (_, next) = ExtraData::data_extra_decode::<K>(&page, seek)?;
+                Ok((page_key, origin_length, len, next))
+            });
+            let (page_key, origin_length, len, next) = match decoded {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    self.violations.push(violation(&e.to_string()));
+                    return Ok(false);
+                }
+            };
+            if page_key != *key {
+                self.violations.push(violation("owner key mismatch"));
+                return Ok(false);
+            }
+            let header_prev = u64::from_be_bytes(page[PREV_OFFSET as usize..PREV_OFFSET as usize + 8].try_into()?);
+            let header_next = u64::from_be_bytes(page[NEXT_OFFSET as usize..NEXT_OFFSET as usize + 8].try_into()?);
+            if header_prev != prev {
+                self.violations.push(violation(&format!("prev is {}, expected {}", header_prev, prev)));
+                return Ok(false);
+            }
+            if origin_length != remain {
+                self.violations.push(violation(&format!("origin length {}, expected {}", origin_length, remain)));
+                return Ok(false);
+            }
+            if next != header_next || (next == 0) != (origin_length == len) {
+                self.violations.push(violation(&format!("next is {}, header next {}", next, header_next)));
+                return Ok(false);
+            }
+            remain = origin_length - len;
+            prev = seek;
+            seek = next;
+        }
+        Ok(true)
+    }
+
+    //siblings 按深度优先顺序检查叶子链表
+    fn siblings(&mut self) {
+        let mut expected_prev = 0;
+        for i in 0..self.leaves.len() {
+            let (seek, prev, next, _, last) = &self.leaves[i];
+            if *prev != expected_prev {
+                self.violations.push(Violation::SiblingLink { seek: *seek, field: "prev", expected: expected_prev, found: *prev });
+            }
+            let right = self.leaves.get(i + 1);
+            let expected_next = right.map(|r| r.0).unwrap_or(0);
+            if *next != expected_next {
+                self.violations.push(Violation::SiblingLink { seek: *seek, field: "next", expected: expected_next, found: *next });
+            }
+            if let Some((right, _, _, Some(first), _)) = right {
+                if last.as_ref().is_some_and(|last| last >= first) {
+                    self.violations.push(Violation::SiblingOrder { left: *seek, right: *right });
+                }
+            }
+            expected_prev = *seek;
+        }
+    }
+
+    //unreachable 文件中没有被引用的有效页
+    fn unreachable(&mut self) -> Result<()> {
+        let page = page_size() as u64;
+        for seek in (page..self.end).step_by(page as usize) {
+            if !self.visited.contains(&seek) && (self.tree.read_flag(seek)? & VALID) == VALID {
+                self.violations.push(Violation::Unreachable { seek });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::node::{page_size, ExtraData, NEXT_OFFSET, NODE_FIXED_SIZE};
+    use crate::store::{MemoryStore, PageStore};
+    use crate::tree::{Config, Tree, Violation};
+    use crate::ValueTest;
+
+    #[test]
+    fn verify() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { fill_factor: 0.05, ..Default::default() }).unwrap();
+        tree.bulk_load((0..2000u64).map(|i| (i * 2, i))).unwrap();
+        for i in 0..200u64 {
+            tree.insert(i * 10 + 1, i).unwrap();
+        }
+        let report = tree.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        assert!(report.depth >= 3);
+        assert!(report.leaves > 1);
+        assert_eq!(report.pages * page_size() as u64, tree.store.len());
+    }
+
+    #[test]
+    fn verify_corrupt() {
+        let tree = Tree::<u64, u64, MemoryStore>::in_memory_with_config(Config { fill_factor: 0.05, ..Default::default() }).unwrap();
+        tree.bulk_load((0..300u64).map(|i| (i, i))).unwrap();
+        let first = tree.first_leaf().unwrap();
+        //next 指向错误
+        tree.set_next(first.seek_start, first.seek_start).unwrap();
+        let report = tree.verify().unwrap();
+        assert!(report.violations.contains(&Violation::SiblingLink { seek: first.seek_start, field: "next", expected: first.next, found: first.seek_start }));
+        tree.set_next(first.seek_start, first.next).unwrap();
+        assert!(tree.verify().unwrap().is_ok());
+
+        //key 顺序错误
+        let mut leaf = tree.read_node(first.next).unwrap();
+        leaf.key.as_mut().unwrap().swap(0, 1);
+        tree.write_node(&mut leaf).unwrap();
+        let report = tree.verify().unwrap();
+        assert!(report.violations.contains(&Violation::KeyOrder { seek: first.next, index: 1 }));
+        leaf.key.as_mut().unwrap().swap(0, 1);
+        tree.write_node(&mut leaf).unwrap();
+
+        //不可达的有效页
+        let mut orphan = tree.read_node(first.next).unwrap();
+        orphan.seek_start = tree.allocate();
+        tree.write_node(&mut orphan).unwrap();
+        let report = tree.verify().unwrap();
+        assert_eq!(report.violations, vec![Violation::Unreachable { seek: orphan.seek_start }]);
+
+        //损坏的页
+        tree.write_page(first.seek_start + 1, &u64::MAX.to_be_bytes()).unwrap();
+        tree.write_page(first.seek_start + 9, &u64::MAX.to_be_bytes()).unwrap();
+        let report = tree.verify().unwrap();
+        assert!(matches!(report.violations[0], Violation::Corrupt { seek, .. } if seek == first.seek_start));
+    }
+
+    #[test]
+    fn verify_extra_chain() {
+        let tree = Tree::<u64, ValueTest, MemoryStore>::in_memory().unwrap();
+        tree.insert(1, ValueTest { id: 1, data: "z".repeat(40000) }).unwrap();
+        assert!(tree.verify().unwrap().is_ok());
+        let leaf = tree.read_node(0).unwrap();
+        let first = leaf.extra_data.as_ref().unwrap()[0].as_ref().unwrap().seek;
+        let next = u64::from_be_bytes(tree.read_page(first).unwrap()[NEXT_OFFSET as usize..NEXT_OFFSET as usize + 8].try_into().unwrap());
+
+        //叶子记录的原始长度和额外数据页不一致
+        let origin = tree.read_page(0).unwrap()[NODE_FIXED_SIZE..NODE_FIXED_SIZE + 8].to_vec();
+        tree.write_page(NODE_FIXED_SIZE as u64, &(u64::from_be_bytes(origin.clone().try_into().unwrap()) + 1).to_be_bytes()).unwrap();
+        let report = tree.verify().unwrap();
+        assert!(matches!(&report.violations[0], Violation::ExtraChain { seek, owner: 0, reason } if *seek == first && reason.starts_with("origin length")), "{:?}", report.violations);
+        tree.write_page(NODE_FIXED_SIZE as u64, &origin).unwrap();
+        assert!(tree.verify().unwrap().is_ok());
+
+        //长度越界的额外数据页解析返回错误
+        let mut page = tree.read_page(first).unwrap();
+        page.truncate(page.len() / 2);
+        assert!(ExtraData::data_extra_decode::<u64>(&page, first).is_err());
+
+        //释放链表中间的页
+        tree.free_page(next).unwrap();
+        let report = tree.verify().unwrap();
+        assert!(matches!(report.violations[0], Violation::ExtraChain { seek, owner: 0, .. } if seek == next));
+    }
+}
@@ -0,0 +1,4 @@
+#[allow(clippy::module_inception)]
+mod versioned;
+
+pub use versioned::{register_migration, Migration, SchemaVersion, Versioned};
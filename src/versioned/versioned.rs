@@ -0,0 +1,363 @@
+use std::any::TypeId;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::io::{Cursor, Error, ErrorKind};
+use std::sync::{OnceLock, RwLock};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crate::{DecodableU8, EncodableU8, Size};
+use crate::store::PageStore;
+use crate::tree::Tree;
+
+// 版本信封: [magic 4][version u16][payload]
+// 没有信封的旧数据(手写 EncodableU8 写入的值)是版本 0, 以 MAGIC 开头的旧数据会被当作带信封
+const MAGIC: [u8; 4] = *b"BPV\0";
+const HEADER_SIZE: usize = 6;
+
+/// 旧版本 payload 升级到下一个版本(from -> from + 1)
+/// 从版本 0 升级时收到的是没有信封的原始字节
+pub type Migration = fn(&[u8]) -> Result<Vec<u8>, Error>;
+
+/// 值的当前结构版本, 从 1 开始, 结构变化时加一并注册旧版本的升级函数
+pub trait SchemaVersion {
+    const VERSION: u16;
+}
+
+// 迁移注册表 类型 -> (旧版本 -> 升级函数)
+static MIGRATIONS: OnceLock<RwLock<HashMap<TypeId, BTreeMap<u16, Migration>>>> = OnceLock::new();
+
+fn migrations() -> &'static RwLock<HashMap<TypeId, BTreeMap<u16, Migration>>> {
+    MIGRATIONS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// 注册 T 从 from 版本升级到 from + 1 版本的迁移函数, 重复注册会覆盖
+/// from 为 0 时注册的是没有版本信封的旧数据的解码, 没有注册时读到这类数据返回错误
+pub fn register_migration<T: 'static>(from: u16, migration: Migration) {
+    let mut registry = migrations().write().unwrap_or_else(|e| e.into_inner());
+    registry.entry(TypeId::of::<T>()).or_default().insert(from, migration);
+}
+
+/// 带版本号的值, 读取时自动按注册表升级旧版本, 比较时只比较 value
+#[derive(Debug, Clone)]
+pub struct Versioned<T> {
+    pub value: T,
+    // 读取时磁盘上的版本
+    version: u16,
+}
+
+impl<T: SchemaVersion> Versioned<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value,
+            version: T::VERSION,
+        }
+    }
+
+    /// 读取时磁盘上的版本号
+    pub fn stored_version(&self) -> u16 {
+        self.version
+    }
+
+    /// 是否经过迁移, Tree::get_migrated 读取时会写回当前版本
+    pub fn is_migrated(&self) -> bool {
+        self.version != T::VERSION
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T: PartialEq> PartialEq for Versioned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl<T: EncodableU8 + SchemaVersion> EncodableU8 for Versioned<T> {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<u64, Error> {
+        // 总是以当前版本写入
+        buf.extend_from_slice(&MAGIC);
+        buf.write_u16::<BigEndian>(T::VERSION)?;
+        self.value.encode(buf)
+    }
+}
+
+impl<T: DecodableU8 + SchemaVersion + 'static> DecodableU8 for Versioned<T> {
+    fn decode(buf: &[u8]) -> Result<(Self, u64), Error> {
+        let (version, payload) = if buf.starts_with(&MAGIC) {
+            if buf.len() < HEADER_SIZE {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "missing version"));
+            }
+            (Cursor::new(&buf[MAGIC.len()..HEADER_SIZE]).read_u16::<BigEndian>()?, &buf[HEADER_SIZE..])
+        } else {
+            //没有信封的旧数据
+            (0, buf)
+        };
+        if version == T::VERSION {
+            let (value, len) = T::decode(payload)?;
+            return Ok((Self { value, version }, (buf.len() - payload.len()) as u64 + len));
+        }
+        if version > T::VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!("version {} newer than {}", version, T::VERSION)));
+        }
+
+        let mut payload = payload.to_vec();
+        {
+            let registry = migrations().read().unwrap_or_else(|e| e.into_inner());
+            let steps = registry.get(&TypeId::of::<T>());
+            for from in version..T::VERSION {
+                let migration = steps.and_then(|s| s.get(&from))
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("missing migration from version {}", from)))?;
+                payload = migration(&payload)?;
+            }
+        }
+        let (value, _) = T::decode(&payload)?;
+        // 旧数据整段都被消费
+        Ok((Self { value, version }, buf.len() as u64))
+    }
+}
+
+impl<K, T, S> Tree<K, Versioned<T>, S> where
+    K: EncodableU8 + DecodableU8 + Size + PartialEq + PartialOrd + Debug + Clone + Send + Sync,
+    T: EncodableU8 + DecodableU8 + SchemaVersion + Debug + Clone + Send + Sync + 'static,
+    S: PageStore
+{
+    /// 读取 key, 旧版本的数据升级后以当前版本写回, 之后读取不再需要迁移
+    /// 写回时整个叶子重新编码, 同一叶子中的其他旧数据也一起升级
+    /// 只读打开时只升级不写回, 返回值的 stored_version 是读取时磁盘上的版本
+    pub fn get_migrated(&self, key: &K) -> anyhow::Result<Option<Versioned<T>>> {
+        let value = self.get(key)?;
+        if !value.as_ref().is_some_and(|v| v.is_migrated()) || self.store.is_read_only() {
+            return Ok(value);
+        }
+        let rewritten = {
+            let _guard = self.write_lock();
+            //等待写锁期间可能已经被改写
+            match self.get_locked(key)? {
+                Some(current) if current.is_migrated() => {
+                    self.insert_locked(key.clone(), Versioned::new(current.value))?;
+                    true
+                }
+                _ => false,
+            }
+        };
+        if rewritten {
+            self.commit()?;
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Error};
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use crate::{temp_path, DecodableU8, EncodableU8, ValueTest};
+    use crate::tree::Tree;
+    use crate::versioned::{register_migration, SchemaVersion, Versioned};
+    use super::MAGIC;
+
+    // v1: id   v2: id + score   v3: id + score + flag
+    #[derive(Debug, Clone, PartialEq)]
+    struct Row {
+        id: u32,
+        score: u32,
+        flag: u8,
+    }
+
+    impl SchemaVersion for Row {
+        const VERSION: u16 = 3;
+    }
+
+    impl EncodableU8 for Row {
+        fn encode(&self, buf: &mut Vec<u8>) -> Result<u64, Error> {
+            buf.write_u32::<BigEndian>(self.id)?;
+            buf.write_u32::<BigEndian>(self.score)?;
+            buf.write_u8(self.flag)?;
+            Ok(buf.len() as u64)
+        }
+    }
+
+    impl DecodableU8 for Row {
+        fn decode(buf: &[u8]) -> Result<(Self, u64), Error> {
+            let mut rdr = Cursor::new(buf);
+            Ok((Row {
+                id: rdr.read_u32::<BigEndian>()?,
+                score: rdr.read_u32::<BigEndian>()?,
+                flag: rdr.read_u8()?,
+            }, 9))
+        }
+    }
+
+    fn register() {
+        register_migration::<Row>(1, |old| {
+            let mut new = old[0..4].to_vec();
+            new.write_u32::<BigEndian>(100)?;
+            Ok(new)
+        });
+        register_migration::<Row>(2, |old| {
+            let mut new = old.to_vec();
+            new.push(1);
+            Ok(new)
+        });
+    }
+
+    //v1 旧数据
+    fn v1(id: u32) -> Vec<u8> {
+        let mut old = MAGIC.to_vec();
+        old.write_u16::<BigEndian>(1).unwrap();
+        old.write_u32::<BigEndian>(id).unwrap();
+        old
+    }
+
+    #[test]
+    fn versioned_migrate() {
+        register();
+
+        let row = Row { id: 7, score: 9, flag: 0 };
+        let mut buf = vec![];
+        Versioned::new(row.clone()).encode(&mut buf).unwrap();
+        let (current, len) = Versioned::<Row>::decode(&buf).unwrap();
+        assert_eq!(len, buf.len() as u64);
+        assert!(!current.is_migrated());
+        assert_eq!(current.into_inner(), row);
+
+        let (migrated, _) = Versioned::<Row>::decode(&v1(7)).unwrap();
+        assert!(migrated.is_migrated());
+        assert_eq!(migrated.stored_version(), 1);
+        assert_eq!(migrated.value, Row { id: 7, score: 100, flag: 1 });
+        //只比较内容, 不比较读取时的版本
+        assert_eq!(migrated, Versioned::new(Row { id: 7, score: 100, flag: 1 }));
+        assert_ne!(migrated, Versioned::new(row));
+
+        // 比当前版本新的数据
+        let mut newer = MAGIC.to_vec();
+        newer.write_u16::<BigEndian>(4).unwrap();
+        assert!(Versioned::<Row>::decode(&newer).is_err());
+    }
+
+    #[test]
+    fn versioned_tree() {
+        register();
+        let dir = tempfile::tempdir().unwrap();
+        let path = temp_path(&dir, "versioned.db");
+        //用原始字节写入 v1 数据
+        {
+            let tree = Tree::<u64, Vec<u8>>::open(&path).unwrap();
+            for i in 0..10u64 {
+                tree.insert(i, v1(i as u32)).unwrap();
+            }
+        }
+
+        //只读打开不写回
+        {
+            let tree = Tree::<u64, Versioned<Row>>::open_read_only(&path).unwrap();
+            assert_eq!(tree.get_migrated(&1).unwrap().unwrap().stored_version(), 1);
+            assert_eq!(tree.metrics().page_writes, 0);
+        }
+
+        let tree = Tree::<u64, Versioned<Row>>::open(&path).unwrap();
+        let migrated = tree.get_migrated(&1).unwrap().unwrap();
+        assert_eq!(migrated.stored_version(), 1);
+        assert_eq!(migrated.value, Row { id: 1, score: 100, flag: 1 });
+        assert!(tree.metrics().page_writes > 0);
+        //写回之后读到的是当前版本, get 不再写回
+        let writes = tree.metrics().page_writes;
+        assert!(!tree.get(&1).unwrap().unwrap().is_migrated());
+        assert!(!tree.get_migrated(&1).unwrap().unwrap().is_migrated());
+        assert_eq!(tree.metrics().page_writes, writes);
+        //同一叶子的数据一起写回
+        assert!(!tree.get(&2).unwrap().unwrap().is_migrated());
+        assert_eq!(tree.get_migrated(&20).unwrap(), None);
+        drop(tree);
+
+        let tree = Tree::<u64, Vec<u8>>::open(&path).unwrap();
+        for i in 0..10u64 {
+            assert_eq!(tree.get(&i).unwrap().unwrap()[0..6], [b'B', b'P', b'V', 0, 0, 3]);
+        }
+    }
+
+    // ValueTest 加上 score 后的结构, 旧数据是 ValueTest::encode 写入的没有信封的字节
+    #[derive(Debug, Clone, PartialEq)]
+    struct ScoredValue {
+        id: u32,
+        score: u32,
+        data: String,
+    }
+
+    impl SchemaVersion for ScoredValue {
+        const VERSION: u16 = 1;
+    }
+
+    impl EncodableU8 for ScoredValue {
+        fn encode(&self, buf: &mut Vec<u8>) -> Result<u64, Error> {
+            buf.write_u32::<BigEndian>(self.id)?;
+            buf.write_u32::<BigEndian>(self.score)?;
+            buf.extend_from_slice(self.data.as_bytes());
+            Ok(buf.len() as u64)
+        }
+    }
+
+    impl DecodableU8 for ScoredValue {
+        fn decode(buf: &[u8]) -> Result<(Self, u64), Error> {
+            let mut rdr = Cursor::new(buf);
+            let id = rdr.read_u32::<BigEndian>()?;
+            let score = rdr.read_u32::<BigEndian>()?;
+            let data = String::from_utf8_lossy(&buf[8..]).to_string();
+            Ok((ScoredValue { id, score, data }, buf.len() as u64))
+        }
+    }
+
+    #[test]
+    fn versioned_legacy() {
+        let old = ValueTest { id: 5, data: "legacy row".to_string() };
+        let mut raw = vec![];
+        old.encode(&mut raw).unwrap();
+        //没有注册版本 0 的迁移时不能读取
+        assert!(Versioned::<ScoredValue>::decode(&raw).is_err());
+
+        register_migration::<ScoredValue>(0, |old| {
+            let (value, _) = ValueTest::decode(old)?;
+            let mut new = vec![];
+            ScoredValue { id: value.id, score: 0, data: value.data }.encode(&mut new)?;
+            Ok(new)
+        });
+        let (migrated, len) = Versioned::<ScoredValue>::decode(&raw).unwrap();
+        assert_eq!(len, raw.len() as u64);
+        assert_eq!(migrated.stored_version(), 0);
+        assert_eq!(migrated.value, ScoredValue { id: 5, score: 0, data: "legacy row".to_string() });
+
+        //树中已有的旧数据读取时升级并写回
+        let dir = tempfile::tempdir().unwrap();
+        let path = temp_path(&dir, "legacy.db");
+        {
+            let tree = Tree::<u64, ValueTest>::open(&path).unwrap();
+            for i in 0..10u64 {
+                tree.insert(i, ValueTest { id: i as u32, data: format!("row {}", i) }).unwrap();
+            }
+        }
+        let tree = Tree::<u64, Versioned<ScoredValue>>::open(&path).unwrap();
+        let value = tree.get_migrated(&3).unwrap().unwrap();
+        assert_eq!(value.stored_version(), 0);
+        assert_eq!(value.value, ScoredValue { id: 3, score: 0, data: "row 3".to_string() });
+        assert_eq!(tree.get(&3).unwrap().unwrap().stored_version(), 1);
+    }
+
+    #[test]
+    fn versioned_missing_migration() {
+        #[derive(Debug)]
+        struct NoMigration;
+        impl SchemaVersion for NoMigration {
+            const VERSION: u16 = 2;
+        }
+        impl DecodableU8 for NoMigration {
+            fn decode(_: &[u8]) -> Result<(Self, u64), Error> {
+                Ok((NoMigration, 0))
+            }
+        }
+        let mut old = vec![];
+        old.write_u16::<BigEndian>(1).unwrap();
+        assert!(Versioned::<NoMigration>::decode(&old).is_err());
+        assert!(Versioned::<NoMigration>::decode(&[0]).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::process::{Command, Output};
+use BPlusTree::tree::Tree;
+
+fn bptree(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bptree")).args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn bptree_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("fixture.db").to_str().unwrap().to_string();
+    {
+        let tree = Tree::<u64, String>::open(&path).unwrap();
+        for i in 0..500u64 {
+            tree.insert(i, format!("value {}", i)).unwrap();
+        }
+    }
+
+    let info = stdout(&bptree(&[&path, "info"]));
+    assert!(info.contains("page size: 16384"), "{}", info);
+    assert!(info.contains("page 0: flag"), "{}", info);
+    //文件中不保存打开配置
+    assert!(!info.contains("fill factor"), "{}", info);
+    assert_eq!(stdout(&bptree(&[&path, "get", "42"])), "value 42\n");
+    assert_eq!(stdout(&bptree(&[&path, "get", "0x10"])), "value 16\n");
+    assert_eq!(stdout(&bptree(&[&path, "get", "500"])), "(not found)\n");
+    assert!(stdout(&bptree(&[&path, "check"])).ends_with("ok\n"));
+    assert_eq!(stdout(&bptree(&[&path, "scan", "--from", "10", "--limit", "2"])), "10\tvalue 10\n11\tvalue 11\n");
+
+    //修改后库可以读到
+    stdout(&bptree(&[&path, "put", "7", "new", "value"]));
+    assert_eq!(Tree::<u64, String>::open_read_only(&path).unwrap().get(&7).unwrap(), Some("new value".to_string()));
+
+    let output = bptree(&[&path, "unknown"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown command"));
+}
+
+#[test]
+fn bptree_value_type() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bytes.db").to_str().unwrap().to_string();
+    {
+        let tree = Tree::<u64, Vec<u8>>::open(&path).unwrap();
+        tree.insert(1, vec![0xde, 0xad, 0xbe, 0xef]).unwrap();
+    }
+    assert_eq!(stdout(&bptree(&["--value", "bytes", &path, "get", "1"])), "deadbeef\n");
+    stdout(&bptree(&["--value", "bytes", &path, "put", "2", "0x0102"]));
+    assert_eq!(Tree::<u64, Vec<u8>>::open_read_only(&path).unwrap().get(&2).unwrap(), Some(vec![1, 2]));
+    assert!(stdout(&bptree(&["--value", "bytes", &path, "check"])).ends_with("ok\n"));
+
+    let path = dir.path().join("u64.db").to_str().unwrap().to_string();
+    Tree::<u64, u64>::open(&path).unwrap().insert(3, 30).unwrap();
+    assert_eq!(stdout(&bptree(&["--value", "u64", &path, "get", "3"])), "30\n");
+    assert_eq!(bptree(&["--value", "float", &path, "get", "3"]).status.code(), Some(1));
+}